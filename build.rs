@@ -0,0 +1,34 @@
+// The gRPC service is described in Rust rather than compiled from proto/friendship.proto,
+// so building it does not need protoc.
+#[cfg(feature = "grpc")]
+fn grpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route_name: &str, input_type: &str, output_type: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route_name)
+            .input_type(format!("super::{}", input_type))
+            .output_type(format!("super::{}", output_type))
+            .codec_path("tonic::codec::ProstCodec")
+    };
+    let service = Service::builder()
+        .name("Friendship")
+        .package("friendship")
+        .method(method("add_user", "AddUser", "UserRequest", "Empty").build())
+        .method(method("delete_user", "DeleteUser", "DeleteUserRequest", "DeleteUserReply").build())
+        .method(method("deactivate_user", "DeactivateUser", "UserRequest", "Empty").build())
+        .method(method("reactivate_user", "ReactivateUser", "UserRequest", "Empty").build())
+        .method(method("add_friend", "AddFriend", "FriendshipRequest", "Empty").build())
+        .method(method("delete_friend", "DeleteFriend", "FriendshipRequest", "Empty").build())
+        .method(method("is_friend", "IsFriend", "FriendshipRequest", "IsFriendReply").build())
+        .method(method("list_friends", "ListFriends", "UserRequest", "UserList").server_streaming().build())
+        .method(method("recommendations", "Recommendations", "UserRequest", "UserList").build())
+        .build();
+    Builder::new().compile(&[service]);
+}
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    grpc();
+}
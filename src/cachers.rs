@@ -1,7 +1,9 @@
-use crate::core::Cacher;
-use crate::error::Error;
-use redis::{AsyncCommands, Client, RedisError};
-use serde_json;
+use crate::core::{BoxFuture, Cacher, IdempotencyStore};
+use redis::{AsyncCommands, Client};
+use std::time::Duration;
+
+pub const DEFAULT_PROCESSED_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
 pub struct Redis {
     client: Client,
 }
@@ -14,45 +16,150 @@ impl Redis {
 
 impl Cacher for Redis {
     type UID = String;
-    fn delete(&self, uid: Self::UID) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), Error>>>> {
+    fn delete(&self, uid: Self::UID) -> BoxFuture<()> {
         let client = self.client.clone();
         Box::pin(async move {
             let mut conn = client.get_async_connection().await?;
-            conn.del(format!("uid_{}", uid)).await?;
+            conn.del::<_, ()>(format!("uid_{}", uid)).await?;
             Ok(())
         })
     }
 
-    fn insert(&self, uid: Self::UID, friends: Vec<Self::UID>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), Error>>>> {
+    fn insert(&self, uid: Self::UID, friends: Vec<Self::UID>) -> BoxFuture<()> {
         let client = self.client.clone();
         Box::pin(async move {
             let mut conn = client.get_async_connection().await?;
-            conn.set(
-                format!("uid_{}", uid),
-                serde_json::to_string(&friends).map_err(|_| RedisError::from((redis::ErrorKind::TypeError, "invalid value")))?,
-            )
-            .await?;
+            conn.set::<_, _, ()>(format!("uid_{}", uid), serde_json::to_string(&friends)?).await?;
             Ok(())
         })
     }
 
-    fn query(&self, uid: Self::UID) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<Option<Vec<Self::UID>>, Error>>>> {
+    fn query(&self, uid: Self::UID) -> BoxFuture<Option<Vec<Self::UID>>> {
         let client = self.client.clone();
         Box::pin(async move {
             let mut conn = client.get_async_connection().await?;
             if let Some(s) = conn.get::<_, Option<String>>(format!("uid_{}", uid)).await? {
-                return Ok(serde_json::from_str(&s).map_err(|_| RedisError::from((redis::ErrorKind::TypeError, "failed to deserialize json string")))?);
+                return Ok(serde_json::from_str(&s)?);
             }
             Ok(None)
         })
     }
+
+    fn query_many(&self, uids: Vec<Self::UID>) -> BoxFuture<Vec<Option<Vec<Self::UID>>>> {
+        let client = self.client.clone();
+        Box::pin(async move {
+            if uids.is_empty() {
+                return Ok(Vec::new());
+            }
+            let mut conn = client.get_async_connection().await?;
+            let values: Vec<Option<String>> = redis::cmd("MGET").arg(uids.iter().map(|uid| format!("uid_{}", uid)).collect::<Vec<_>>()).query_async(&mut conn).await?;
+            let mut res = Vec::with_capacity(values.len());
+            for value in values {
+                res.push(match value {
+                    Some(s) => Some(serde_json::from_str(&s)?),
+                    None => None,
+                });
+            }
+            Ok(res)
+        })
+    }
+}
+
+// Replies are kept under `processed:{request_id}` for `ttl`, which has to cover the
+// time a request may be redelivered in.
+pub struct RedisProcessed {
+    client: Client,
+    ttl: Duration,
+}
+
+impl RedisProcessed {
+    pub fn new(client: Client, ttl: Duration) -> Self {
+        Self { client, ttl }
+    }
+}
+
+impl IdempotencyStore for RedisProcessed {
+    fn claim(&self, request_id: String, marker: String, lease: Duration) -> BoxFuture<Option<String>> {
+        let client = self.client.clone();
+        Box::pin(async move {
+            let mut conn = client.get_async_connection().await?;
+            let key = format!("processed:{}", request_id);
+            loop {
+                let claimed: Option<String> = redis::cmd("SET")
+                    .arg(&key)
+                    .arg(&marker)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(lease.as_millis().max(1) as u64)
+                    .query_async(&mut conn)
+                    .await?;
+                if claimed.is_some() {
+                    return Ok(None);
+                }
+                // Gone again when it expired in between, then the claim is tried once more.
+                if let Some(stored) = conn.get::<_, Option<String>>(&key).await? {
+                    return Ok(Some(stored));
+                }
+            }
+        })
+    }
+
+    fn put(&self, request_id: String, reply: String) -> BoxFuture<()> {
+        let client = self.client.clone();
+        let ttl = self.ttl.as_secs().max(1) as usize;
+        Box::pin(async move {
+            let mut conn = client.get_async_connection().await?;
+            conn.set_ex::<_, _, ()>(format!("processed:{}", request_id), reply, ttl).await?;
+            Ok(())
+        })
+    }
+
+    fn release(&self, request_id: String) -> BoxFuture<()> {
+        let client = self.client.clone();
+        Box::pin(async move {
+            let mut conn = client.get_async_connection().await?;
+            conn.del::<_, ()>(format!("processed:{}", request_id)).await?;
+            Ok(())
+        })
+    }
+}
+
+// In-memory implementation of `Cacher` for tests.
+#[cfg(test)]
+#[derive(Default, Clone)]
+pub struct Memory {
+    entries: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, Vec<String>>>>,
+}
+
+#[cfg(test)]
+impl Cacher for Memory {
+    type UID = String;
+    fn delete(&self, uid: Self::UID) -> BoxFuture<()> {
+        self.entries.lock().unwrap().remove(&uid);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn insert(&self, uid: Self::UID, friends: Vec<Self::UID>) -> BoxFuture<()> {
+        self.entries.lock().unwrap().insert(uid, friends);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn query(&self, uid: Self::UID) -> BoxFuture<Option<Vec<Self::UID>>> {
+        let res = self.entries.lock().unwrap().get(&uid).cloned();
+        Box::pin(async move { Ok(res) })
+    }
+
+    fn query_many(&self, uids: Vec<Self::UID>) -> BoxFuture<Vec<Option<Vec<Self::UID>>>> {
+        let entries = self.entries.lock().unwrap();
+        let res = uids.iter().map(|uid| entries.get(uid).cloned()).collect();
+        Box::pin(async move { Ok(res) })
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use redis::Client;
-    use tokio;
 
     #[tokio::test]
     async fn test_insert_cache() {
@@ -71,4 +178,27 @@ mod test {
         r.delete(1.to_string()).await.unwrap();
         assert!(r.query(1.to_string()).await.unwrap().is_none());
     }
+
+    #[tokio::test]
+    async fn test_query_many_cache() {
+        let client = Client::open("redis://localhost").unwrap();
+        let r = Redis::new(client);
+        r.insert(1.to_string(), vec![2.to_string(), 3.to_string()]).await.unwrap();
+        r.delete(2.to_string()).await.unwrap();
+        assert!(r.query_many(vec![1.to_string(), 2.to_string()]).await.unwrap() == vec![Some(vec![2.to_string(), 3.to_string()]), None]);
+    }
+
+    #[tokio::test]
+    async fn test_processed() {
+        let processed = RedisProcessed::new(Client::open("redis://localhost").unwrap(), Duration::from_secs(10));
+        let lease = Duration::from_secs(10);
+        processed.release("test-request".into()).await.unwrap();
+        assert_eq!(processed.claim("test-request".into(), "\"Running\"".into(), lease).await.unwrap(), None);
+        assert_eq!(processed.claim("test-request".into(), "\"Running\"".into(), lease).await.unwrap().as_deref(), Some("\"Running\""));
+        processed.put("test-request".into(), r#"{"Ok":null}"#.into()).await.unwrap();
+        assert_eq!(processed.claim("test-request".into(), "\"Running\"".into(), lease).await.unwrap().as_deref(), Some(r#"{"Ok":null}"#));
+        processed.release("test-request".into()).await.unwrap();
+        assert_eq!(processed.claim("test-request".into(), "\"Running\"".into(), lease).await.unwrap(), None);
+        processed.release("test-request".into()).await.unwrap();
+    }
 }
@@ -0,0 +1,247 @@
+use crate::cachers::{RedisProcessed, DEFAULT_PROCESSED_TTL};
+use crate::client::{self, Client};
+use crate::core::{Cacher, IdempotencyStore, Persister};
+use crate::error::Error;
+use crate::exporter::{self, DEFAULT_PAGE_SIZE};
+use crate::importer::{read_checkpoint, Checkpoint, Format, Importer, DEFAULT_BATCH_SIZE};
+use crate::models::DeadLetter;
+use crate::protocol::{decode, without_deadline, CORRELATION_HEADER, DEFAULT_DEAD_LETTER_TOPIC, DEFAULT_REQUEST_TOPIC};
+use crate::Request;
+use log::warn;
+use rdkafka::config::ClientConfig as KafkaConfig;
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::Message;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const USAGE: &str = "usage:
+    with-baby-friendship import <path> [--format csv|jsonl] [--batch-size <n>] [--reject <path>] [--resume]
+    with-baby-friendship export <path> [--format jsonl|csv|graphml] [--page-size <n>]
+    with-baby-friendship restore <path> [--format csv|jsonl] [--batch-size <n>] [--reject <path>] [--resume]
+    with-baby-friendship replay-dead-letters [--limit <n>] [--idle-timeout <secs>]
+    with-baby-friendship request add|delete <uid_a> <uid_b>
+    with-baby-friendship request friends|recommendation|add-node|delete-node <uid>
+    with-baby-friendship request batch <ops json> [--atomic]";
+
+pub async fn run<P: Persister<UID = String>, C: Cacher<UID = String>>(args: &[String], persister: P, cacher: C) -> Result<(), Error> {
+    match args.first().map(String::as_str) {
+        // A snapshot written by `export` is in the import format, so restoring it is an import.
+        Some("import") | Some("restore") => import(&args[1..], &persister, &cacher).await,
+        Some("export") => export(&args[1..], &persister).await,
+        Some("replay-dead-letters") => replay_dead_letters(&args[1..]).await,
+        Some("request") => request(parse_request(&args[1..])?).await,
+        _ => Err(Error::invalid_input(USAGE.into())),
+    }
+}
+
+fn value<'a>(args: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<&'a String, Error> {
+    args.next().ok_or_else(|| Error::invalid_input(format!("missing value of {}\n{}", flag, USAGE)))
+}
+
+async fn import<P: Persister<UID = String>, C: Cacher<UID = String>>(args: &[String], persister: &P, cacher: &C) -> Result<(), Error> {
+    let mut path = None;
+    let mut format = None;
+    let mut batch_size = DEFAULT_BATCH_SIZE;
+    let mut reject = None;
+    let mut resume = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = Some(value(&mut args, arg)?.parse::<Format>()?),
+            "--batch-size" => batch_size = value(&mut args, arg)?.parse().map_err(|_| Error::invalid_input(format!("invalid batch size\n{}", USAGE)))?,
+            "--reject" => reject = Some(value(&mut args, arg)?.clone()),
+            "--resume" => resume = true,
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(Error::invalid_input(format!("unexpected argument: {}\n{}", arg, USAGE))),
+        }
+    }
+    let path = path.ok_or_else(|| Error::invalid_input(USAGE.into()))?;
+    let format = format.unwrap_or_else(|| Format::from_path(&path));
+    let checkpoint = PathBuf::from(format!("{}.checkpoint", path));
+    let resume_at = if resume { read_checkpoint(&checkpoint)? } else { Checkpoint::default() };
+    let rejects = OpenOptions::new().create(true).append(true).open(reject.unwrap_or_else(|| format!("{}.rejected", path)))?;
+    // Rejects written after the checkpoint belong to lines which are imported again.
+    rejects.set_len(resume_at.rejects)?;
+    let mut importer = Importer::new(persister, cacher, format, BufWriter::new(rejects))
+        .batch_size(batch_size)
+        .resume(resume_at)
+        .checkpoint(checkpoint);
+    for line in BufReader::new(File::open(&path)?).lines() {
+        importer.feed(&line?).await?;
+    }
+    let report = importer.finish().await?;
+    println!("{}", serde_json::to_string(&report)?);
+    Ok(())
+}
+
+async fn export<P: Persister<UID = String>>(args: &[String], persister: &P) -> Result<(), Error> {
+    let mut path = None;
+    let mut format = None;
+    let mut page_size = DEFAULT_PAGE_SIZE;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = Some(value(&mut args, arg)?.parse::<exporter::Format>()?),
+            "--page-size" => page_size = value(&mut args, arg)?.parse().map_err(|_| Error::invalid_input(format!("invalid page size\n{}", USAGE)))?,
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(Error::invalid_input(format!("unexpected argument: {}\n{}", arg, USAGE))),
+        }
+    }
+    let path = path.ok_or_else(|| Error::invalid_input(USAGE.into()))?;
+    let format = format.unwrap_or_else(|| exporter::Format::from_path(&path));
+    let report = exporter::export(persister, format, &mut BufWriter::new(File::create(&path)?), page_size).await?;
+    println!("{}", serde_json::to_string(&report)?);
+    Ok(())
+}
+
+fn uid(arg: Option<&String>) -> Result<i64, Error> {
+    arg.and_then(|uid| uid.parse().ok()).ok_or_else(|| Error::invalid_input(format!("invalid uid\n{}", USAGE)))
+}
+
+fn parse_request(args: &[String]) -> Result<Request, Error> {
+    let request = match args.first().map(String::as_str) {
+        Some("add") => Request::Add {
+            uid_a: uid(args.get(1))?,
+            uid_b: uid(args.get(2))?,
+        },
+        Some("delete") => Request::Delete {
+            uid_a: uid(args.get(1))?,
+            uid_b: uid(args.get(2))?,
+        },
+        Some("friends") => Request::Friends { uid: uid(args.get(1))? },
+        Some("recommendation") => Request::Recommendation { uid: uid(args.get(1))? },
+        Some("add-node") => Request::AddNode { uid: uid(args.get(1))? },
+        Some("delete-node") => Request::DeleteNode { uid: uid(args.get(1))? },
+        Some("batch") => Request::Batch {
+            ops: serde_json::from_str(args.get(1).ok_or_else(|| Error::invalid_input(USAGE.into()))?).map_err(|e| Error::invalid_input(format!("invalid batch ops: {}\n{}", e, USAGE)))?,
+            atomic: args.get(2).map(String::as_str) == Some("--atomic"),
+        },
+        _ => return Err(Error::invalid_input(USAGE.into())),
+    };
+    let expected = match request {
+        Request::Add { .. } | Request::Delete { .. } => 3,
+        Request::Batch { atomic, .. } => 2 + atomic as usize,
+        _ => 2,
+    };
+    match args.get(expected) {
+        Some(arg) => Err(Error::invalid_input(format!("unexpected argument: {}\n{}", arg, USAGE))),
+        None => Ok(request),
+    }
+}
+
+// Sends one request through the kafka client, the way other services do, and prints
+// the reply.
+async fn request(request: Request) -> Result<(), Error> {
+    let config = client::Config {
+        topic: dotenv::var("REQUEST_TOPIC").unwrap_or(DEFAULT_REQUEST_TOPIC.into()),
+        caller: Some("with-baby-friendship-cli".into()),
+        ..client::Config::default()
+    };
+    let client = Client::connect("redis://localhost", &dotenv::var("KAFKA_ADDRESS").unwrap_or("localhost:9092".into()), config)?;
+    let reply = match request {
+        Request::Add { uid_a, uid_b } => serde_json::to_string(&client.add(uid_a, uid_b).await?)?,
+        Request::Delete { uid_a, uid_b } => serde_json::to_string(&client.delete(uid_a, uid_b).await?)?,
+        Request::Friends { uid } => serde_json::to_string(&client.friends(uid).await?)?,
+        Request::Recommendation { uid } => serde_json::to_string(&client.recommendation(uid).await?)?,
+        Request::AddNode { uid } => serde_json::to_string(&client.add_node(uid).await?)?,
+        Request::DeleteNode { uid } => serde_json::to_string(&client.delete_node(uid).await?)?,
+        Request::Batch { ops, atomic } => serde_json::to_string(&client.batch(ops, atomic).await?)?,
+    };
+    println!("{}", reply);
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ReplayReport {
+    replayed: usize,
+    skipped: usize,
+}
+
+// Moves dead letters back to the request topic. Progress is committed to the
+// `REPLAY_GROUP` consumer group, so every dead letter is replayed once; the command
+// stops after `--limit` letters or when none arrived for `--idle-timeout` seconds.
+async fn replay_dead_letters(args: &[String]) -> Result<(), Error> {
+    let mut limit = usize::MAX;
+    let mut idle_timeout = Duration::from_secs(10);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--limit" => limit = value(&mut args, arg)?.parse().map_err(|_| Error::invalid_input(format!("invalid limit\n{}", USAGE)))?,
+            "--idle-timeout" => idle_timeout = Duration::from_secs(value(&mut args, arg)?.parse().map_err(|_| Error::invalid_input(format!("invalid idle timeout\n{}", USAGE)))?),
+            _ => return Err(Error::invalid_input(format!("unexpected argument: {}\n{}", arg, USAGE))),
+        }
+    }
+    let kafka_address = dotenv::var("KAFKA_ADDRESS").unwrap_or("localhost:9092".into());
+    let request_topic = dotenv::var("REQUEST_TOPIC").unwrap_or(DEFAULT_REQUEST_TOPIC.into());
+    let producer: FutureProducer = KafkaConfig::new().set("bootstrap.servers", &kafka_address).create()?;
+    let dead_letters: StreamConsumer = KafkaConfig::new()
+        .set("bootstrap.servers", &kafka_address)
+        .set("group.id", dotenv::var("REPLAY_GROUP").unwrap_or("with-baby-friendship-replay".into()))
+        .set("enable.auto.commit", "false")
+        .set("auto.offset.reset", "earliest")
+        .create()?;
+    dead_letters.subscribe(&[&dotenv::var("DEAD_LETTER_TOPIC").unwrap_or(DEFAULT_DEAD_LETTER_TOPIC.into())])?;
+    let processed = RedisProcessed::new(redis::Client::open("redis://localhost")?, DEFAULT_PROCESSED_TTL);
+    let mut report = ReplayReport { replayed: 0, skipped: 0 };
+    while report.replayed < limit {
+        let msg = match tokio::time::timeout(idle_timeout, dead_letters.recv()).await {
+            Ok(msg) => msg?,
+            Err(_) => break,
+        };
+        match serde_json::from_slice::<DeadLetter>(msg.payload().unwrap_or_default()) {
+            Ok(letter) => {
+                // The consumer stored the failure as the reply of the request, which would be
+                // replayed instead of running the request again.
+                if let Ok(envelope) = decode(letter.payload.as_bytes(), letter.key.as_deref().map(str::as_bytes), letter.correlation_id.as_deref()) {
+                    processed.release(envelope.request_id).await?;
+                }
+                let payload = without_deadline(&letter.payload);
+                let mut record = FutureRecord::to(&request_topic).payload(&payload);
+                if let Some(key) = &letter.key {
+                    record = record.key(key);
+                }
+                if let Some(correlation_id) = &letter.correlation_id {
+                    record = record.headers(OwnedHeaders::new().add(CORRELATION_HEADER, correlation_id));
+                }
+                producer.send(record, Duration::from_secs(10)).await.map_err(|(e, _)| e)?;
+                report.replayed += 1;
+            }
+            Err(e) => {
+                warn!("skipped invalid dead letter at offset {}: {}", msg.offset(), e);
+                report.skipped += 1;
+            }
+        }
+        dead_letters.commit_message(&msg, CommitMode::Sync)?;
+    }
+    println!("{}", serde_json::to_string(&report)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ErrorKind;
+    use crate::models::BatchOp;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_request() {
+        assert!(matches!(parse_request(&args("add 1 2")).unwrap(), Request::Add { uid_a: 1, uid_b: 2 }));
+        assert!(matches!(parse_request(&args("delete-node 3")).unwrap(), Request::DeleteNode { uid: 3 }));
+        match parse_request(&args(r#"batch [{"op":"Add","uid_a":1,"uid_b":2}] --atomic"#)).unwrap() {
+            Request::Batch { ops, atomic } => assert!(atomic && matches!(ops[..], [BatchOp::Add { uid_a: 1, uid_b: 2 }])),
+            request => panic!("unexpected request {:?}", request),
+        }
+        for line in ["add 1", "friends a", "friends 1 2", "batch [] --force", "batch {}", "unfriend 1 2"] {
+            assert_eq!(parse_request(&args(line)).unwrap_err().kind(), ErrorKind::InvalidInput, "{}", line);
+        }
+    }
+}
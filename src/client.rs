@@ -1,117 +1,196 @@
-use core::time;
-
-use anyhow::Error;
-use chrono::prelude::*;
-use rdkafka::{
-    producer::{
-        future_producer::FutureProducerContext, FutureProducer as KafkaProducer, FutureRecord,
-    },
-    util::Timeout,
-};
-use redis::{aio::Connection as RedisConnection, AsyncCommands};
-use serde::Serialize;
-use tokio;
-
-async fn gen_key(redis: &mut RedisConnection, uid: i64) -> Result<String, Error> {
-    let seq: i32 = redis.incr(format!("{}_seq", uid), 1).await?;
-    Ok(format!("{}-{}", Utc::now().timestamp(), seq))
+use crate::error::{Error, ErrorKind};
+use crate::models::{BatchOp, BatchOutcome};
+use crate::protocol::{reply_key, Channel, Envelope, Meta, Response, CORRELATION_HEADER, DEFAULT_REQUEST_TOPIC, PROTOCOL_VERSION};
+use crate::Request;
+use chrono::Utc;
+use log::debug;
+use rdkafka::config::ClientConfig as KafkaConfig;
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer as KafkaProducer, FutureRecord};
+use redis::{AsyncCommands, Client as RedisClient};
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub topic: String,
+    pub send_timeout: Duration,
+    pub reply_timeout: Duration,
+    pub caller: Option<String>,
 }
 
-pub async fn request<T: Send + Serialize>(
-    redis: &mut RedisConnection,
-    kafka: &KafkaProducer,
-    uid: i64,
-    topic: String,
-    req: T,
-    read_timeout: usize,
-) -> Result<String, Error> {
-    let key = gen_key(redis, uid).await?;
-    let body = serde_json::to_string(&req)?;
-    kafka
-        .send(
-            FutureRecord {
-                topic: &topic,
-                partition: None,
-                payload: Some(&body),
-                key: Some(&key),
-                timestamp: None,
-                headers: None,
-            },
-            Timeout::After(time::Duration::from_secs(10)),
-        )
-        .await
-        .map_err(|e| Error::msg(e.0.to_string()))?;
-    let mut res: Vec<String> = redis.blpop(key, read_timeout).await?;
-    Ok(res.pop().unwrap())
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            topic: DEFAULT_REQUEST_TOPIC.into(),
+            send_timeout: Duration::from_secs(10),
+            reply_timeout: Duration::from_secs(10),
+            caller: None,
+        }
+    }
 }
 
-use crate::Request;
+fn decode_reply<T: DeserializeOwned>(reply: &str) -> Result<T, Error> {
+    let response = serde_json::from_str::<Response<T>>(reply)?;
+    if let Meta {
+        request_id: Some(request_id),
+        elapsed_ms: Some(elapsed_ms),
+        ..
+    } = response.meta()
+    {
+        debug!("request {} served in {}ms", request_id, elapsed_ms);
+    }
+    response.into_result()
+}
 
-pub async fn add_node(
-    uid: i64,
-    redis: &mut RedisConnection,
-    kafka: &KafkaProducer,
-) -> Result<String, Error> {
-    request(
-        redis,
-        kafka,
-        uid,
-        "friendship".into(),
-        Request::AddNode { uid: uid },
-        10,
-    )
-    .await
+// Requests of a user are keyed by the uid they act on; a batch by the first uid it touches.
+fn partition(request: &Request) -> i64 {
+    match request {
+        Request::Add { uid_a, .. } | Request::Delete { uid_a, .. } => *uid_a,
+        Request::Friends { uid } | Request::Recommendation { uid } | Request::AddNode { uid } | Request::DeleteNode { uid } => *uid,
+        Request::Batch { ops, .. } => match ops.first() {
+            Some(BatchOp::Add { uid_a, .. } | BatchOp::Delete { uid_a, .. }) => *uid_a,
+            None => 0,
+        },
+    }
+}
+
+// Sends `Request`s to the friendship service over Kafka and waits for the reply the
+// service pushes to the Redis list named by `reply_key`. Messages are keyed by uid, so
+// the requests of one user stay ordered within a partition.
+pub struct Client {
+    redis: RedisClient,
+    kafka: KafkaProducer,
+    config: Config,
+}
+
+impl Client {
+    pub fn new(redis: RedisClient, kafka: KafkaProducer, config: Config) -> Self {
+        Self { redis, kafka, config }
+    }
+
+    pub fn connect(redis_uri: &str, kafka_servers: &str, config: Config) -> Result<Self, Error> {
+        let redis = RedisClient::open(redis_uri)?;
+        let kafka = KafkaConfig::new().set("bootstrap.servers", kafka_servers).create()?;
+        Ok(Self::new(redis, kafka, config))
+    }
+
+    pub async fn request<T: DeserializeOwned>(&self, request: Request) -> Result<T, Error> {
+        let mut redis = self.redis.get_async_connection().await?;
+        let correlation_id = Uuid::new_v4().to_string();
+        let key = reply_key(&correlation_id);
+        let partition_key = partition(&request).to_string();
+        let body = serde_json::to_string(&Envelope {
+            version: PROTOCOL_VERSION,
+            request_id: correlation_id.clone(),
+            reply_to: key.clone(),
+            reply_channel: Channel::Redis,
+            deadline: Some(Utc::now().timestamp_millis() + self.config.reply_timeout.as_millis() as i64),
+            caller: self.config.caller.clone(),
+            request,
+        })?;
+        let record = FutureRecord::to(&self.config.topic)
+            .payload(&body)
+            .key(&partition_key)
+            .headers(OwnedHeaders::new().add(CORRELATION_HEADER, &correlation_id));
+        self.kafka.send(record, self.config.send_timeout).await.map_err(|(e, _)| e)?;
+        let reply: Option<(String, String)> = redis.blpop(&key, self.config.reply_timeout.as_secs().max(1) as usize).await?;
+        match reply {
+            Some((_, reply)) => decode_reply(&reply),
+            None => {
+                // A reply pushed between the timeout and here would never be read.
+                redis.del::<_, ()>(&key).await?;
+                Err(Error::new(
+                    ErrorKind::Timeout,
+                    format!("no reply for request {} within {:?}", correlation_id, self.config.reply_timeout),
+                ))
+            }
+        }
+    }
+
+    pub async fn add(&self, uid_a: i64, uid_b: i64) -> Result<(), Error> {
+        self.request(Request::Add { uid_a, uid_b }).await
+    }
+
+    pub async fn delete(&self, uid_a: i64, uid_b: i64) -> Result<(), Error> {
+        self.request(Request::Delete { uid_a, uid_b }).await
+    }
+
+    pub async fn friends(&self, uid: i64) -> Result<Vec<i64>, Error> {
+        self.request(Request::Friends { uid }).await
+    }
+
+    pub async fn recommendation(&self, uid: i64) -> Result<Vec<i64>, Error> {
+        self.request(Request::Recommendation { uid }).await
+    }
+
+    pub async fn add_node(&self, uid: i64) -> Result<(), Error> {
+        self.request(Request::AddNode { uid }).await
+    }
+
+    // Returns the former friends of the deleted user.
+    pub async fn delete_node(&self, uid: i64) -> Result<Vec<i64>, Error> {
+        self.request(Request::DeleteNode { uid }).await
+    }
+
+    pub async fn batch(&self, ops: Vec<BatchOp<i64>>, atomic: bool) -> Result<BatchOutcome, Error> {
+        self.request(Request::Batch { ops, atomic }).await
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use rdkafka::config::ClientConfig as KafkaConfig;
+    use super::*;
+    use crate::protocol::correlation_id;
     use rdkafka::consumer::{Consumer, DefaultConsumerContext, StreamConsumer};
-    use rdkafka::producer::FutureProducer as KafkaProducer;
     use rdkafka::util::TokioRuntime;
     use rdkafka::Message;
-    use redis::{AsyncCommands, Client as RedisClient};
     use std::str::from_utf8;
 
+    #[test]
+    fn test_decode_reply() {
+        assert_eq!(decode_reply::<Vec<i64>>(r#"{"Ok":{"data":[2,3]}}"#).unwrap(), vec![2, 3]);
+        decode_reply::<()>(r#"{"Ok":{"data":null}}"#).unwrap();
+        let err = decode_reply::<()>(r#"{"Err":{"code":"NOT_FOUND","detail":"user 1 not found","request_id":"0f6e","elapsed_ms":3}}"#).unwrap_err();
+        assert_eq!((err.kind(), format!("{}", err)), (ErrorKind::NotFound, "user 1 not found".to_owned()));
+        assert_eq!(decode_reply::<()>("not json").unwrap_err().kind(), ErrorKind::Internal);
+    }
+
+    #[test]
+    fn test_partition() {
+        assert_eq!(partition(&Request::Delete { uid_a: 2, uid_b: 1 }), 2);
+        assert_eq!(partition(&Request::DeleteNode { uid: 3 }), 3);
+        let ops = vec![BatchOp::Delete { uid_a: 4, uid_b: 1 }, BatchOp::Add { uid_a: 5, uid_b: 1 }];
+        assert_eq!(partition(&Request::Batch { ops, atomic: true }), 4);
+        assert_eq!(partition(&Request::Batch { ops: vec![], atomic: false }), 0);
+    }
+
     #[tokio::test]
     async fn test_request() {
         tokio::spawn(async move {
             let redis = RedisClient::open("redis://localhost").unwrap();
-            let kafka: StreamConsumer<DefaultConsumerContext, TokioRuntime> = KafkaConfig::new()
-                .set("bootstrap.servers", "localhost:12092")
-                .set("group.id", "1")
-                .create()
-                .unwrap();
+            let kafka: StreamConsumer<DefaultConsumerContext, TokioRuntime> = KafkaConfig::new().set("bootstrap.servers", "localhost:12092").set("group.id", "1").create().unwrap();
             kafka.subscribe(&["friendship"]).unwrap();
             let data = kafka.recv().await.unwrap().detach();
-            let key = from_utf8(data.key().unwrap()).unwrap();
+            assert_eq!(data.key().unwrap(), b"1");
+            let key = reply_key(correlation_id(data.headers().unwrap()).unwrap());
             let value = from_utf8(data.payload().unwrap()).unwrap();
             println!("key: {}, value: {}", key, value);
-            redis
-                .get_async_connection()
-                .await
-                .unwrap()
-                .rpush::<&str, &str, ()>(key, value)
-                .await
-                .unwrap()
+            redis.get_async_connection().await.unwrap().rpush::<_, &str, ()>(key, r#"{"Ok":{"data":null}}"#).await.unwrap()
         });
-        let redis = RedisClient::open("redis://localhost").unwrap();
-        let kafka = KafkaProducer::from(
-            KafkaConfig::new()
-                .set("bootstrap.servers", "localhost:12092")
-                .create()
-                .unwrap(),
-        );
-        let res = super::request(
-            &mut redis.get_async_connection().await.unwrap(),
-            &kafka,
-            1,
-            "friendship".into(),
-            "hello world".to_owned(),
-            10,
-        )
-        .await
-        .unwrap();
-        println!("response: {}", res);
+        let client = Client::connect("redis://localhost", "localhost:12092", Config::default()).unwrap();
+        client.add_node(1).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let config = Config {
+            topic: "friendship_unconsumed".into(),
+            reply_timeout: Duration::from_secs(1),
+            ..Config::default()
+        };
+        let client = Client::connect("redis://localhost", "localhost:12092", config).unwrap();
+        assert_eq!(client.friends(1).await.unwrap_err().kind(), ErrorKind::Timeout);
     }
 }
@@ -0,0 +1,622 @@
+use crate::core::{Cacher, DeadLetterQueue, IdempotencyStore, Outputer, Persister};
+use crate::error::{Error, ErrorBody, ErrorKind};
+use crate::handlers::apply_batch;
+use crate::models::{BatchOp, DeadLetter};
+use crate::protocol::{correlation_id, decode, Meta, ReplyTo, Response};
+use crate::Request;
+use chrono::Utc;
+use log::{debug, warn};
+use rdkafka::consumer::{CommitMode, Consumer as _, StreamConsumer};
+use rdkafka::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_RETRIES: usize = 3;
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// What is kept per request ID: the claim of the consumer running the request, then its
+// reply. `Ok` and `Err` are stored like `Result`, as they were before claims existed.
+#[derive(Debug, Serialize, Deserialize)]
+enum Outcome {
+    Running,
+    Ok(Value),
+    Err(ErrorBody),
+}
+
+// `deadline` is a unix timestamp in milliseconds, requests without one never expire.
+fn expired(deadline: Option<i64>) -> bool {
+    deadline.is_some_and(|deadline| Utc::now().timestamp_millis() >= deadline)
+}
+
+// Failures worth retrying: the backends were unreachable or too slow, the request
+// itself may well succeed.
+fn transient(err: &Error) -> bool {
+    matches!(err.kind(), ErrorKind::Unavailable | ErrorKind::Timeout)
+}
+
+fn parse_uids(uids: Vec<String>) -> Result<Vec<i64>, Error> {
+    uids.into_iter()
+        .map(|uid| uid.parse().map_err(|_| Error::internal(format!("user id {} is not numeric", uid))))
+        .collect()
+}
+
+// Runs one request of the Kafka protocol, shared by every transport serving it.
+pub async fn handle<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: &P, cacher: &C, request: Request) -> Result<Value, Error> {
+    let (value, stale) = apply(persister, cacher, request).await?;
+    invalidate(cacher, stale).await?;
+    Ok(value)
+}
+
+// Runs the request up to its write, returning the reply and the users whose cached
+// friends the write made stale. Once this succeeded the write is committed, and running
+// it again would fail with "already exists" or "not found".
+async fn apply<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: &P, cacher: &C, request: Request) -> Result<(Value, Vec<String>), Error> {
+    match request {
+        Request::Add { uid_a, uid_b } => {
+            persister.insert(uid_a.to_string(), uid_b.to_string()).await?;
+            Ok((Value::Null, vec![uid_a.to_string(), uid_b.to_string()]))
+        }
+        Request::Delete { uid_a, uid_b } => {
+            persister.delete(uid_a.to_string(), uid_b.to_string()).await?;
+            Ok((Value::Null, vec![uid_a.to_string(), uid_b.to_string()]))
+        }
+        Request::Friends { uid } => {
+            let friends = match cacher.query(uid.to_string()).await? {
+                Some(friends) => friends,
+                None => persister.friends(uid.to_string()).await?,
+            };
+            Ok((serde_json::to_value(parse_uids(friends)?)?, Vec::new()))
+        }
+        Request::Recommendation { uid } => {
+            if !persister.exist_node(uid.to_string()).await? {
+                return Err(Error::not_found(format!("user {} not found", uid)));
+            }
+            Ok((serde_json::to_value(parse_uids(persister.recommendations(uid.to_string(), 2, 3).await?)?)?, Vec::new()))
+        }
+        Request::AddNode { uid } => {
+            persister.insert_node(uid.to_string()).await?;
+            Ok((Value::Null, Vec::new()))
+        }
+        Request::DeleteNode { uid } => {
+            // The reply can only carry numeric uids, which is checked before anything is deleted.
+            parse_uids(persister.all_friends(uid.to_string()).await?)?;
+            let friends = persister.delete_node(uid.to_string()).await?;
+            let value = serde_json::to_value(parse_uids(friends.clone())?)?;
+            Ok((value, std::iter::once(uid.to_string()).chain(friends).collect()))
+        }
+        Request::Batch { ops, atomic } => {
+            let ops = ops
+                .into_iter()
+                .map(|op| match op {
+                    BatchOp::Add { uid_a, uid_b } => BatchOp::Add {
+                        uid_a: uid_a.to_string(),
+                        uid_b: uid_b.to_string(),
+                    },
+                    BatchOp::Delete { uid_a, uid_b } => BatchOp::Delete {
+                        uid_a: uid_a.to_string(),
+                        uid_b: uid_b.to_string(),
+                    },
+                })
+                .collect();
+            let (outcome, stale) = apply_batch(persister, ops, atomic).await?;
+            Ok((serde_json::to_value(outcome)?, stale))
+        }
+    }
+}
+
+async fn invalidate<C: Cacher<UID = String>>(cacher: &C, stale: Vec<String>) -> Result<(), Error> {
+    for uid in stale {
+        cacher.delete(uid).await?;
+    }
+    Ok(())
+}
+
+// Serves the Kafka request protocol: every request is run against the persister and the
+// reply is written through the outputer to the address named in the envelope. Transient
+// failures are retried with exponential backoff, requests still failing after that are
+// pushed to the dead letter queue.
+pub struct Consumer<P, C, O, D, I> {
+    persister: P,
+    cacher: C,
+    outputer: O,
+    dead_letters: D,
+    processed: I,
+    retries: usize,
+    backoff: Duration,
+    lease: Duration,
+}
+
+impl<P: Persister<UID = String>, C: Cacher<UID = String>, O: Outputer, D: DeadLetterQueue, I: IdempotencyStore> Consumer<P, C, O, D, I> {
+    pub fn new(persister: P, cacher: C, outputer: O, dead_letters: D, processed: I) -> Self {
+        Self {
+            persister,
+            cacher,
+            outputer,
+            dead_letters,
+            processed,
+            retries: DEFAULT_RETRIES,
+            backoff: Duration::from_millis(100),
+            lease: DEFAULT_LEASE,
+        }
+    }
+
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    // Delay before the first retry, doubled for every following one.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    // How long a request is claimed for while it runs, retries included. A request whose
+    // consumer died is run by another one after that.
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    async fn release(&self, request_id: &str) {
+        if let Err(e) = self.processed.release(request_id.to_owned()).await {
+            warn!("failed to release request {}: {}", request_id, e);
+        }
+    }
+
+    async fn reply(&self, reply_to: ReplyTo, res: Result<Value, Error>, started: Instant) -> Result<(), Error> {
+        let address = reply_to.address.clone();
+        let meta = Meta::new(reply_to.request_id.clone(), started);
+        self.outputer
+            .reply(reply_to, Response::new(res, meta))
+            .await
+            .map_err(|e| Error::unavailable(format!("failed to write reply to {}: {}", address, e)))
+    }
+
+    // Waits before the next attempt, or returns false when `err` is not worth another one.
+    async fn retry(&self, err: &Error, attempts: &mut usize, backoff: &mut Duration, request_id: &str, deadline: Option<i64>) -> bool {
+        if !transient(err) || *attempts > self.retries || expired(deadline) {
+            return false;
+        }
+        warn!("attempt {} of request {} failed, retrying in {:?}: {}", attempts, request_id, backoff, err);
+        tokio::time::sleep(*backoff).await;
+        *backoff *= 2;
+        *attempts += 1;
+        true
+    }
+
+    // The request is retried until its write commits, after that only the cache
+    // invalidation is: running a committed write again would report it as failed.
+    async fn execute(&self, request: &Request, request_id: &str, deadline: Option<i64>) -> (Result<Value, Error>, usize) {
+        let mut attempts = 1;
+        let mut backoff = self.backoff;
+        let (value, stale) = loop {
+            match apply(&self.persister, &self.cacher, request.clone()).await {
+                Ok(applied) => break applied,
+                Err(e) if self.retry(&e, &mut attempts, &mut backoff, request_id, deadline).await => {}
+                Err(e) => return (Err(e), attempts),
+            }
+        };
+        loop {
+            match invalidate(&self.cacher, stale.clone()).await {
+                Ok(()) => break,
+                Err(e) if self.retry(&e, &mut attempts, &mut backoff, request_id, deadline).await => {}
+                // The write stands, so the request succeeded; stale friend lists are
+                // served until they are cached again.
+                Err(e) => {
+                    warn!("failed to invalidate the cached friends of {:?} for request {}: {}", stale, request_id, e);
+                    break;
+                }
+            }
+        }
+        (Ok(value), attempts)
+    }
+
+    // Returns once the message is dealt with and its offset may be committed. An error
+    // means it has to be processed again; a request which already ran is then answered
+    // with its stored reply instead of running twice.
+    pub async fn process(&self, payload: &[u8], key: Option<&[u8]>, correlation_id: Option<&str>) -> Result<(), Error> {
+        let started = Instant::now();
+        let envelope = match decode(payload, key, correlation_id) {
+            Ok(envelope) => envelope,
+            Err(rejected) => {
+                warn!("rejected request: {}", rejected.error);
+                if let Some(reply_to) = rejected.reply_to {
+                    self.reply(reply_to, Err(rejected.error), started).await?;
+                }
+                return Ok(());
+            }
+        };
+        let caller = envelope.caller.as_deref().unwrap_or("unknown caller");
+        let running = serde_json::to_string(&Outcome::Running)?;
+        if let Some(stored) = self.processed.claim(envelope.request_id.clone(), running, self.lease).await? {
+            let res = match serde_json::from_str(&stored)? {
+                // Processed again once the other consumer stored its reply, or its lease ran out.
+                Outcome::Running => return Err(Error::unavailable(format!("request {} is running on another consumer", envelope.request_id))),
+                Outcome::Ok(value) => Ok(value),
+                Outcome::Err(body) => Err(Error::new(body.code, body.detail)),
+            };
+            debug!("request {} from {} already processed, replaying its reply", envelope.request_id, caller);
+            return self.reply(envelope.reply(), res, started).await;
+        }
+        // The caller stopped waiting at the deadline, so neither running the request
+        // nor replying is of use after it.
+        if expired(envelope.deadline) {
+            warn!("skipped request {} from {}: deadline passed", envelope.request_id, caller);
+            self.release(&envelope.request_id).await;
+            return Ok(());
+        }
+        debug!("request {} (version {}) from {}", envelope.request_id, envelope.version, caller);
+        let (res, attempts) = self.execute(&envelope.request, &envelope.request_id, envelope.deadline).await;
+        if let Err(e) = &res {
+            if transient(e) {
+                let letter = DeadLetter {
+                    payload: String::from_utf8_lossy(payload).into_owned(),
+                    key: key.map(|key| String::from_utf8_lossy(key).into_owned()),
+                    correlation_id: correlation_id.map(String::from),
+                    error: e.body(),
+                    attempts,
+                    failed_at: Utc::now().to_rfc3339(),
+                };
+                if let Err(e) = self.dead_letters.push(letter).await {
+                    self.release(&envelope.request_id).await;
+                    return Err(e);
+                }
+            }
+        }
+        // The reply is stored before it is written, so a message processed again after a
+        // failed write is answered from the store instead of running, or being dead
+        // lettered, twice. Replaying a dead letter releases its request first.
+        let outcome = match &res {
+            Ok(value) => Outcome::Ok(value.clone()),
+            Err(e) => Outcome::Err(e.body()),
+        };
+        if let Err(e) = self.processed.put(envelope.request_id.clone(), serde_json::to_string(&outcome)?).await {
+            warn!("failed to record request {} as processed: {}", envelope.request_id, e);
+        }
+        if expired(envelope.deadline) {
+            warn!("dropped reply to request {} from {}: deadline passed", envelope.request_id, caller);
+            return Ok(());
+        }
+        self.reply(envelope.reply(), res, started).await
+    }
+
+    // Offsets are committed only after a message is processed, so a crash redelivers the
+    // message instead of losing it. Failed messages are retried in place, which holds
+    // back the partition until the backends are reachable again.
+    pub async fn run(&self, consumer: StreamConsumer) {
+        loop {
+            let msg = match consumer.recv().await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("failed to receive request: {}", e);
+                    continue;
+                }
+            };
+            let mut backoff = self.backoff;
+            while let Err(e) = self
+                .process(msg.payload().unwrap_or_default(), msg.key(), msg.headers().and_then(|headers| correlation_id(headers)))
+                .await
+            {
+                warn!("failed to process message at offset {}, retrying in {:?}: {}", msg.offset(), backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            if let Err(e) = consumer.commit_message(&msg, CommitMode::Async) {
+                warn!("failed to commit offset {}: {}", msg.offset(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cachers::Memory as MemoryCache;
+    use crate::core::BoxFuture;
+    use crate::events::Event;
+    use crate::outputers::Memory as Replies;
+    use crate::persisters::Memory;
+    use crate::protocol::{Channel, Envelope, PROTOCOL_VERSION};
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct Processed(RefCell<HashMap<String, String>>);
+
+    // Leases never run out here.
+    impl IdempotencyStore for Processed {
+        fn claim(&self, request_id: String, marker: String, _lease: Duration) -> BoxFuture<Option<String>> {
+            let stored = self.0.borrow().get(&request_id).cloned();
+            if stored.is_none() {
+                self.0.borrow_mut().insert(request_id, marker);
+            }
+            Box::pin(async move { Ok(stored) })
+        }
+
+        fn put(&self, request_id: String, reply: String) -> BoxFuture<()> {
+            self.0.borrow_mut().insert(request_id, reply);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn release(&self, request_id: String) -> BoxFuture<()> {
+            self.0.borrow_mut().remove(&request_id);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[derive(Default)]
+    struct DeadLetters(RefCell<Vec<DeadLetter>>);
+
+    impl DeadLetterQueue for DeadLetters {
+        fn push(&self, letter: DeadLetter) -> BoxFuture<()> {
+            self.0.borrow_mut().push(letter);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    // Fails `query`, or `delete`, as if Redis were down the given number of times before
+    // answering.
+    #[derive(Default)]
+    struct Flaky {
+        failures: Cell<usize>,
+        delete_failures: Cell<usize>,
+        inner: MemoryCache,
+    }
+
+    impl Cacher for Flaky {
+        type UID = String;
+        fn insert(&self, uid: Self::UID, friends: Vec<Self::UID>) -> BoxFuture<()> {
+            self.inner.insert(uid, friends)
+        }
+
+        fn delete(&self, uid: Self::UID) -> BoxFuture<()> {
+            if self.delete_failures.get() > 0 {
+                self.delete_failures.set(self.delete_failures.get() - 1);
+                return Box::pin(async { Err(Error::unavailable("connection refused".into())) });
+            }
+            self.inner.delete(uid)
+        }
+
+        fn query(&self, uid: Self::UID) -> BoxFuture<Option<Vec<Self::UID>>> {
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Box::pin(async { Err(Error::unavailable("connection refused".into())) });
+            }
+            self.inner.query(uid)
+        }
+
+        fn query_many(&self, uids: Vec<Self::UID>) -> BoxFuture<Vec<Option<Vec<Self::UID>>>> {
+            self.inner.query_many(uids)
+        }
+    }
+
+    fn consumer<C: Cacher<UID = String> + Default>() -> Consumer<Memory, C, Replies, DeadLetters, Processed> {
+        Consumer::new(Memory::new(), C::default(), Replies::default(), DeadLetters::default(), Processed::default())
+    }
+
+    fn envelope(request_id: &str, request: Request) -> Vec<u8> {
+        envelope_with_deadline(request_id, request, None)
+    }
+
+    fn envelope_with_deadline(request_id: &str, request: Request, deadline: Option<i64>) -> Vec<u8> {
+        serde_json::to_vec(&Envelope {
+            version: PROTOCOL_VERSION,
+            request_id: request_id.into(),
+            reply_to: format!("reply:{}", request_id),
+            reply_channel: Channel::Redis,
+            deadline,
+            caller: Some("test".into()),
+            request,
+        })
+        .unwrap()
+    }
+
+    fn last_reply<P, C, D, I>(consumer: &Consumer<P, C, Replies, D, I>) -> (String, String) {
+        consumer.outputer.replies().last().cloned().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_process() {
+        let consumer = consumer::<MemoryCache>();
+        for uid in 1..=3 {
+            consumer.process(&envelope(&format!("n{}", uid), Request::AddNode { uid }), None, None).await.unwrap();
+        }
+        consumer.process(&envelope("a", Request::Add { uid_a: 1, uid_b: 2 }), None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:a".into(), r#"{"Ok":{"data":null}}"#.into()));
+        let (_, response) = consumer.outputer.responses().pop().unwrap();
+        let meta = response.meta();
+        assert_eq!(meta.request_id.as_deref(), Some("a"));
+        assert!(meta.served_at.is_some() && meta.elapsed_ms.is_some());
+        consumer.process(&envelope("f", Request::Friends { uid: 2 }), None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:f".into(), r#"{"Ok":{"data":[1]}}"#.into()));
+        consumer.process(&envelope("r", Request::Recommendation { uid: 4 }), None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:r".into(), r#"{"Err":{"code":"NOT_FOUND","detail":"user 4 not found"}}"#.into()));
+        consumer
+            .process(
+                &envelope(
+                    "b",
+                    Request::Batch {
+                        ops: vec![BatchOp::Add { uid_a: 1, uid_b: 3 }],
+                        atomic: true,
+                    },
+                ),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(last_reply(&consumer).1, r#"{"Ok":{"data":{"committed":true,"results":[null]}}}"#);
+        consumer.process(&envelope("d", Request::DeleteNode { uid: 1 }), None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer).1, r#"{"Ok":{"data":[2,3]}}"#);
+        let uid = |uid: i64| uid.to_string();
+        assert_eq!(
+            consumer.persister.pending(10).await.unwrap().into_iter().map(|pending| pending.event).collect::<Vec<_>>(),
+            vec![
+                Event::UserCreated { uid: uid(1) },
+                Event::UserCreated { uid: uid(2) },
+                Event::UserCreated { uid: uid(3) },
+                Event::FriendAdded { uid: uid(1), friend: uid(2) },
+                Event::FriendAdded { uid: uid(1), friend: uid(3) },
+                Event::UserDeleted {
+                    uid: uid(1),
+                    friends: vec![uid(2), uid(3)]
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_node_non_numeric_friend() {
+        let consumer = consumer::<MemoryCache>();
+        // Users created over REST can have any uid.
+        for uid in ["1", "alice"] {
+            consumer.persister.insert_node(uid.into()).await.unwrap();
+        }
+        consumer.persister.insert("1".into(), "alice".into()).await.unwrap();
+        consumer.process(&envelope("d", Request::DeleteNode { uid: 1 }), None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer).1, r#"{"Err":{"code":"INTERNAL","detail":"user id alice is not numeric"}}"#);
+        assert!(consumer.persister.exist_node("1".into()).await.unwrap());
+        assert!(consumer.persister.is_friend("1".into(), "alice".into()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_process_legacy_and_rejected() {
+        let consumer = consumer::<MemoryCache>();
+        consumer.process(br#"{"AddNode":{"uid":1}}"#, Some(b"1700000000-1"), None).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("1700000000-1".into(), r#"{"Ok":{"data":null}}"#.into()));
+        consumer.process(br#"{"Friends":{"uid":1}}"#, Some(b"1"), Some("0f6e")).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:0f6e".into(), r#"{"Ok":{"data":[]}}"#.into()));
+        consumer.process(br#"{"version":9,"request_id":"x","reply_to":"reply:x","request":{}}"#, None, None).await.unwrap();
+        assert_eq!(
+            last_reply(&consumer),
+            ("reply:x".into(), r#"{"Err":{"code":"INVALID_INPUT","detail":"unsupported protocol version 9"}}"#.into())
+        );
+        consumer.process(b"garbage", None, None).await.unwrap();
+        assert_eq!(consumer.outputer.replies().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_process_deadline() {
+        let consumer = consumer::<MemoryCache>();
+        let now = Utc::now().timestamp_millis();
+        consumer.process(&envelope_with_deadline("late", Request::AddNode { uid: 1 }, Some(now - 1)), None, None).await.unwrap();
+        assert!(consumer.outputer.replies().is_empty());
+        assert!(!consumer.persister.exist_node(1.to_string()).await.unwrap());
+        consumer
+            .process(&envelope_with_deadline("on-time", Request::AddNode { uid: 1 }, Some(now + 60_000)), None, None)
+            .await
+            .unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:on-time".into(), r#"{"Ok":{"data":null}}"#.into()));
+        assert!(consumer.persister.exist_node(1.to_string()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_process_retry() {
+        let consumer = consumer::<Flaky>().backoff(Duration::from_millis(1));
+        consumer.persister.insert_node(1.to_string()).await.unwrap();
+        consumer.cacher.failures.set(DEFAULT_RETRIES);
+        consumer.process(&envelope("f", Request::Friends { uid: 1 }), Some(b"1"), Some("f")).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:f".into(), r#"{"Ok":{"data":[]}}"#.into()));
+        assert!(consumer.dead_letters.0.borrow().is_empty());
+
+        consumer.cacher.failures.set(DEFAULT_RETRIES + 1);
+        let payload = envelope("g", Request::Friends { uid: 1 });
+        consumer.process(&payload, Some(b"1"), Some("g")).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:g".into(), r#"{"Err":{"code":"UNAVAILABLE","detail":"connection refused"}}"#.into()));
+        let letters = consumer.dead_letters.0.borrow();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].payload.as_bytes(), payload.as_slice());
+        assert_eq!((letters[0].key.as_deref(), letters[0].correlation_id.as_deref()), (Some("1"), Some("g")));
+        assert_eq!((letters[0].error.code, letters[0].attempts), (ErrorKind::Unavailable, DEFAULT_RETRIES + 1));
+    }
+
+    #[tokio::test]
+    async fn test_process_invalidation_retry() {
+        let consumer = consumer::<Flaky>().backoff(Duration::from_millis(1));
+        consumer.persister.insert_nodes(vec![1.to_string(), 2.to_string()]).await.unwrap();
+        consumer.cacher.inner.insert(1.to_string(), vec![]).await.unwrap();
+        consumer.cacher.delete_failures.set(1);
+        consumer.process(&envelope("a", Request::Add { uid_a: 1, uid_b: 2 }), None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:a".into(), r#"{"Ok":{"data":null}}"#.into()));
+        assert_eq!(consumer.cacher.inner.query(1.to_string()).await.unwrap(), None);
+
+        // A cache which stays down leaves stale friends behind, the committed write still succeeded.
+        consumer.cacher.delete_failures.set(DEFAULT_RETRIES + 1);
+        consumer.process(&envelope("d", Request::Delete { uid_a: 1, uid_b: 2 }), None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:d".into(), r#"{"Ok":{"data":null}}"#.into()));
+        assert!(consumer.dead_letters.0.borrow().is_empty());
+        assert!(!consumer.persister.is_friend(1.to_string(), 2.to_string()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_process_no_retry() {
+        let consumer = consumer::<MemoryCache>().retries(5);
+        consumer.process(&envelope("a", Request::Add { uid_a: 1, uid_b: 2 }), None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:a".into(), r#"{"Err":{"code":"NOT_FOUND","detail":"user 1 not found"}}"#.into()));
+        assert!(consumer.dead_letters.0.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_process_duplicate() {
+        let consumer = consumer::<MemoryCache>();
+        for uid in 1..=2 {
+            consumer.process(&envelope(&format!("n{}", uid), Request::AddNode { uid }), None, None).await.unwrap();
+        }
+        let payload = envelope("a", Request::Add { uid_a: 1, uid_b: 2 });
+        consumer.process(&payload, None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:a".into(), r#"{"Ok":{"data":null}}"#.into()));
+        // Run again the request would fail with "already exists".
+        consumer.process(&payload, None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:a".into(), r#"{"Ok":{"data":null}}"#.into()));
+        assert_eq!(consumer.outputer.replies().len(), 4);
+
+        let payload = envelope("r", Request::Recommendation { uid: 3 });
+        consumer.process(&payload, None, None).await.unwrap();
+        consumer.persister.insert_node(3.to_string()).await.unwrap();
+        consumer.process(&payload, None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:r".into(), r#"{"Err":{"code":"NOT_FOUND","detail":"user 3 not found"}}"#.into()));
+    }
+
+    #[tokio::test]
+    async fn test_process_reply_failure() {
+        let consumer = consumer::<MemoryCache>();
+        consumer.outputer.fail(1);
+        let payload = envelope("n", Request::AddNode { uid: 1 });
+        assert_eq!(consumer.process(&payload, None, None).await.unwrap_err().kind(), ErrorKind::Unavailable);
+        assert!(consumer.persister.exist_node(1.to_string()).await.unwrap());
+        // Processing the message again only writes the stored reply.
+        consumer.process(&payload, None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:n".into(), r#"{"Ok":{"data":null}}"#.into()));
+    }
+
+    #[tokio::test]
+    async fn test_process_dead_letter_reply_failure() {
+        let consumer = consumer::<Flaky>().backoff(Duration::from_millis(1));
+        consumer.persister.insert_node(1.to_string()).await.unwrap();
+        consumer.cacher.failures.set(DEFAULT_RETRIES + 1);
+        consumer.outputer.fail(1);
+        let payload = envelope("f", Request::Friends { uid: 1 });
+        assert_eq!(consumer.process(&payload, None, None).await.unwrap_err().kind(), ErrorKind::Unavailable);
+        consumer.process(&payload, None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:f".into(), r#"{"Err":{"code":"UNAVAILABLE","detail":"connection refused"}}"#.into()));
+        assert_eq!(consumer.dead_letters.0.borrow().len(), 1);
+        // Replaying the dead letter releases the request, which then runs again.
+        consumer.processed.release("f".into()).await.unwrap();
+        consumer.process(&payload, None, None).await.unwrap();
+        assert_eq!(last_reply(&consumer), ("reply:f".into(), r#"{"Ok":{"data":[]}}"#.into()));
+    }
+
+    #[tokio::test]
+    async fn test_process_claimed() {
+        let consumer = consumer::<MemoryCache>();
+        let running = serde_json::to_string(&Outcome::Running).unwrap();
+        consumer.processed.claim("n".into(), running, DEFAULT_LEASE).await.unwrap();
+        let payload = envelope("n", Request::AddNode { uid: 1 });
+        assert_eq!(consumer.process(&payload, None, None).await.unwrap_err().kind(), ErrorKind::Unavailable);
+        assert!(!consumer.persister.exist_node(1.to_string()).await.unwrap());
+        assert!(consumer.outputer.replies().is_empty());
+    }
+}
@@ -1,22 +1,112 @@
 use crate::error::Error;
+use crate::events::{Event, OutboxEvent};
+use crate::models::{BatchOp, BatchOutcome, DeadLetter, DeliveryFailure, FriendshipPair, InsertOutcome, UserExport};
+use crate::protocol::{ReplyTo, Response};
+use futures_util::Stream;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::future::Future;
 use std::pin::Pin;
+use std::time::Duration;
+
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = Result<T, Error>>>>;
+pub type EventStream<UID> = Pin<Box<dyn Stream<Item = Event<UID>>>>;
 
 pub trait Persister {
     type UID;
-    fn insert_node(&self, uid: Self::UID) -> Pin<Box<dyn Future<Output = Result<(), Error>>>>;
-    fn delete_node(&self, uid: Self::UID) -> Pin<Box<dyn Future<Output = Result<(), Error>>>>;
-    fn exist_node(&self, uid: Self::UID) -> Pin<Box<dyn Future<Output = Result<bool, Error>>>>;
-    fn insert(&self, uid_a: Self::UID, uid_b: Self::UID) -> Pin<Box<dyn Future<Output = Result<(), Error>>>>;
-    fn delete(&self, uid_a: Self::UID, uid_b: Self::UID) -> Pin<Box<dyn Future<Output = Result<(), Error>>>>;
-    fn friends(&self, uid: Self::UID) -> Pin<Box<dyn Future<Output = Result<Vec<Self::UID>, Error>>>>;
-    fn is_friend(&self, uid_a: Self::UID, uid_b: Self::UID) -> Pin<Box<dyn Future<Output = Result<bool, Error>>>>;
-    fn recommendations(&self, uid: Self::UID, level: i32, threshold: i32) -> Pin<Box<dyn Future<Output = Result<Vec<Self::UID>, Error>>>>;
+    fn insert_node(&self, uid: Self::UID) -> BoxFuture<()>;
+    fn delete_node(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>>;
+    fn exist_node(&self, uid: Self::UID) -> BoxFuture<bool>;
+    fn insert(&self, uid_a: Self::UID, uid_b: Self::UID) -> BoxFuture<()>;
+    fn delete(&self, uid_a: Self::UID, uid_b: Self::UID) -> BoxFuture<()>;
+    fn friends(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>>;
+    // Friends including deactivated ones, that is everything `delete_node` detaches.
+    fn all_friends(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>>;
+    fn friends_many(&self, uids: Vec<Self::UID>) -> BoxFuture<BTreeMap<Self::UID, Vec<Self::UID>>>;
+    fn is_friend(&self, uid_a: Self::UID, uid_b: Self::UID) -> BoxFuture<bool>;
+    fn recommendations(&self, uid: Self::UID, level: i32, threshold: i32) -> BoxFuture<Vec<Self::UID>>;
+    fn deactivate(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>>;
+    fn reactivate(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>>;
+    fn export(&self, uid: Self::UID) -> BoxFuture<UserExport<Self::UID>>;
+    // The properties of each user, as in `export`. Users which don't exist are left out.
+    fn profiles(&self, uids: Vec<Self::UID>) -> BoxFuture<BTreeMap<Self::UID, BTreeMap<String, String>>>;
+    // Returns the users which were created, existing ones are left as they are. Like the
+    // single mutations, the bulk ones record an event for each user or friendship they change.
+    fn insert_nodes(&self, uids: Vec<Self::UID>) -> BoxFuture<Vec<Self::UID>>;
+    // Creates the friendship of each `(uid_a, uid_b, created_at)` as if `uid_a` added `uid_b`,
+    // at `created_at` (RFC 3339) when given, else now. Pairs are refused for the reasons
+    // `insert` fails, except that existing friendships are left as they are.
+    fn insert_many(&self, pairs: Vec<(Self::UID, Self::UID, Option<String>)>) -> BoxFuture<InsertOutcome<Self::UID>>;
+    // Deactivates the active users among `uids`, missing and deactivated ones are skipped.
+    // Returns the friends of the deactivated users, like `deactivate`.
+    fn deactivate_many(&self, uids: Vec<Self::UID>) -> BoxFuture<Vec<Self::UID>>;
+    fn batch(&self, ops: Vec<BatchOp<Self::UID>>, atomic: bool) -> BoxFuture<BatchOutcome>;
+    // Users ordered by uid, starting after `after`, so pages stay stable while the graph changes.
+    fn list_users(&self, after: Option<Self::UID>, limit: usize) -> BoxFuture<Vec<(Self::UID, bool)>>;
+    // Friendships each listed once whichever way it was created, ordered by the pair and
+    // paged like `list_users`.
+    fn list_friendships(&self, after: Option<(Self::UID, Self::UID)>, limit: usize) -> BoxFuture<Vec<FriendshipPair<Self::UID>>>;
 }
 
 pub trait Cacher {
     type UID;
-    fn insert(&self, uid: Self::UID, friends: Vec<Self::UID>) -> Pin<Box<dyn Future<Output = Result<(), Error>>>>;
-    fn delete(&self, uid: Self::UID) -> Pin<Box<dyn Future<Output = Result<(), Error>>>>;
-    fn query(&self, uid: Self::UID) -> Pin<Box<dyn Future<Output = Result<Option<Vec<Self::UID>>, Error>>>>;
+    fn insert(&self, uid: Self::UID, friends: Vec<Self::UID>) -> BoxFuture<()>;
+    fn delete(&self, uid: Self::UID) -> BoxFuture<()>;
+    fn query(&self, uid: Self::UID) -> BoxFuture<Option<Vec<Self::UID>>>;
+    fn query_many(&self, uids: Vec<Self::UID>) -> BoxFuture<Vec<Option<Vec<Self::UID>>>>;
+}
+
+pub trait Publisher {
+    type UID;
+    fn publish(&self, event: Event<Self::UID>) -> BoxFuture<()>;
+}
+
+// Live events of one user, as they are published. Events published before subscribing
+// are not replayed.
+pub trait Subscriber {
+    type UID;
+    fn subscribe(&self, uid: Self::UID) -> BoxFuture<EventStream<Self::UID>>;
+}
+
+// Events stored by the persister in the transaction of the mutation that caused them,
+// waiting to be published by `relay::Relay`.
+pub trait Outbox {
+    type UID;
+    // Claims the oldest undelivered events for `owner` for `lease`, returning them in order.
+    // Returns nothing while another owner holds a live claim, so only one relay publishes
+    // at a time and events are published in order; a crashed relay's claims expire.
+    fn claim(&self, owner: String, limit: usize, lease: Duration) -> BoxFuture<Vec<OutboxEvent<Self::UID>>>;
+    fn mark_delivered(&self, ids: Vec<String>) -> BoxFuture<()>;
+    // Deletes at most `limit` events delivered more than `retention` ago, returns how many.
+    fn purge(&self, retention: Duration, limit: usize) -> BoxFuture<usize>;
+}
+
+pub trait DeadLetterQueue {
+    fn push(&self, letter: DeadLetter) -> BoxFuture<()>;
+}
+
+pub trait DeliveryFailureLog {
+    fn record(&self, failure: DeliveryFailure) -> BoxFuture<()>;
+}
+
+// Replies of processed requests by request ID, so redelivered requests are answered
+// without running them again.
+pub trait IdempotencyStore {
+    // Stores `marker` for `lease` unless something is stored for the request already, in
+    // which case that is returned. Only one consumer gets to claim a request.
+    fn claim(&self, request_id: String, marker: String, lease: Duration) -> BoxFuture<Option<String>>;
+    fn put(&self, request_id: String, reply: String) -> BoxFuture<()>;
+    // Forgets the request, so it runs again when it is delivered again.
+    fn release(&self, request_id: String) -> BoxFuture<()>;
+}
+
+// Writes the reply to a request to the destination the request named.
+pub trait Outputer {
+    fn reply<T: Serialize>(&self, to: ReplyTo, response: Response<T>) -> BoxFuture<()>;
+}
+
+// Synchronous counterpart of `Outputer`, see `outputers::Blocking`.
+pub trait BlockingOutputer<K, E, RE> {
+    fn ok<T: Serialize>(&self, key: K, data: T) -> Result<(), RE>;
+    fn error(&self, key: K, err: E) -> Result<(), RE>;
 }
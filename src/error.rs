@@ -1,26 +1,85 @@
 use std::fmt::Display;
 
-use actix_web::{body::BoxBody, http::StatusCode, HttpResponse, ResponseError};
+use crate::protocol::{Meta, Response};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use rdkafka::error::KafkaError;
+use redis::RedisError;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorKind {
+    NotFound,
+    AlreadyExists,
+    Conflict,
+    InvalidInput,
+    Unavailable,
+    Timeout,
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::AlreadyExists | ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+            ErrorKind::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Error {
+    kind: ErrorKind,
     msg: String,
-    code: StatusCode,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: ErrorKind,
+    pub detail: String,
 }
 
 impl Error {
-    pub fn new(msg: String, code: StatusCode) -> Self {
-        Self { msg, code }
+    pub fn new(kind: ErrorKind, msg: String) -> Self {
+        Self { kind, msg }
+    }
+
+    pub fn not_found(msg: String) -> Self {
+        Self::new(ErrorKind::NotFound, msg)
+    }
+
+    pub fn already_exists(msg: String) -> Self {
+        Self::new(ErrorKind::AlreadyExists, msg)
     }
 
-    pub fn new_404(msg: String) -> Self {
-        Self { msg, code: StatusCode::NOT_FOUND }
+    pub fn conflict(msg: String) -> Self {
+        Self::new(ErrorKind::Conflict, msg)
     }
 
-    pub fn new_500(msg: String) -> Self {
-        Self {
-            msg,
-            code: StatusCode::INTERNAL_SERVER_ERROR,
+    pub fn invalid_input(msg: String) -> Self {
+        Self::new(ErrorKind::InvalidInput, msg)
+    }
+
+    pub fn unavailable(msg: String) -> Self {
+        Self::new(ErrorKind::Unavailable, msg)
+    }
+
+    pub fn internal(msg: String) -> Self {
+        Self::new(ErrorKind::Internal, msg)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            code: self.kind,
+            detail: self.msg.clone(),
         }
     }
 }
@@ -32,19 +91,96 @@ impl Display for Error {
 }
 
 impl ResponseError for Error {
-    fn error_response(&self) -> HttpResponse<BoxBody> {
-        HttpResponse::with_body(self.code, BoxBody::new(self.msg.clone()))
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(Response::<()>::new(Err(self.clone()), Meta::default()))
     }
     fn status_code(&self) -> StatusCode {
-        self.code
+        self.kind().status_code()
+    }
+}
+
+impl From<RedisError> for Error {
+    fn from(value: RedisError) -> Self {
+        if value.is_io_error() || value.is_connection_refusal() || value.is_connection_dropped() || value.is_timeout() {
+            return Self::unavailable(format!("{}", value));
+        }
+        Self::internal(format!("{}", value))
     }
 }
 
-impl<T: std::error::Error> From<T> for Error {
-    fn from(value: T) -> Self {
-        Self {
-            msg: format!("{}", value),
-            code: StatusCode::INTERNAL_SERVER_ERROR,
+impl From<neo4rs::Error> for Error {
+    fn from(value: neo4rs::Error) -> Self {
+        match value {
+            neo4rs::Error::IOError { .. } | neo4rs::Error::ConnectionError => Self::unavailable(format!("{:?}", value)),
+            neo4rs::Error::UnexpectedMessage(ref msg) if msg.contains("ConstraintValidationFailed") => Self::already_exists(msg.clone()),
+            neo4rs::Error::UnexpectedMessage(ref msg) if msg.contains("TransientError") => Self::conflict(msg.clone()),
+            _ => Self::internal(format!("{:?}", value)),
         }
     }
 }
+
+impl From<KafkaError> for Error {
+    fn from(value: KafkaError) -> Self {
+        Self::unavailable(format!("{}", value))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::internal(format!("{}", value))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Self::internal(format!("{}", value))
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(value: reqwest::Error) -> Self {
+        if value.is_timeout() {
+            return Self::new(ErrorKind::Timeout, format!("{}", value));
+        }
+        if value.is_connect() {
+            return Self::unavailable(format!("{}", value));
+        }
+        Self::internal(format!("{}", value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::body::to_bytes;
+
+    #[test]
+    fn test_status_code() {
+        assert_eq!(Error::not_found("".into()).status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(Error::already_exists("".into()).status_code(), StatusCode::CONFLICT);
+        assert_eq!(Error::conflict("".into()).status_code(), StatusCode::CONFLICT);
+        assert_eq!(Error::invalid_input("".into()).status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(Error::unavailable("".into()).status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(Error::new(ErrorKind::Timeout, "".into()).status_code(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(Error::internal("".into()).status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn test_error_response() {
+        let resp = Error::already_exists("user 1 already exists".into()).error_response();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, serde_json::json!({"Err": {"code": "ALREADY_EXISTS", "detail": "user 1 already exists"}}));
+    }
+
+    #[test]
+    fn test_from_neo4j_error() {
+        assert_eq!(Error::from(neo4rs::Error::ConnectionError).kind(), ErrorKind::Unavailable);
+        assert_eq!(Error::from(neo4rs::Error::ConverstionError).kind(), ErrorKind::Internal);
+        assert_eq!(
+            Error::from(neo4rs::Error::UnexpectedMessage("Neo.TransientError.Transaction.DeadlockDetected".into())).kind(),
+            ErrorKind::Conflict
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+
+// Published after every successful mutation. `uid` is the user the event is about,
+// for friendships the one who added or removed the friend.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Event<UID> {
+    UserCreated { uid: UID },
+    UserDeleted { uid: UID, friends: Vec<UID> },
+    FriendAdded { uid: UID, friend: UID },
+    FriendRemoved { uid: UID, friend: UID },
+    // The user is hidden from, or shown again in, the friend lists of `friends`.
+    UserDeactivated { uid: UID, friends: Vec<UID> },
+    UserReactivated { uid: UID, friends: Vec<UID> },
+}
+
+impl<UID> Event<UID> {
+    // Events are keyed by this, so the events of one user stay in order.
+    pub fn uid(&self) -> &UID {
+        match self {
+            Self::UserCreated { uid }
+            | Self::UserDeleted { uid, .. }
+            | Self::FriendAdded { uid, .. }
+            | Self::FriendRemoved { uid, .. }
+            | Self::UserDeactivated { uid, .. }
+            | Self::UserReactivated { uid, .. } => uid,
+        }
+    }
+
+    // Every user whose friends the event changes, they are notified of it.
+    pub fn users(&self) -> Vec<&UID> {
+        match self {
+            Self::UserCreated { uid } => vec![uid],
+            Self::UserDeleted { uid, friends } | Self::UserDeactivated { uid, friends } | Self::UserReactivated { uid, friends } => std::iter::once(uid).chain(friends).collect(),
+            Self::FriendAdded { uid, friend } | Self::FriendRemoved { uid, friend } => vec![uid, friend],
+        }
+    }
+}
+
+// An event waiting in the outbox, `id` identifies it when it is marked delivered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxEvent<UID> {
+    pub id: String,
+    pub event: Event<UID>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serialize_event() {
+        let event = Event::UserDeleted {
+            uid: 1.to_string(),
+            friends: vec![2.to_string()],
+        };
+        let s = serde_json::to_string(&event).unwrap();
+        assert_eq!(s, r#"{"type":"UserDeleted","uid":"1","friends":["2"]}"#);
+        assert_eq!(serde_json::from_str::<Event<String>>(&s).unwrap(), event);
+
+        let event = Event::FriendAdded {
+            uid: 1.to_string(),
+            friend: 2.to_string(),
+        };
+        let s = serde_json::to_string(&event).unwrap();
+        assert_eq!(s, r#"{"type":"FriendAdded","uid":"1","friend":"2"}"#);
+        assert_eq!(serde_json::from_str::<Event<String>>(&s).unwrap(), event);
+        assert_eq!(serde_json::to_string(&Event::UserCreated { uid: 3 }).unwrap(), r#"{"type":"UserCreated","uid":3}"#);
+    }
+
+    #[test]
+    fn test_event_uid() {
+        assert_eq!(Event::UserCreated { uid: 1 }.uid(), &1);
+        assert_eq!(Event::FriendRemoved { uid: 2, friend: 1 }.uid(), &2);
+        assert_eq!(Event::FriendRemoved { uid: 2, friend: 1 }.users(), vec![&2, &1]);
+        assert_eq!(Event::UserDeleted { uid: 1, friends: vec![2, 3] }.users(), vec![&1, &2, &3]);
+        assert_eq!(Event::UserDeactivated { uid: 1, friends: vec![2] }.users(), vec![&1, &2]);
+    }
+}
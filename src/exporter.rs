@@ -0,0 +1,240 @@
+use crate::core::Persister;
+use crate::error::Error;
+use crate::models::Record;
+use log::info;
+use serde::Serialize;
+use std::io::Write;
+use std::str::FromStr;
+
+pub const DEFAULT_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Jsonl,
+    Csv,
+    Graphml,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            "graphml" => Ok(Format::Graphml),
+            _ => Err(Error::invalid_input(format!("unsupported export format: {}", s))),
+        }
+    }
+}
+
+impl Format {
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".csv") {
+            Format::Csv
+        } else if path.ends_with(".graphml") {
+            Format::Graphml
+        } else {
+            Format::Jsonl
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ExportReport {
+    pub users: usize,
+    pub friendships: usize,
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+fn write_record<W: Write>(out: &mut W, format: Format, record: &Record<String>) -> Result<(), Error> {
+    match (format, record) {
+        (Format::Jsonl, record) => writeln!(out, "{}", serde_json::to_string(record)?)?,
+        (Format::Csv, record) => {
+            let mut writer = csv::Writer::from_writer(&mut *out);
+            match record {
+                Record::User { uid, deactivated: false } => writer.write_record(["user", uid]),
+                Record::User { uid, deactivated: true } => writer.write_record(["user", uid, "deactivated"]),
+                Record::Friendship { uid_a, uid_b, created_at: None } => writer.write_record(["friendship", uid_a, uid_b]),
+                Record::Friendship {
+                    uid_a,
+                    uid_b,
+                    created_at: Some(created_at),
+                } => writer.write_record(["friendship", uid_a, uid_b, created_at]),
+            }
+            .map_err(|e| Error::internal(format!("{}", e)))?;
+            writer.flush()?;
+        }
+        (Format::Graphml, Record::User { uid, deactivated }) => writeln!(out, "    <node id=\"{}\"><data key=\"deactivated\">{}</data></node>", escape_xml(uid), deactivated)?,
+        (Format::Graphml, Record::Friendship { uid_a, uid_b, .. }) => writeln!(out, "    <edge source=\"{}\" target=\"{}\"/>", escape_xml(uid_a), escape_xml(uid_b))?,
+    }
+    Ok(())
+}
+
+// Writes every user followed by every friendship, reading the persister page by page.
+// Pages continue after the last key written, so rows changing meanwhile are never
+// skipped or written twice, but the output is not a point in time snapshot: users and
+// friendships changed during the export may or may not be part of it. JSON Lines and
+// CSV output use the import formats, with each friendship written from the user who
+// added it and with its creation time, so a snapshot can be restored with `Importer`;
+// GraphML is meant for graph tooling.
+pub async fn export<P: Persister<UID = String>, W: Write>(persister: &P, format: Format, out: &mut W, page_size: usize) -> Result<ExportReport, Error> {
+    let page_size = page_size.max(1);
+    let mut report = ExportReport::default();
+    if format == Format::Graphml {
+        writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+        writeln!(out, "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">")?;
+        writeln!(out, "  <key id=\"deactivated\" for=\"node\" attr.name=\"deactivated\" attr.type=\"boolean\"/>")?;
+        writeln!(out, "  <graph id=\"friendship\" edgedefault=\"undirected\">")?;
+    }
+    let mut after = None;
+    loop {
+        let users = persister.list_users(after, page_size).await?;
+        for (uid, deactivated) in &users {
+            // Imports refuse friendships of deactivated users, so the import formats mark
+            // users deactivated after the friendships.
+            let deactivated = *deactivated && format == Format::Graphml;
+            write_record(out, format, &Record::User { uid: uid.clone(), deactivated })?;
+        }
+        report.users += users.len();
+        if users.len() < page_size {
+            break;
+        }
+        after = users.last().map(|(uid, _)| uid.clone());
+    }
+    let mut after = None;
+    loop {
+        let friendships = persister.list_friendships(after, page_size).await?;
+        for (uid, friendship) in &friendships {
+            let (uid_a, uid_b) = if friendship.initiated { (uid, &friendship.uid) } else { (&friendship.uid, uid) };
+            write_record(
+                out,
+                format,
+                &Record::Friendship {
+                    uid_a: uid_a.clone(),
+                    uid_b: uid_b.clone(),
+                    created_at: friendship.created_at.clone(),
+                },
+            )?;
+        }
+        report.friendships += friendships.len();
+        info!("export progress: {} users, {} friendships", report.users, report.friendships);
+        if friendships.len() < page_size {
+            break;
+        }
+        after = friendships.last().map(|(uid, friendship)| (uid.clone(), friendship.uid.clone()));
+    }
+    if format != Format::Graphml {
+        let mut after = None;
+        loop {
+            let users = persister.list_users(after, page_size).await?;
+            for (uid, _) in users.iter().filter(|(_, deactivated)| *deactivated) {
+                write_record(out, format, &Record::User { uid: uid.clone(), deactivated: true })?;
+            }
+            if users.len() < page_size {
+                break;
+            }
+            after = users.last().map(|(uid, _)| uid.clone());
+        }
+    }
+    if format == Format::Graphml {
+        writeln!(out, "  </graph>")?;
+        writeln!(out, "</graphml>")?;
+    }
+    out.flush()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cachers::Memory as MemoryCache;
+    use crate::importer::{self, Importer};
+    use crate::models::FriendshipPair;
+    use crate::persisters::Memory;
+
+    async fn seed() -> Memory {
+        let persister = Memory::new();
+        for uid in 1..=4 {
+            persister.insert_node(uid.to_string()).await.unwrap();
+        }
+        persister.insert(1.to_string(), 2.to_string()).await.unwrap();
+        persister.insert(1.to_string(), 3.to_string()).await.unwrap();
+        persister.insert(4.to_string(), 2.to_string()).await.unwrap();
+        persister.deactivate(3.to_string()).await.unwrap();
+        persister
+    }
+
+    async fn snapshot(persister: &Memory) -> (Vec<(String, bool)>, Vec<FriendshipPair<String>>) {
+        (persister.list_users(None, 100).await.unwrap(), persister.list_friendships(None, 100).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_restore() {
+        for (export_format, import_format) in [(Format::Jsonl, importer::Format::Jsonl), (Format::Csv, importer::Format::Csv)] {
+            let source = seed().await;
+            let mut out = Vec::new();
+            let report = export(&source, export_format, &mut out, 2).await.unwrap();
+            assert_eq!((report.users, report.friendships), (4, 3));
+
+            let target = Memory::new();
+            let cache = MemoryCache::default();
+            let mut rejects = Vec::new();
+            let mut importer = Importer::new(&target, &cache, import_format, &mut rejects).batch_size(2);
+            for line in String::from_utf8(out).unwrap().lines() {
+                importer.feed(line).await.unwrap();
+            }
+            let report = importer.finish().await.unwrap();
+            assert_eq!((report.users, report.friendships, report.rejected), (4, 3, 0));
+            // Creation times and who added whom are restored too.
+            assert_eq!(snapshot(&target).await, snapshot(&source).await);
+            assert!(target.friends(1.to_string()).await.unwrap() == vec![2.to_string()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_friendship_rows() {
+        let persister = Memory::new();
+        persister.insert_nodes(vec!["1".into(), "2".into()]).await.unwrap();
+        persister.insert_many(vec![("2".into(), "1".into(), Some("2024-01-02T03:04:05+00:00".into()))]).await.unwrap();
+        let mut out = Vec::new();
+        export(&persister, Format::Csv, &mut out, DEFAULT_PAGE_SIZE).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "user,1\nuser,2\nfriendship,2,1,2024-01-02T03:04:05+00:00\n");
+        let mut out = Vec::new();
+        export(&persister, Format::Jsonl, &mut out, DEFAULT_PAGE_SIZE).await.unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .ends_with("{\"type\":\"Friendship\",\"uid_a\":\"2\",\"uid_b\":\"1\",\"created_at\":\"2024-01-02T03:04:05+00:00\"}\n"));
+    }
+
+    #[tokio::test]
+    async fn test_pages() {
+        let persister = seed().await;
+        let pairs = |page: &[FriendshipPair<String>]| page.iter().map(|(uid, f)| (uid.clone(), f.uid.clone(), f.initiated)).collect::<Vec<_>>();
+        let page = persister.list_friendships(None, 2).await.unwrap();
+        assert_eq!(pairs(&page), vec![("1".to_owned(), "2".to_owned(), true), ("1".to_owned(), "3".to_owned(), true)]);
+        // Deleting a listed row does not shift the next page. 4 -> 2 is listed from the
+        // smaller uid, as not initiated by it.
+        persister.delete("1".into(), "2".into()).await.unwrap();
+        let after = page.last().map(|(uid, f)| (uid.clone(), f.uid.clone()));
+        assert_eq!(pairs(&persister.list_friendships(after, 2).await.unwrap()), vec![("2".to_owned(), "4".to_owned(), false)]);
+        persister.delete_node("1".into()).await.unwrap();
+        assert_eq!(persister.list_users(Some("2".into()), 1).await.unwrap(), vec![("3".to_owned(), true)]);
+    }
+
+    #[tokio::test]
+    async fn test_export_graphml() {
+        let persister = Memory::new();
+        persister.insert_node("a&b".into()).await.unwrap();
+        persister.insert_node("c".into()).await.unwrap();
+        persister.insert("a&b".into(), "c".into()).await.unwrap();
+        let mut out = Vec::new();
+        export(&persister, Format::Graphml, &mut out, DEFAULT_PAGE_SIZE).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("<node id=\"a&amp;b\"><data key=\"deactivated\">false</data></node>"));
+        assert!(out.contains("<edge source=\"a&amp;b\" target=\"c\"/>"));
+        assert!(out.trim_end().ends_with("</graphml>"));
+    }
+}
@@ -0,0 +1,325 @@
+use crate::core::{Cacher, Persister};
+use crate::error::Error;
+use crate::local::Local;
+use actix_web::web::{post, Data, Json, ServiceConfig};
+use async_graphql::dataloader::{DataLoader, HashMapCache, Loader};
+use async_graphql::{Context, EmptyMutation, EmptySubscription, ErrorExtensions, Object, Schema, SimpleObject};
+use futures_util::future::{self, BoxFuture};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+pub const DEFAULT_MAX_DEPTH: usize = 8;
+pub const DEFAULT_MAX_COMPLEXITY: usize = 5000;
+
+// Assumed size of a user list when estimating the cost of a query, so that every hop
+// through `friends` multiplies the complexity of what is selected below it.
+const FAN_OUT: usize = 10;
+
+type SendFuture<T> = BoxFuture<'static, Result<T, Error>>;
+
+// What the schema reads. Resolvers have to be `Send`, so the calls go through `Local`.
+trait Source: Send + Sync {
+    fn friends_many(&self, uids: Vec<String>) -> SendFuture<BTreeMap<String, Vec<String>>>;
+    fn profiles(&self, uids: Vec<String>) -> SendFuture<BTreeMap<String, BTreeMap<String, String>>>;
+    fn recommendations(&self, uid: String) -> SendFuture<Vec<String>>;
+}
+
+impl<P: Persister<UID = String> + 'static, C: Cacher<UID = String> + 'static> Source for Local<P, C> {
+    // Cached friend lists are used where present, like `/users/friends:batch`.
+    fn friends_many(&self, uids: Vec<String>) -> SendFuture<BTreeMap<String, Vec<String>>> {
+        let local = self.clone();
+        Box::pin(async move {
+            local
+                .call(move |persister, cacher| async move {
+                    let mut res = BTreeMap::new();
+                    let mut misses = Vec::new();
+                    for (uid, cached) in uids.iter().zip(cacher.query_many(uids.clone()).await?) {
+                        match cached {
+                            Some(friends) => {
+                                res.insert(uid.clone(), friends);
+                            }
+                            None => misses.push(uid.clone()),
+                        }
+                    }
+                    if !misses.is_empty() {
+                        res.extend(persister.friends_many(misses).await?);
+                    }
+                    Ok(res)
+                })
+                .await
+        })
+    }
+
+    fn profiles(&self, uids: Vec<String>) -> SendFuture<BTreeMap<String, BTreeMap<String, String>>> {
+        let local = self.clone();
+        Box::pin(async move { local.call(move |persister, _| async move { persister.profiles(uids).await }).await })
+    }
+
+    fn recommendations(&self, uid: String) -> SendFuture<Vec<String>> {
+        let local = self.clone();
+        Box::pin(async move { local.call(move |persister, _| async move { persister.recommendations(uid, 2, 3).await }).await })
+    }
+}
+
+// Batches the friend lists wanted while resolving one query level into one `friends_many`.
+// Users which don't exist are left out.
+struct FriendsLoader(Arc<dyn Source>);
+
+impl Loader<String> for FriendsLoader {
+    type Value = Vec<String>;
+    type Error = Error;
+
+    async fn load(&self, keys: &[String]) -> Result<HashMap<String, Self::Value>, Self::Error> {
+        Ok(self.0.friends_many(keys.to_vec()).await?.into_iter().collect())
+    }
+}
+
+// Batches the profiles wanted while resolving one query level into one `profiles`.
+// Users which don't exist are left out.
+struct ProfileLoader(Arc<dyn Source>);
+
+impl Loader<String> for ProfileLoader {
+    type Value = BTreeMap<String, String>;
+    type Error = Error;
+
+    async fn load(&self, keys: &[String]) -> Result<HashMap<String, Self::Value>, Self::Error> {
+        Ok(self.0.profiles(keys.to_vec()).await?.into_iter().collect())
+    }
+}
+
+// Errors carry the same code as the REST responses in `extensions.code`.
+fn field_error(err: Error) -> async_graphql::Error {
+    let code = async_graphql::to_value(err.kind()).unwrap_or_default();
+    async_graphql::Error::new(format!("{}", err)).extend_with(|_, ext| ext.set("code", code))
+}
+
+fn not_found(uid: &str) -> async_graphql::Error {
+    field_error(Error::not_found(format!("user {} not found", uid)))
+}
+
+async fn friends_of(ctx: &Context<'_>, uid: &str) -> async_graphql::Result<Vec<String>> {
+    let loader = ctx.data_unchecked::<DataLoader<FriendsLoader, HashMapCache>>();
+    loader.load_one(uid.to_owned()).await.map_err(field_error)?.ok_or_else(|| not_found(uid))
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    // The user, or null if it does not exist.
+    async fn user(&self, ctx: &Context<'_>, uid: String) -> async_graphql::Result<Option<User>> {
+        let loader = ctx.data_unchecked::<DataLoader<FriendsLoader, HashMapCache>>();
+        Ok(loader.load_one(uid.clone()).await.map_err(field_error)?.map(|_| User { uid }))
+    }
+
+    // The users in the order asked for, null where a user does not exist.
+    #[graphql(complexity = "uids.len() * child_complexity")]
+    async fn users(&self, ctx: &Context<'_>, uids: Vec<String>) -> async_graphql::Result<Vec<Option<User>>> {
+        let loader = ctx.data_unchecked::<DataLoader<FriendsLoader, HashMapCache>>();
+        let found = loader.load_many(uids.clone()).await.map_err(field_error)?;
+        Ok(uids.into_iter().map(|uid| found.contains_key(&uid).then_some(User { uid })).collect())
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct ProfileEntry {
+    key: String,
+    value: String,
+}
+
+pub struct User {
+    uid: String,
+}
+
+#[Object]
+impl User {
+    async fn uid(&self) -> &str {
+        &self.uid
+    }
+
+    #[graphql(complexity = "FAN_OUT * child_complexity")]
+    async fn friends(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<User>> {
+        Ok(friends_of(ctx, &self.uid).await?.into_iter().map(|uid| User { uid }).collect())
+    }
+
+    async fn friend_count(&self, ctx: &Context<'_>) -> async_graphql::Result<usize> {
+        Ok(friends_of(ctx, &self.uid).await?.len())
+    }
+
+    async fn is_friend(&self, ctx: &Context<'_>, with: String) -> async_graphql::Result<bool> {
+        Ok(friends_of(ctx, &self.uid).await?.contains(&with))
+    }
+
+    #[graphql(complexity = "FAN_OUT * child_complexity")]
+    async fn mutual_friends(&self, ctx: &Context<'_>, with: String) -> async_graphql::Result<Vec<User>> {
+        let (mine, theirs) = future::try_join(friends_of(ctx, &self.uid), friends_of(ctx, &with)).await?;
+        Ok(mine.into_iter().filter(|uid| theirs.contains(uid)).map(|uid| User { uid }).collect())
+    }
+
+    async fn mutual_friend_count(&self, ctx: &Context<'_>, with: String) -> async_graphql::Result<usize> {
+        let (mine, theirs) = future::try_join(friends_of(ctx, &self.uid), friends_of(ctx, &with)).await?;
+        Ok(mine.iter().filter(|uid| theirs.contains(uid)).count())
+    }
+
+    async fn profile(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<ProfileEntry>> {
+        let loader = ctx.data_unchecked::<DataLoader<ProfileLoader, HashMapCache>>();
+        let profile = loader.load_one(self.uid.clone()).await.map_err(field_error)?.ok_or_else(|| not_found(&self.uid))?;
+        Ok(profile.into_iter().map(|(key, value)| ProfileEntry { key, value }).collect())
+    }
+
+    #[graphql(complexity = "FAN_OUT * child_complexity")]
+    async fn recommendations(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<User>> {
+        let source = ctx.data_unchecked::<Arc<dyn Source>>();
+        let uids = source.recommendations(self.uid.clone()).await.map_err(field_error)?;
+        Ok(uids.into_iter().map(|uid| User { uid }).collect())
+    }
+}
+
+pub type FriendshipSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+fn schema(source: Arc<dyn Source>, max_depth: usize, max_complexity: usize) -> FriendshipSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .data(source)
+        .limit_depth(max_depth)
+        .limit_complexity(max_complexity)
+        .finish()
+}
+
+#[derive(Clone)]
+pub struct GraphQL {
+    source: Arc<dyn Source>,
+    schema: FriendshipSchema,
+}
+
+impl GraphQL {
+    // Has to be called on the actix runtime, which then runs the persister and cacher calls.
+    pub fn new<P: Persister<UID = String> + 'static, C: Cacher<UID = String> + 'static>(persister: P, cacher: C) -> Self {
+        let source: Arc<dyn Source> = Arc::new(Local::new(persister, cacher));
+        let schema = schema(source.clone(), DEFAULT_MAX_DEPTH, DEFAULT_MAX_COMPLEXITY);
+        Self { source, schema }
+    }
+
+    // Queries nested deeper or estimated costlier than this are rejected before they run.
+    pub fn limits(mut self, max_depth: usize, max_complexity: usize) -> Self {
+        self.schema = schema(self.source.clone(), max_depth, max_complexity);
+        self
+    }
+
+    // Loaders are created per query, so nothing is cached between queries.
+    pub async fn execute(&self, request: async_graphql::Request) -> async_graphql::Response {
+        let friends = DataLoader::with_cache(FriendsLoader(self.source.clone()), tokio::spawn, HashMapCache::default());
+        let profiles = DataLoader::with_cache(ProfileLoader(self.source.clone()), tokio::spawn, HashMapCache::default());
+        self.schema.execute(request.data(friends).data(profiles)).await
+    }
+}
+
+pub async fn graphql(graphql: Data<GraphQL>, body: Json<async_graphql::Request>) -> Json<async_graphql::Response> {
+    Json(graphql.execute(body.into_inner()).await)
+}
+
+pub fn routes(cfg: &mut ServiceConfig) {
+    cfg.route("/graphql", post().to(graphql));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cachers::Memory as MemoryCache;
+    use crate::core::BoxFuture as LocalFuture;
+    use crate::persisters::Memory;
+    use actix_web::test::{call_and_read_body_json, init_service, TestRequest};
+    use actix_web::App;
+    use serde_json::{json, Value};
+    use std::sync::Mutex;
+
+    // Records the users of every `query_many`, one entry per batch.
+    #[derive(Clone, Default)]
+    struct Counting {
+        inner: MemoryCache,
+        batches: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    impl Cacher for Counting {
+        type UID = String;
+        fn insert(&self, uid: String, friends: Vec<String>) -> LocalFuture<()> {
+            self.inner.insert(uid, friends)
+        }
+        fn delete(&self, uid: String) -> LocalFuture<()> {
+            self.inner.delete(uid)
+        }
+        fn query(&self, uid: String) -> LocalFuture<Option<Vec<String>>> {
+            self.inner.query(uid)
+        }
+        fn query_many(&self, uids: Vec<String>) -> LocalFuture<Vec<Option<Vec<String>>>> {
+            let mut batch = uids.clone();
+            batch.sort();
+            self.batches.lock().unwrap().push(batch);
+            self.inner.query_many(uids)
+        }
+    }
+
+    async fn persister() -> Memory {
+        let persister = Memory::new();
+        persister.insert_nodes((1..=4).map(|uid| uid.to_string()).collect()).await.unwrap();
+        let friendships = [(1, 2), (1, 3), (2, 3), (2, 4), (3, 4)];
+        persister.insert_many(friendships.iter().map(|(a, b)| (a.to_string(), b.to_string(), None)).collect()).await.unwrap();
+        persister
+    }
+
+    async fn execute(graphql: &GraphQL, query: &str) -> Value {
+        serde_json::to_value(graphql.execute(async_graphql::Request::new(query)).await).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_graphql() {
+        let cacher = Counting::default();
+        let graphql = GraphQL::new(persister().await, cacher.clone());
+        let app = init_service(App::new().app_data(Data::new(graphql)).configure(routes)).await;
+        let query = r#"{
+            me: user(uid: "1") { friends { uid mutualFriendCount(with: "1") profile { key value } } }
+            missing: user(uid: "9") { uid }
+        }"#;
+        let req = TestRequest::post().uri("/graphql").set_json(json!({ "query": query })).to_request();
+        let res: Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            res,
+            json!({
+                "data": {
+                    "me": {
+                        "friends": [
+                            { "uid": "2", "mutualFriendCount": 1, "profile": [{ "key": "uid", "value": "2" }] },
+                            { "uid": "3", "mutualFriendCount": 1, "profile": [{ "key": "uid", "value": "3" }] },
+                        ]
+                    },
+                    "missing": null,
+                }
+            })
+        );
+        // One lookup per query level, however many users are on it.
+        assert_eq!(*cacher.batches.lock().unwrap(), vec![vec!["1".to_owned(), "9".to_owned()], vec!["2".to_owned(), "3".to_owned()]]);
+    }
+
+    #[actix_web::test]
+    async fn test_graphql_errors() {
+        let graphql = GraphQL::new(persister().await, MemoryCache::default());
+        let res = execute(&graphql, r#"{ user(uid: "1") { isFriend(with: "2") recommendations { uid } mutualFriendCount(with: "9") } }"#).await;
+        // The failed field is left out, the others are still answered.
+        assert_eq!(res["data"], json!({ "user": { "isFriend": true, "recommendations": [] } }));
+        assert_eq!(res["errors"][0]["extensions"]["code"], json!("NOT_FOUND"));
+        assert_eq!(res["errors"][0]["path"], json!(["user", "mutualFriendCount"]));
+    }
+
+    #[actix_web::test]
+    async fn test_graphql_limits() {
+        let graphql = GraphQL::new(persister().await, MemoryCache::default());
+        let three_hops = r#"{ user(uid: "1") { friends { friends { friends { uid } } } } }"#;
+        assert!(execute(&graphql, three_hops).await.get("errors").is_none());
+        let four_hops = r#"{ user(uid: "1") { friends { friends { friends { friends { uid } } } } } }"#;
+        let res = execute(&graphql, four_hops).await;
+        assert!(res["errors"][0]["message"].as_str().unwrap().contains("complex"));
+
+        let graphql = graphql.limits(3, DEFAULT_MAX_COMPLEXITY);
+        let res = execute(&graphql, r#"{ user(uid: "1") { friends { friends { uid } } } }"#).await;
+        assert!(res["errors"][0]["message"].as_str().unwrap().contains("nested too deep"));
+    }
+}
@@ -0,0 +1,274 @@
+use crate::core::{Cacher, Persister};
+use crate::error::{Error, ErrorKind};
+use crate::local::Local;
+use futures_util::stream::{self, Stream, StreamExt};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use tonic::{Request, Response, Status};
+
+// Generated by build.rs: `friendship_server` and `friendship_client`.
+include!(concat!(env!("OUT_DIR"), "/friendship.Friendship.rs"));
+
+pub use friendship_server::FriendshipServer;
+
+// Friends per `ListFriends` message, keeping large lists under the message size limit.
+const PAGE_SIZE: usize = 1000;
+
+// Messages of proto/friendship.proto.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Empty {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UserRequest {
+    #[prost(string, tag = "1")]
+    pub uid: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteUserRequest {
+    #[prost(string, tag = "1")]
+    pub uid: String,
+    #[prost(bool, tag = "2")]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteUserReply {
+    #[prost(string, repeated, tag = "1")]
+    pub friends: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FriendshipRequest {
+    #[prost(string, tag = "1")]
+    pub uid_a: String,
+    #[prost(string, tag = "2")]
+    pub uid_b: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct IsFriendReply {
+    #[prost(bool, tag = "1")]
+    pub is_friend: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UserList {
+    #[prost(string, repeated, tag = "1")]
+    pub uids: Vec<String>,
+}
+
+fn status_of(err: Error) -> Status {
+    let msg = format!("{}", err);
+    match err.kind() {
+        ErrorKind::NotFound => Status::not_found(msg),
+        ErrorKind::AlreadyExists => Status::already_exists(msg),
+        ErrorKind::Conflict => Status::failed_precondition(msg),
+        ErrorKind::InvalidInput => Status::invalid_argument(msg),
+        ErrorKind::Unavailable => Status::unavailable(msg),
+        ErrorKind::Timeout => Status::deadline_exceeded(msg),
+        ErrorKind::Internal => Status::internal(msg),
+    }
+}
+
+// tonic needs `Send` handlers, so the calls go through `Local`.
+pub struct FriendshipService<P, C> {
+    local: Local<P, C>,
+}
+
+impl<P: Persister<UID = String> + 'static, C: Cacher<UID = String> + 'static> FriendshipService<P, C> {
+    // Has to be called on the actix runtime, which then runs the calls.
+    pub fn new(persister: P, cacher: C) -> Self {
+        Self { local: Local::new(persister, cacher) }
+    }
+
+    async fn call<T, F, Fut>(&self, f: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(Rc<P>, Rc<C>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, Error>> + 'static,
+    {
+        self.local.call(f).await.map_err(status_of)
+    }
+}
+
+#[tonic::async_trait]
+impl<P: Persister<UID = String> + 'static, C: Cacher<UID = String> + 'static> friendship_server::Friendship for FriendshipService<P, C> {
+    async fn add_user(&self, request: Request<UserRequest>) -> Result<Response<Empty>, Status> {
+        let uid = request.into_inner().uid;
+        self.call(move |persister, _| async move { persister.insert_node(uid).await }).await?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn delete_user(&self, request: Request<DeleteUserRequest>) -> Result<Response<DeleteUserReply>, Status> {
+        let DeleteUserRequest { uid, dry_run } = request.into_inner();
+        let friends = self
+            .call(move |persister, cacher| async move {
+                if dry_run {
+                    return persister.all_friends(uid).await;
+                }
+                let friends = persister.delete_node(uid.clone()).await?;
+                cacher.delete(uid).await?;
+                for friend in &friends {
+                    cacher.delete(friend.clone()).await?;
+                }
+                Ok(friends)
+            })
+            .await?;
+        Ok(Response::new(DeleteUserReply { friends }))
+    }
+
+    async fn deactivate_user(&self, request: Request<UserRequest>) -> Result<Response<Empty>, Status> {
+        let uid = request.into_inner().uid;
+        self.call(move |persister, cacher| async move {
+            for friend in persister.deactivate(uid).await? {
+                cacher.delete(friend).await?;
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn reactivate_user(&self, request: Request<UserRequest>) -> Result<Response<Empty>, Status> {
+        let uid = request.into_inner().uid;
+        self.call(move |persister, cacher| async move {
+            for friend in persister.reactivate(uid).await? {
+                cacher.delete(friend).await?;
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn add_friend(&self, request: Request<FriendshipRequest>) -> Result<Response<Empty>, Status> {
+        let FriendshipRequest { uid_a, uid_b } = request.into_inner();
+        self.call(move |persister, cacher| async move {
+            persister.insert(uid_a.clone(), uid_b.clone()).await?;
+            cacher.delete(uid_a).await?;
+            cacher.delete(uid_b).await
+        })
+        .await?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn delete_friend(&self, request: Request<FriendshipRequest>) -> Result<Response<Empty>, Status> {
+        let FriendshipRequest { uid_a, uid_b } = request.into_inner();
+        self.call(move |persister, cacher| async move {
+            persister.delete(uid_a.clone(), uid_b.clone()).await?;
+            cacher.delete(uid_a).await?;
+            cacher.delete(uid_b).await
+        })
+        .await?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn is_friend(&self, request: Request<FriendshipRequest>) -> Result<Response<IsFriendReply>, Status> {
+        let FriendshipRequest { uid_a, uid_b } = request.into_inner();
+        let is_friend = self.call(move |persister, _| async move { persister.is_friend(uid_a, uid_b).await }).await?;
+        Ok(Response::new(IsFriendReply { is_friend }))
+    }
+
+    type ListFriendsStream = Pin<Box<dyn Stream<Item = Result<UserList, Status>> + Send>>;
+
+    async fn list_friends(&self, request: Request<UserRequest>) -> Result<Response<Self::ListFriendsStream>, Status> {
+        let uid = request.into_inner().uid;
+        let friends = self
+            .call(move |persister, cacher| async move {
+                match cacher.query(uid.clone()).await? {
+                    Some(friends) => Ok(friends),
+                    None => persister.friends(uid).await,
+                }
+            })
+            .await?;
+        let pages = friends.chunks(PAGE_SIZE).map(|uids| UserList { uids: uids.to_vec() }).collect::<Vec<_>>();
+        Ok(Response::new(Box::pin(stream::iter(pages).map(Ok))))
+    }
+
+    async fn recommendations(&self, request: Request<UserRequest>) -> Result<Response<UserList>, Status> {
+        let uid = request.into_inner().uid;
+        let uids = self
+            .call(move |persister, _| async move {
+                if !persister.exist_node(uid.clone()).await? {
+                    return Err(Error::not_found(format!("user {} not found", uid)));
+                }
+                persister.recommendations(uid, 2, 3).await
+            })
+            .await?;
+        Ok(Response::new(UserList { uids }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::friendship_client::FriendshipClient;
+    use super::*;
+    use crate::cachers::Memory as MemoryCache;
+    use crate::persisters::Memory;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::{Channel, Server};
+    use tonic::Code;
+
+    async fn serve(persister: Memory) -> FriendshipClient<Channel> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = FriendshipServer::new(FriendshipService::new(persister, MemoryCache::default()));
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        actix_web::rt::spawn(Server::builder().add_service(service).serve_with_incoming(incoming));
+        FriendshipClient::connect(format!("http://{}", addr)).await.unwrap()
+    }
+
+    fn user(uid: &str) -> UserRequest {
+        UserRequest { uid: uid.into() }
+    }
+
+    fn friendship(uid_a: &str, uid_b: &str) -> FriendshipRequest {
+        FriendshipRequest {
+            uid_a: uid_a.into(),
+            uid_b: uid_b.into(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_friendship_service() {
+        let mut client = serve(Memory::new()).await;
+        for uid in ["1", "2", "3"] {
+            client.add_user(user(uid)).await.unwrap();
+        }
+        assert_eq!(client.add_user(user("1")).await.unwrap_err().code(), Code::AlreadyExists);
+        client.add_friend(friendship("1", "2")).await.unwrap();
+        client.add_friend(friendship("1", "3")).await.unwrap();
+        assert!(client.is_friend(friendship("2", "1")).await.unwrap().into_inner().is_friend);
+        client.delete_friend(friendship("1", "3")).await.unwrap();
+        assert!(!client.is_friend(friendship("1", "3")).await.unwrap().into_inner().is_friend);
+        client.deactivate_user(user("2")).await.unwrap();
+        assert_eq!(client.deactivate_user(user("2")).await.unwrap_err().code(), Code::FailedPrecondition);
+        assert!(!client.is_friend(friendship("1", "2")).await.unwrap().into_inner().is_friend);
+        // A deactivated friend is detached by a delete too, so the dry run reports it.
+        let request = DeleteUserRequest { uid: "1".into(), dry_run: true };
+        assert_eq!(client.delete_user(request).await.unwrap().into_inner().friends, vec!["2".to_owned()]);
+        client.reactivate_user(user("2")).await.unwrap();
+        assert_eq!(client.recommendations(user("4")).await.unwrap_err().code(), Code::NotFound);
+
+        let request = DeleteUserRequest { uid: "1".into(), dry_run: true };
+        assert_eq!(client.delete_user(request).await.unwrap().into_inner().friends, vec!["2".to_owned()]);
+        assert!(client.is_friend(friendship("1", "2")).await.unwrap().into_inner().is_friend);
+        let request = DeleteUserRequest { uid: "1".into(), dry_run: false };
+        assert_eq!(client.delete_user(request).await.unwrap().into_inner().friends, vec!["2".to_owned()]);
+        assert_eq!(client.list_friends(user("1")).await.unwrap_err().code(), Code::NotFound);
+    }
+
+    #[actix_web::test]
+    async fn test_list_friends() {
+        let persister = Memory::new();
+        let friends = (1..=PAGE_SIZE + 1).map(|uid| format!("f{:05}", uid)).collect::<Vec<_>>();
+        persister.insert_nodes(friends.iter().cloned().chain(["u".to_owned()]).collect()).await.unwrap();
+        persister.insert_many(friends.iter().map(|friend| ("u".to_owned(), friend.clone(), None)).collect()).await.unwrap();
+        let mut client = serve(persister).await;
+        let pages = client.list_friends(user("u")).await.unwrap().into_inner().map(|page| page.unwrap().uids).collect::<Vec<_>>().await;
+        assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), vec![PAGE_SIZE, 1]);
+        assert_eq!(pages.concat(), friends);
+    }
+}
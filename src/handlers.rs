@@ -1,49 +1,359 @@
-use crate::core::{Cacher, Persister};
+use crate::core::{Cacher, Persister, Subscriber};
 use crate::error::Error;
-use actix_web::web::{Data, Json, Path};
-use actix_web::HttpResponse;
+use crate::importer::{Format, ImportReport, Importer, DEFAULT_BATCH_SIZE};
+use crate::middleware;
+use crate::models::{BatchOp, BatchOutcome};
+use crate::protocol::Response;
+use actix_web::body::BoxBody;
+use actix_web::http::header::{CacheControl, CacheDirective, ContentDisposition, DispositionParam, DispositionType};
+use actix_web::web::{delete, get, post, Bytes, Data, Json, Path, Payload, Query, ServiceConfig};
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+// Successful replies in the `protocol::Response` model shared with the other transports,
+// along with the `Meta` recorded by `middleware::RequestMeta`. Errors get the same model
+// from `Error::error_response`.
+pub struct Reply<T>(pub T);
+
+impl<T: Serialize> Responder for Reply<T> {
+    type Body = BoxBody;
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        HttpResponse::Ok().json(Response::Ok {
+            data: self.0,
+            meta: middleware::meta(req),
+        })
+    }
+}
 
 async fn refresh_cache<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, uid: String) -> Result<(), Error> {
     let friends = persister.friends(uid.clone()).await?;
     cacher.insert(uid, friends).await
 }
 
-pub async fn add_friend<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, uids: Path<(String, String)>) -> Result<String, Error> {
+pub async fn add_friend<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, uids: Path<(String, String)>) -> Result<Reply<()>, Error> {
     persister.insert(uids.0.clone(), uids.1.clone()).await?;
     refresh_cache(persister, cacher, uids.0.clone()).await?;
-    Ok("ok".into())
+    Ok(Reply(()))
 }
 
-pub async fn delete_friend<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, uids: Path<(String, String)>) -> Result<String, Error> {
+pub async fn delete_friend<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, uids: Path<(String, String)>) -> Result<Reply<()>, Error> {
     persister.delete(uids.0.clone(), uids.1.clone()).await?;
     refresh_cache(persister, cacher, uids.0.clone()).await?;
-    Ok("ok".into())
+    Ok(Reply(()))
 }
 
-pub async fn query_friends<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, uid: Path<(String,)>) -> Result<Json<Vec<String>>, Error> {
+pub async fn query_friends<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, uid: Path<(String,)>) -> Result<Reply<Vec<String>>, Error> {
     if let Some(friends) = cacher.query(uid.0.clone()).await? {
-        return Ok(Json(friends));
+        return Ok(Reply(friends));
     }
-    Ok(Json(persister.friends(uid.0.clone()).await?))
+    Ok(Reply(persister.friends(uid.0.clone()).await?))
 }
 
-pub async fn recommendation<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, uid: Path<(String,)>) -> Result<Json<Vec<String>>, Error> {
-    Ok(Json(persister.recommendations(uid.0.clone(), 2, 3).await?))
+pub async fn recommendation<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, _cacher: Data<C>, uid: Path<(String,)>) -> Result<Reply<Vec<String>>, Error> {
+    if !persister.exist_node(uid.0.clone()).await? {
+        return Err(Error::not_found(format!("user {} not found", uid.0)));
+    }
+    Ok(Reply(persister.recommendations(uid.0.clone(), 2, 3).await?))
 }
 
-pub async fn add_user<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, uid: Path<(String,)>) -> Result<String, Error> {
+pub async fn add_user<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, _cacher: Data<C>, uid: Path<(String,)>) -> Result<Reply<()>, Error> {
     persister.insert_node(uid.0.clone()).await?;
-    Ok("ok".into())
+    Ok(Reply(()))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteUserParams {
+    #[serde(default)]
+    dry_run: bool,
 }
 
-pub async fn delete_user<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, uid: Path<(String,)>) -> Result<String, Error> {
-    persister.delete_node(uid.0.clone()).await?;
-    Ok("ok".into())
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteUserReport {
+    pub uid: String,
+    pub friends: Vec<String>,
+    pub dry_run: bool,
 }
 
-pub async fn is_friend<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, uids: Path<(String, String)>) -> Result<HttpResponse, Error> {
+pub async fn delete_user<P: Persister<UID = String>, C: Cacher<UID = String>>(
+    persister: Data<P>,
+    cacher: Data<C>,
+    uid: Path<(String,)>,
+    params: Query<DeleteUserParams>,
+) -> Result<Reply<DeleteUserReport>, Error> {
+    if params.dry_run {
+        return Ok(Reply(DeleteUserReport {
+            uid: uid.0.clone(),
+            friends: persister.all_friends(uid.0.clone()).await?,
+            dry_run: true,
+        }));
+    }
+    let friends = persister.delete_node(uid.0.clone()).await?;
+    cacher.delete(uid.0.clone()).await?;
+    for friend in &friends {
+        cacher.delete(friend.clone()).await?;
+    }
+    Ok(Reply(DeleteUserReport {
+        uid: uid.0.clone(),
+        friends,
+        dry_run: false,
+    }))
+}
+
+pub async fn is_friend<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, _cacher: Data<C>, uids: Path<(String, String)>) -> Result<Reply<()>, Error> {
     match persister.is_friend(uids.0.clone(), uids.1.clone()).await? {
-        true => Ok(HttpResponse::Ok().finish()),
-        _ => Ok(HttpResponse::NotFound().finish()),
+        true => Ok(Reply(())),
+        _ => Err(Error::not_found(format!("user {} and user {} are not friends", uids.0, uids.1))),
+    }
+}
+
+pub async fn deactivate_user<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, uid: Path<(String,)>) -> Result<Reply<()>, Error> {
+    let friends = persister.deactivate(uid.0.clone()).await?;
+    for friend in friends {
+        cacher.delete(friend).await?;
+    }
+    Ok(Reply(()))
+}
+
+pub async fn reactivate_user<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, uid: Path<(String,)>) -> Result<Reply<()>, Error> {
+    let friends = persister.reactivate(uid.0.clone()).await?;
+    for friend in friends {
+        cacher.delete(friend).await?;
+    }
+    Ok(Reply(()))
+}
+
+// A download of the document itself, not wrapped in a `protocol::Response`.
+pub async fn export_user<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, _cacher: Data<C>, uid: Path<(String,)>) -> Result<HttpResponse, Error> {
+    let export = persister.export(uid.0.clone()).await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!("user-{}.json", uid.0))],
+        })
+        .json(export))
+}
+
+// Streams the events of a user as server-sent events, one JSON `Event` per message. A
+// comment is sent when nothing happened for a while, so proxies keep the connection open.
+pub async fn user_events<P: Persister<UID = String>, S: Subscriber<UID = String>>(persister: Data<P>, subscriber: Data<S>, uid: Path<(String,)>) -> Result<HttpResponse, Error> {
+    if !persister.exist_node(uid.0.clone()).await? {
+        return Err(Error::not_found(format!("user {} not found", uid.0)));
+    }
+    let events = subscriber.subscribe(uid.0.clone()).await?;
+    let messages = stream::unfold(events, |mut events| async move {
+        let message = match tokio::time::timeout(KEEP_ALIVE, events.next()).await {
+            Ok(Some(event)) => format!("data: {}\n\n", serde_json::to_string(&event).ok()?),
+            Ok(None) => return None,
+            Err(_) => ": keep-alive\n\n".to_owned(),
+        };
+        Some((Ok::<_, actix_web::Error>(Bytes::from(message)), events))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(messages))
+}
+
+#[derive(Deserialize)]
+pub struct ImportParams {
+    format: Format,
+    batch_size: Option<usize>,
+    #[serde(default)]
+    skip: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportResult {
+    #[serde(flatten)]
+    pub report: ImportReport,
+    pub rejects: Vec<String>,
+}
+
+pub async fn import<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, params: Query<ImportParams>, mut payload: Payload) -> Result<Reply<ImportResult>, Error> {
+    let mut rejects = Vec::new();
+    let mut importer = Importer::new(persister.get_ref(), cacher.get_ref(), params.format, &mut rejects)
+        .batch_size(params.batch_size.unwrap_or(DEFAULT_BATCH_SIZE))
+        .skip(params.skip);
+    let mut buf = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        buf.extend_from_slice(&chunk.map_err(|e| Error::invalid_input(format!("{}", e)))?);
+        while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+            let line = buf.drain(..=pos).collect::<Vec<_>>();
+            importer.feed(&String::from_utf8_lossy(&line[..pos])).await?;
+        }
+    }
+    if !buf.is_empty() {
+        importer.feed(&String::from_utf8_lossy(&buf)).await?;
+    }
+    let report = importer.finish().await?;
+    Ok(Reply(ImportResult {
+        report,
+        // The reject file format puts a `# reason` comment before each line.
+        rejects: String::from_utf8_lossy(&rejects).lines().filter(|line| !line.starts_with('#')).map(String::from).collect(),
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BatchBody {
+    pub ops: Vec<BatchOp<String>>,
+    #[serde(default)]
+    pub atomic: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BatchFriendsBody {
+    #[serde(default)]
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub delete: Vec<String>,
+    #[serde(default)]
+    pub atomic: bool,
+}
+
+// Batches run in a single transaction, so their size is bounded.
+pub const MAX_BATCH_OPS: usize = 1000;
+
+// Runs the batch and returns the users whose cached friends it made stale.
+pub async fn apply_batch<P: Persister<UID = String>>(persister: &P, ops: Vec<BatchOp<String>>, atomic: bool) -> Result<(BatchOutcome, Vec<String>), Error> {
+    if ops.len() > MAX_BATCH_OPS {
+        return Err(Error::invalid_input(format!("at most {} operations can be run in one batch", MAX_BATCH_OPS)));
+    }
+    let outcome = persister.batch(ops.clone(), atomic).await?;
+    let mut touched = BTreeSet::new();
+    if outcome.committed {
+        for (op, res) in ops.into_iter().zip(&outcome.results) {
+            if res.is_none() {
+                let (BatchOp::Add { uid_a, uid_b } | BatchOp::Delete { uid_a, uid_b }) = op;
+                touched.insert(uid_a);
+                touched.insert(uid_b);
+            }
+        }
+    }
+    Ok((outcome, touched.into_iter().collect()))
+}
+
+async fn run_batch<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: &P, cacher: &C, ops: Vec<BatchOp<String>>, atomic: bool) -> Result<BatchOutcome, Error> {
+    let (outcome, touched) = apply_batch(persister, ops, atomic).await?;
+    for uid in touched {
+        cacher.delete(uid).await?;
+    }
+    Ok(outcome)
+}
+
+pub async fn batch<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: Data<P>, cacher: Data<C>, body: Json<BatchBody>) -> Result<Reply<BatchOutcome>, Error> {
+    let body = body.into_inner();
+    Ok(Reply(run_batch(persister.get_ref(), cacher.get_ref(), body.ops, body.atomic).await?))
+}
+
+pub async fn batch_friends<P: Persister<UID = String>, C: Cacher<UID = String>>(
+    persister: Data<P>,
+    cacher: Data<C>,
+    uid: Path<(String,)>,
+    body: Json<BatchFriendsBody>,
+) -> Result<Reply<BatchOutcome>, Error> {
+    let body = body.into_inner();
+    let ops = body
+        .add
+        .into_iter()
+        .map(|friend| BatchOp::Add { uid_a: uid.0.clone(), uid_b: friend })
+        .chain(body.delete.into_iter().map(|friend| BatchOp::Delete { uid_a: uid.0.clone(), uid_b: friend }))
+        .collect();
+    Ok(Reply(run_batch(persister.get_ref(), cacher.get_ref(), ops, body.atomic).await?))
+}
+
+pub const MAX_BATCH_LOOKUP: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+pub struct FriendsBatchBody {
+    pub uids: Vec<String>,
+}
+
+// Friend lists are served from the cache where possible, the misses are loaded from the
+// persister in a single round trip. Unknown users are left out of the result.
+pub async fn query_friends_many<P: Persister<UID = String>, C: Cacher<UID = String>>(
+    persister: Data<P>,
+    cacher: Data<C>,
+    body: Json<FriendsBatchBody>,
+) -> Result<Reply<BTreeMap<String, Vec<String>>>, Error> {
+    let uids = body.into_inner().uids.into_iter().collect::<BTreeSet<_>>().into_iter().collect::<Vec<_>>();
+    if uids.len() > MAX_BATCH_LOOKUP {
+        return Err(Error::invalid_input(format!("at most {} users can be queried at once", MAX_BATCH_LOOKUP)));
+    }
+    let mut res = BTreeMap::new();
+    let mut misses = Vec::new();
+    for (uid, cached) in uids.iter().zip(cacher.query_many(uids.clone()).await?) {
+        match cached {
+            Some(friends) => {
+                res.insert(uid.clone(), friends);
+            }
+            None => misses.push(uid.clone()),
+        }
+    }
+    if !misses.is_empty() {
+        res.extend(persister.friends_many(misses).await?);
+    }
+    Ok(Reply(res))
+}
+
+pub fn routes<P: Persister<UID = String> + 'static, C: Cacher<UID = String> + 'static, S: Subscriber<UID = String> + 'static>(cfg: &mut ServiceConfig) {
+    // `/users/friends:batch` has to be registered before `/users/{uid}`, which would match it too.
+    cfg.route("/users/friends:batch", post().to(query_friends_many::<P, C>))
+        .route("/users/{uid}", post().to(add_user::<P, C>))
+        .route("/users/{uid}", delete().to(delete_user::<P, C>))
+        .route("/users/{uid_a}/friends/{uid_b}", post().to(add_friend::<P, C>))
+        .route("/users/{uid_a}/friends/{uid_b}", delete().to(delete_friend::<P, C>))
+        .route("/users/{uid}/friends", get().to(query_friends::<P, C>))
+        .route("/users/{uid_a}/friends/{uid_b}", get().to(is_friend::<P, C>))
+        .route("/users/{uid}/recommendations", get().to(recommendation::<P, C>))
+        .route("/users/{uid}/export", get().to(export_user::<P, C>))
+        .route("/users/{uid}/events", get().to(user_events::<P, S>))
+        .route("/ws", get().to(crate::websocket::connect::<P, C>))
+        .route("/users/{uid}/deactivate", post().to(deactivate_user::<P, C>))
+        .route("/users/{uid}/reactivate", post().to(reactivate_user::<P, C>))
+        .route("/friendships:batch", post().to(batch::<P, C>))
+        .route("/users/{uid}/friends:batch", post().to(batch_friends::<P, C>));
+}
+
+// Operator routes, served on their own listener which is not exposed to clients.
+pub fn admin_routes<P: Persister<UID = String> + 'static, C: Cacher<UID = String> + 'static>(cfg: &mut ServiceConfig) {
+    cfg.route("/admin/import", post().to(import::<P, C>));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cachers::Memory as MemoryCache;
+    use crate::persisters::Memory;
+    use crate::publishers::Memory as MemoryPublisher;
+    use actix_web::{test, App};
+
+    // Goes through `routes`, where `POST /users/{uid}` would take the request and create a
+    // user named "friends:batch" if it was registered first.
+    #[actix_web::test]
+    async fn test_friends_batch_route() {
+        let persister = Memory::new();
+        for uid in ["1", "2"] {
+            persister.insert_node(uid.into()).await.unwrap();
+        }
+        persister.insert("1".into(), "2".into()).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(middleware::RequestMeta)
+                .app_data(Data::new(persister.clone()))
+                .app_data(Data::new(MemoryCache::default()))
+                .app_data(Data::new(MemoryPublisher::default()))
+                .configure(routes::<Memory, MemoryCache, MemoryPublisher>),
+        )
+        .await;
+        let req = test::TestRequest::post().uri("/users/friends:batch").set_json(FriendsBatchBody { uids: vec!["1".into()] }).to_request();
+        let friends: Response<BTreeMap<String, Vec<String>>> = test::call_and_read_body_json(&app, req).await;
+        assert!(friends.meta().request_id.is_some() && friends.meta().elapsed_ms.is_some());
+        assert_eq!(friends.into_result().unwrap(), BTreeMap::from([("1".to_owned(), vec!["2".to_owned()])]));
+        assert!(!persister.exist_node("friends:batch".into()).await.unwrap());
     }
 }
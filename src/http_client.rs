@@ -0,0 +1,499 @@
+use crate::core::EventStream;
+use crate::error::{Error, ErrorBody, ErrorKind};
+use crate::handlers::{BatchBody, BatchFriendsBody, DeleteUserReport, FriendsBatchBody, ImportResult};
+use crate::importer::Format;
+use crate::models::{BatchOp, BatchOutcome, UserExport};
+use crate::protocol::Response as ProtocolResponse;
+use futures_util::stream;
+use reqwest::header::ACCEPT;
+use reqwest::{Client as ReqwestClient, RequestBuilder, Response, Url};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+pub const DEFAULT_RETRIES: usize = 2;
+
+fn kind_of_status(status: u16) -> ErrorKind {
+    match status {
+        400 => ErrorKind::InvalidInput,
+        404 => ErrorKind::NotFound,
+        409 => ErrorKind::Conflict,
+        503 => ErrorKind::Unavailable,
+        504 => ErrorKind::Timeout,
+        _ => ErrorKind::Internal,
+    }
+}
+
+// Error responses carry a `protocol::Response`, or a bare `ErrorBody` from servers
+// predating it; anything else (a proxy page, an empty 404) is mapped from the status
+// code alone.
+async fn error_of(resp: Response) -> Error {
+    let status = resp.status();
+    let text = resp.text().await.unwrap_or_default();
+    if let Ok(ProtocolResponse::<Value>::Err { code, detail, .. }) = serde_json::from_str(&text) {
+        return Error::new(code, detail);
+    }
+    match serde_json::from_str::<ErrorBody>(&text) {
+        Ok(body) => Error::new(body.code, body.detail),
+        Err(_) => Error::new(kind_of_status(status.as_u16()), format!("{} {}", status, text).trim_end().to_owned()),
+    }
+}
+
+async fn data_of<T: DeserializeOwned>(resp: Response) -> Result<T, Error> {
+    resp.json::<ProtocolResponse<T>>().await?.into_result()
+}
+
+fn base_url(base: &str) -> Result<Url, Error> {
+    let url = Url::parse(base).map_err(|e| Error::invalid_input(format!("invalid base url {}: {}", base, e)))?;
+    if url.cannot_be_a_base() {
+        return Err(Error::invalid_input(format!("invalid base url {}", base)));
+    }
+    Ok(url)
+}
+
+fn join(base: &Url, segments: &[&str]) -> Url {
+    let mut url = base.clone();
+    url.path_segments_mut().expect("base url checked in base_url").pop_if_empty().extend(segments);
+    url
+}
+
+// Client of the REST API. Idempotent calls (reads and deletions) are retried with
+// exponential backoff when the service is unavailable or times out. The admin routes
+// are served on a separate address (`ADMIN_ADDRESS`), set with `admin`.
+pub struct HttpClient {
+    http: ReqwestClient,
+    base: Url,
+    admin: Option<Url>,
+    retries: usize,
+    backoff: Duration,
+    timeout: Duration,
+}
+
+impl HttpClient {
+    pub fn new(base: &str) -> Result<Self, Error> {
+        Ok(Self {
+            http: ReqwestClient::new(),
+            base: base_url(base)?,
+            admin: None,
+            retries: DEFAULT_RETRIES,
+            backoff: Duration::from_millis(100),
+            timeout: Duration::from_secs(30),
+        })
+    }
+
+    pub fn admin(mut self, admin: &str) -> Result<Self, Error> {
+        self.admin = Some(base_url(admin)?);
+        Ok(self)
+    }
+
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    // Delay before the first retry, doubled for every following one.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn url(&self, segments: &[&str]) -> Url {
+        join(&self.base, segments)
+    }
+
+    fn admin_url(&self, segments: &[&str]) -> Result<Url, Error> {
+        let admin = self.admin.as_ref().ok_or_else(|| Error::invalid_input("admin url not set".into()))?;
+        Ok(join(admin, segments))
+    }
+
+    // Fails with the last error and whether the request was retried before it.
+    async fn send(&self, idempotent: bool, request: impl Fn(&ReqwestClient) -> RequestBuilder) -> Result<Response, (Error, bool)> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let err = match request(&self.http).timeout(self.timeout).send().await {
+                Ok(resp) if resp.status().is_success() => return Ok(resp),
+                Ok(resp) => error_of(resp).await,
+                Err(e) => e.into(),
+            };
+            if !idempotent || attempt >= self.retries || !matches!(err.kind(), ErrorKind::Unavailable | ErrorKind::Timeout) {
+                return Err((err, attempt > 0));
+            }
+            attempt += 1;
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    async fn execute(&self, idempotent: bool, request: impl Fn(&ReqwestClient) -> RequestBuilder) -> Result<Response, Error> {
+        self.send(idempotent, request).await.map_err(|(err, _)| err)
+    }
+
+    // A retried deletion which finds nothing to delete means an earlier attempt went
+    // through and only its response was lost, so it succeeded without a response.
+    async fn delete(&self, request: impl Fn(&ReqwestClient) -> RequestBuilder) -> Result<Option<Response>, Error> {
+        match self.send(true, request).await {
+            Ok(resp) => Ok(Some(resp)),
+            Err((err, true)) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err((err, _)) => Err(err),
+        }
+    }
+
+    async fn json<T: DeserializeOwned>(&self, idempotent: bool, request: impl Fn(&ReqwestClient) -> RequestBuilder) -> Result<T, Error> {
+        data_of(self.execute(idempotent, request).await?).await
+    }
+
+    pub async fn add_user(&self, uid: &str) -> Result<(), Error> {
+        let url = self.url(&["users", uid]);
+        self.execute(false, |http| http.post(url.clone())).await?;
+        Ok(())
+    }
+
+    // When the response of the deletion was lost, the detached friends are not known any
+    // more and the report lists none.
+    pub async fn delete_user(&self, uid: &str, dry_run: bool) -> Result<DeleteUserReport, Error> {
+        let url = self.url(&["users", uid]);
+        let request = |http: &ReqwestClient| http.delete(url.clone()).query(&[("dry_run", dry_run)]);
+        if dry_run {
+            return self.json(true, request).await;
+        }
+        match self.delete(request).await? {
+            Some(resp) => data_of(resp).await,
+            None => Ok(DeleteUserReport {
+                uid: uid.to_owned(),
+                friends: Vec::new(),
+                dry_run,
+            }),
+        }
+    }
+
+    pub async fn add_friend(&self, uid_a: &str, uid_b: &str) -> Result<(), Error> {
+        let url = self.url(&["users", uid_a, "friends", uid_b]);
+        self.execute(false, |http| http.post(url.clone())).await?;
+        Ok(())
+    }
+
+    pub async fn delete_friend(&self, uid_a: &str, uid_b: &str) -> Result<(), Error> {
+        let url = self.url(&["users", uid_a, "friends", uid_b]);
+        self.delete(|http| http.delete(url.clone())).await?;
+        Ok(())
+    }
+
+    pub async fn is_friend(&self, uid_a: &str, uid_b: &str) -> Result<bool, Error> {
+        let url = self.url(&["users", uid_a, "friends", uid_b]);
+        match self.execute(true, |http| http.get(url.clone())).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn friends(&self, uid: &str) -> Result<Vec<String>, Error> {
+        let url = self.url(&["users", uid, "friends"]);
+        self.json(true, |http| http.get(url.clone())).await
+    }
+
+    pub async fn friends_many(&self, uids: Vec<String>) -> Result<BTreeMap<String, Vec<String>>, Error> {
+        let url = self.url(&["users", "friends:batch"]);
+        let body = FriendsBatchBody { uids };
+        self.json(true, |http| http.post(url.clone()).json(&body)).await
+    }
+
+    pub async fn recommendations(&self, uid: &str) -> Result<Vec<String>, Error> {
+        let url = self.url(&["users", uid, "recommendations"]);
+        self.json(true, |http| http.get(url.clone())).await
+    }
+
+    // The export is a download of the document, not a `protocol::Response`.
+    pub async fn export_user(&self, uid: &str) -> Result<UserExport<String>, Error> {
+        let url = self.url(&["users", uid, "export"]);
+        Ok(self.execute(true, |http| http.get(url.clone())).await?.json().await?)
+    }
+
+    pub async fn deactivate_user(&self, uid: &str) -> Result<(), Error> {
+        let url = self.url(&["users", uid, "deactivate"]);
+        self.execute(false, |http| http.post(url.clone())).await?;
+        Ok(())
+    }
+
+    pub async fn reactivate_user(&self, uid: &str) -> Result<(), Error> {
+        let url = self.url(&["users", uid, "reactivate"]);
+        self.execute(false, |http| http.post(url.clone())).await?;
+        Ok(())
+    }
+
+    pub async fn batch(&self, ops: Vec<BatchOp<String>>, atomic: bool) -> Result<BatchOutcome, Error> {
+        let url = self.url(&["friendships:batch"]);
+        let body = BatchBody { ops, atomic };
+        self.json(false, |http| http.post(url.clone()).json(&body)).await
+    }
+
+    pub async fn batch_friends(&self, uid: &str, add: Vec<String>, delete: Vec<String>, atomic: bool) -> Result<BatchOutcome, Error> {
+        let url = self.url(&["users", uid, "friends:batch"]);
+        let body = BatchFriendsBody { add, delete, atomic };
+        self.json(false, |http| http.post(url.clone()).json(&body)).await
+    }
+
+    // Subscribes to the live events of a user. The stream ends when the connection is
+    // closed and is not reopened, events published in between are missed.
+    pub async fn events(&self, uid: &str) -> Result<EventStream<String>, Error> {
+        let url = self.url(&["users", uid, "events"]);
+        let resp = self.http.get(url).header(ACCEPT, "text/event-stream").send().await?;
+        if !resp.status().is_success() {
+            return Err(error_of(resp).await);
+        }
+        let events = stream::unfold((resp, Vec::new()), |(mut resp, mut buf)| async move {
+            loop {
+                if let Some(end) = buf.windows(2).position(|w| w == b"\n\n") {
+                    let message = buf.drain(..end + 2).collect::<Vec<_>>();
+                    let message = String::from_utf8_lossy(&message);
+                    // Comments (keep-alives) have no data lines.
+                    let data = message.lines().filter_map(|line| line.strip_prefix("data:")).map(str::trim_start).collect::<Vec<_>>().join("\n");
+                    if let Ok(event) = serde_json::from_str(&data) {
+                        return Some((event, (resp, buf)));
+                    }
+                    continue;
+                }
+                match resp.chunk().await {
+                    Ok(Some(chunk)) => buf.extend_from_slice(&chunk),
+                    _ => return None,
+                }
+            }
+        });
+        Ok(Box::pin(events))
+    }
+
+    pub async fn import(&self, format: Format, data: String, batch_size: Option<usize>, skip: usize) -> Result<ImportResult, Error> {
+        let url = self.admin_url(&["admin", "import"])?;
+        let mut query = vec![("format", serde_json::to_value(format)?.as_str().unwrap_or_default().to_owned()), ("skip", skip.to_string())];
+        if let Some(batch_size) = batch_size {
+            query.push(("batch_size", batch_size.to_string()));
+        }
+        self.json(false, |http| http.post(url.clone()).query(&query).body(data.clone())).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cachers::Memory as MemoryCache;
+    use crate::core::Persister;
+    use crate::events::Event;
+    use crate::handlers;
+    use crate::persisters::Memory;
+    use crate::publishers::Memory as MemoryPublisher;
+    use crate::relay::Relay;
+    use actix_web::dev::Service;
+    use actix_web::{web::Data, App, HttpResponse, HttpServer};
+    use futures_util::StreamExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Serves the real routes on an ephemeral port, backed by the in-memory implementations.
+    fn serve() -> (HttpClient, Memory, MemoryPublisher) {
+        serve_lossy(0)
+    }
+
+    // Like `serve`, but the first `lost` requests to the public routes are handled and
+    // answered with a 503, as if their responses were lost on the way. As in main.rs, the
+    // admin routes are served by a server of their own.
+    fn serve_lossy(lost: usize) -> (HttpClient, Memory, MemoryPublisher) {
+        let lost = Arc::new(AtomicUsize::new(lost));
+        let persister = Memory::new();
+        let cacher = MemoryCache::default();
+        let subscriber = MemoryPublisher::default();
+        let (outbox, notifier) = (persister.clone(), subscriber.clone());
+        let admin = {
+            let (persister, cacher) = (persister.clone(), cacher.clone());
+            HttpServer::new(move || {
+                App::new()
+                    .wrap(crate::middleware::RequestMeta)
+                    .app_data(Data::new(persister.clone()))
+                    .app_data(Data::new(cacher.clone()))
+                    .configure(handlers::admin_routes::<Memory, MemoryCache>)
+            })
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .expect("failed to bind test admin server")
+        };
+        let admin_addr = admin.addrs()[0];
+        actix_web::rt::spawn(admin.run());
+        let server = HttpServer::new(move || {
+            let lost = lost.clone();
+            App::new()
+                .wrap_fn(move |req, srv| {
+                    let res = srv.call(req);
+                    let lost = lost.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok();
+                    async move {
+                        let res = res.await?;
+                        Ok(if lost { res.into_response(HttpResponse::ServiceUnavailable().finish()) } else { res })
+                    }
+                })
+                .wrap(crate::middleware::RequestMeta)
+                .app_data(Data::new(persister.clone()))
+                .app_data(Data::new(cacher.clone()))
+                .app_data(Data::new(subscriber.clone()))
+                .configure(handlers::routes::<Memory, MemoryCache, MemoryPublisher>)
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .expect("failed to bind test server");
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        let client = HttpClient::new(&format!("http://{}", addr)).unwrap().admin(&format!("http://{}", admin_addr)).unwrap();
+        (client, outbox, notifier)
+    }
+
+    #[actix_web::test]
+    async fn test_users_and_friends() {
+        let (client, outbox, _) = serve();
+        for uid in ["1", "2", "3"] {
+            client.add_user(uid).await.unwrap();
+        }
+        client.add_friend("1", "2").await.unwrap();
+        client.add_friend("1", "3").await.unwrap();
+        assert_eq!(client.friends("1").await.unwrap(), vec!["2".to_owned(), "3".to_owned()]);
+        assert!(client.is_friend("2", "1").await.unwrap());
+        assert!(!client.is_friend("2", "3").await.unwrap());
+        let many = client.friends_many(vec!["1".into(), "2".into(), "4".into()]).await.unwrap();
+        assert_eq!(many.get("2"), Some(&vec!["1".to_owned()]));
+        assert!(!many.contains_key("4"));
+        assert_eq!(client.export_user("1").await.unwrap().friendships.len(), 2);
+
+        client.delete_friend("1", "3").await.unwrap();
+        assert_eq!(client.friends("1").await.unwrap(), vec!["2".to_owned()]);
+        client.deactivate_user("2").await.unwrap();
+        assert!(client.friends("1").await.unwrap().is_empty());
+        assert!(!client.is_friend("1", "2").await.unwrap());
+        // A deactivated friend is detached by a delete too, so the dry run reports it.
+        let report = client.delete_user("1", true).await.unwrap();
+        assert!(report.dry_run && report.friends == vec!["2".to_owned()]);
+        client.reactivate_user("2").await.unwrap();
+        assert!(client.is_friend("1", "2").await.unwrap());
+
+        let report = client.delete_user("1", false).await.unwrap();
+        assert!(!report.dry_run && report.friends == vec!["2".to_owned()]);
+        let events = MemoryPublisher::default();
+        Relay::new(outbox, events.clone()).run_once().await.unwrap();
+        assert_eq!(
+            events.events(),
+            vec![
+                Event::UserCreated { uid: "1".into() },
+                Event::UserCreated { uid: "2".into() },
+                Event::UserCreated { uid: "3".into() },
+                Event::FriendAdded { uid: "1".into(), friend: "2".into() },
+                Event::FriendAdded { uid: "1".into(), friend: "3".into() },
+                Event::FriendRemoved { uid: "1".into(), friend: "3".into() },
+                Event::UserDeactivated {
+                    uid: "2".into(),
+                    friends: vec!["1".into()]
+                },
+                Event::UserReactivated {
+                    uid: "2".into(),
+                    friends: vec!["1".into()]
+                },
+                Event::UserDeleted {
+                    uid: "1".into(),
+                    friends: vec!["2".into()]
+                }
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_error_mapping() {
+        let (client, ..) = serve();
+        client.add_user("1").await.unwrap();
+        assert_eq!(client.add_user("1").await.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(client.recommendations("2").await.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(client.delete_user("2", true).await.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(client.add_friend("1", "1").await.unwrap_err().kind(), ErrorKind::InvalidInput);
+        client.deactivate_user("1").await.unwrap();
+        assert_eq!(client.deactivate_user("1").await.unwrap_err().kind(), ErrorKind::Conflict);
+    }
+
+    #[actix_web::test]
+    async fn test_batch_and_import() {
+        let (client, ..) = serve();
+        let result = client.import(Format::Csv, "user,1\nuser,2\nuser,3\nfriendship,1,4\nbad row\n".into(), Some(2), 0).await.unwrap();
+        assert_eq!((result.report.users, result.report.rejected), (3, 2));
+        assert_eq!(result.rejects, vec!["friendship,1,4".to_owned(), "bad row".to_owned()]);
+        // The public server does not serve the admin routes.
+        let public = HttpClient::new(client.base.as_str()).unwrap();
+        assert_eq!(public.import(Format::Csv, "user,5\n".into(), None, 0).await.unwrap_err().kind(), ErrorKind::InvalidInput);
+        let public = public.admin(client.base.as_str()).unwrap();
+        assert_eq!(public.import(Format::Csv, "user,5\n".into(), None, 0).await.unwrap_err().kind(), ErrorKind::NotFound);
+
+        let outcome = client
+            .batch(vec![BatchOp::Add { uid_a: "1".into(), uid_b: "2".into() }, BatchOp::Add { uid_a: "1".into(), uid_b: "4".into() }], true)
+            .await
+            .unwrap();
+        assert!(!outcome.committed);
+        let outcome = client.batch_friends("1", vec!["2".into(), "3".into()], vec![], true).await.unwrap();
+        assert!(outcome.committed);
+        assert_eq!(client.friends("1").await.unwrap(), vec!["2".to_owned(), "3".to_owned()]);
+        let ops = vec![BatchOp::Delete { uid_a: "1".into(), uid_b: "2".into() }; handlers::MAX_BATCH_OPS + 1];
+        assert_eq!(client.batch(ops, false).await.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(client.friends("1").await.unwrap(), vec!["2".to_owned(), "3".to_owned()]);
+    }
+
+    #[actix_web::test]
+    async fn test_events() {
+        let (client, outbox, notifier) = serve();
+        for uid in ["1", "2", "3"] {
+            client.add_user(uid).await.unwrap();
+        }
+        assert_eq!(client.events("4").await.err().map(|e| e.kind()), Some(ErrorKind::NotFound));
+        let relay = Relay::new(outbox, notifier);
+        relay.run_once().await.unwrap();
+        let mut events = client.events("1").await.unwrap();
+        client.add_friend("2", "1").await.unwrap();
+        client.add_friend("2", "3").await.unwrap();
+        client.delete_user("2", false).await.unwrap();
+        relay.run_once().await.unwrap();
+        assert_eq!(events.next().await, Some(Event::FriendAdded { uid: "2".into(), friend: "1".into() }));
+        assert_eq!(
+            events.next().await,
+            Some(Event::UserDeleted {
+                uid: "2".into(),
+                friends: vec!["1".into(), "3".into()]
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_lost_delete_response() {
+        let (client, persister, _) = serve_lossy(2);
+        let client = client.backoff(Duration::from_millis(10));
+        for uid in ["1", "2"] {
+            persister.insert_node(uid.into()).await.unwrap();
+        }
+        persister.insert("1".into(), "2".into()).await.unwrap();
+        client.delete_friend("1", "2").await.unwrap();
+        assert!(!client.is_friend("1", "2").await.unwrap());
+        let report = client.delete_user("1", false).await.unwrap();
+        assert!(report.friends.is_empty() && !report.dry_run);
+        assert!(!persister.exist_node("1".into()).await.unwrap());
+        // Without a lost response a missing user is still an error.
+        assert_eq!(client.delete_user("1", false).await.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(client.delete_friend("1", "2").await.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[actix_web::test]
+    async fn test_retry() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let client = HttpClient::new(&format!("http://{}/", addr)).unwrap().backoff(Duration::from_millis(10));
+        assert_eq!(client.friends("1").await.unwrap_err().kind(), ErrorKind::Unavailable);
+        assert_eq!(client.url(&["users", "a/b", "friends:batch"]).path(), "/users/a%2Fb/friends:batch");
+        assert_eq!(kind_of_status(504), ErrorKind::Timeout);
+        assert_eq!(kind_of_status(502), ErrorKind::Internal);
+    }
+}
@@ -0,0 +1,446 @@
+use crate::core::{Cacher, Persister};
+use crate::error::Error;
+use crate::models::Record;
+use chrono::DateTime;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Csv,
+    Jsonl,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::Jsonl),
+            _ => Err(Error::invalid_input(format!("unsupported import format: {}", s))),
+        }
+    }
+}
+
+impl Format {
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".csv") {
+            Format::Csv
+        } else {
+            Format::Jsonl
+        }
+    }
+
+    // CSV rows are `user,<uid>[,deactivated]` or `friendship,<uid_a>,<uid_b>[,<created_at>]`, JSON Lines rows are
+    // serialized `Record`s. Rows the persister would refuse are rejected here, so they don't fail their whole batch.
+    pub fn parse(&self, line: &str) -> Result<Record<String>, Error> {
+        let mut record = self.parse_record(line)?;
+        match &mut record {
+            Record::User { uid, .. } => check_uid(uid)?,
+            Record::Friendship { uid_a, uid_b, created_at } => {
+                check_uid(uid_a)?;
+                check_uid(uid_b)?;
+                if uid_a == uid_b {
+                    return Err(Error::invalid_input(format!("user {} can not be friend of itself", uid_a)));
+                }
+                if let Some(at) = created_at {
+                    let parsed = DateTime::parse_from_rfc3339(at).map_err(|e| Error::invalid_input(format!("invalid created_at {:?}: {}", at, e)))?;
+                    *at = parsed.to_rfc3339();
+                }
+            }
+        }
+        Ok(record)
+    }
+
+    fn parse_record(&self, line: &str) -> Result<Record<String>, Error> {
+        match self {
+            Format::Jsonl => serde_json::from_str(line).map_err(|e| Error::invalid_input(format!("{}", e))),
+            Format::Csv => {
+                let mut reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).trim(csv::Trim::All).from_reader(line.as_bytes());
+                let row = reader
+                    .records()
+                    .next()
+                    .ok_or_else(|| Error::invalid_input("empty row".into()))?
+                    .map_err(|e| Error::invalid_input(format!("{}", e)))?;
+                let fields = row.iter().collect::<Vec<_>>();
+                match fields.as_slice() {
+                    ["user", uid] if !uid.is_empty() => Ok(Record::User {
+                        uid: uid.to_string(),
+                        deactivated: false,
+                    }),
+                    ["user", uid, "deactivated"] if !uid.is_empty() => Ok(Record::User {
+                        uid: uid.to_string(),
+                        deactivated: true,
+                    }),
+                    ["friendship", uid_a, uid_b, created_at @ ..] if !uid_a.is_empty() && !uid_b.is_empty() && created_at.len() <= 1 => Ok(Record::Friendship {
+                        uid_a: uid_a.to_string(),
+                        uid_b: uid_b.to_string(),
+                        created_at: created_at.first().map(|at| at.to_string()),
+                    }),
+                    _ => Err(Error::invalid_input(format!("invalid row: {}", line))),
+                }
+            }
+        }
+    }
+}
+
+// Control characters include the separators neo4j batches are joined with.
+fn check_uid(uid: &str) -> Result<(), Error> {
+    if uid.is_empty() || uid.chars().any(char::is_control) {
+        return Err(Error::invalid_input(format!("invalid uid {:?}", uid)));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub lines: usize,
+    pub users: usize,
+    pub friendships: usize,
+    pub rejected: usize,
+}
+
+// How far an import got: the lines handled and the length of the reject file at that
+// point, so a resumed import can cut off rejects written after it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub lines: usize,
+    pub rejects: u64,
+}
+
+pub struct Importer<'a, P, C, W> {
+    persister: &'a P,
+    cacher: &'a C,
+    format: Format,
+    batch_size: usize,
+    skip: usize,
+    rejects: W,
+    rejects_len: u64,
+    checkpoint: Option<PathBuf>,
+    pending: Vec<(usize, String, Record<String>)>,
+    // Rejected lines and the reasons by line number, written with the next checkpoint.
+    rejected: Vec<(usize, String, String)>,
+    report: ImportReport,
+}
+
+impl<'a, P: Persister<UID = String>, C: Cacher<UID = String>, W: Write> Importer<'a, P, C, W> {
+    pub fn new(persister: &'a P, cacher: &'a C, format: Format, rejects: W) -> Self {
+        Self {
+            persister,
+            cacher,
+            format,
+            batch_size: DEFAULT_BATCH_SIZE,
+            skip: 0,
+            rejects,
+            rejects_len: 0,
+            checkpoint: None,
+            pending: Vec::new(),
+            rejected: Vec::new(),
+            report: ImportReport::default(),
+        }
+    }
+
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    // Lines up to `skip` were imported by an earlier run and are only counted.
+    pub fn skip(mut self, skip: usize) -> Self {
+        self.skip = skip;
+        self
+    }
+
+    // Continues after `checkpoint`, writing to a reject file which was cut back to the
+    // checkpointed length.
+    pub fn resume(mut self, checkpoint: Checkpoint) -> Self {
+        self.skip = checkpoint.lines;
+        self.rejects_len = checkpoint.rejects;
+        self
+    }
+
+    // A `Checkpoint` is written to `path` after every batch, together with the rejects of
+    // the batch, so an interrupted import can be resumed with `resume(read_checkpoint(path))`.
+    pub fn checkpoint(mut self, path: PathBuf) -> Self {
+        self.checkpoint = Some(path);
+        self
+    }
+
+    pub async fn feed(&mut self, line: &str) -> Result<(), Error> {
+        self.report.lines += 1;
+        // Comments hold the reasons in a reject file, so it can be imported again once fixed.
+        if self.report.lines <= self.skip || line.trim().is_empty() || line.trim_start().starts_with('#') {
+            return Ok(());
+        }
+        match self.format.parse(line.trim()) {
+            Ok(record) => self.pending.push((self.report.lines, line.to_owned(), record)),
+            Err(e) => self.reject(self.report.lines, line, e.to_string()),
+        }
+        if self.pending.len() >= self.batch_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> Result<ImportReport, Error> {
+        self.flush().await?;
+        info!(
+            "import finished: {} lines, {} users, {} friendships, {} rejected",
+            self.report.lines, self.report.users, self.report.friendships, self.report.rejected
+        );
+        Ok(self.report)
+    }
+
+    fn reject(&mut self, line_no: usize, line: &str, reason: String) {
+        warn!("rejected import row {:?}: {}", line, reason);
+        self.rejected.push((line_no, line.to_owned(), reason));
+        self.report.rejected += 1;
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        let pending = std::mem::take(&mut self.pending);
+        let mut uids = Vec::new();
+        let mut deactivated = Vec::new();
+        let mut pairs = Vec::new();
+        for (_, _, record) in &pending {
+            match record {
+                Record::User { uid, deactivated: d } => {
+                    uids.push(uid.clone());
+                    if *d {
+                        deactivated.push(uid.clone());
+                    }
+                }
+                Record::Friendship { uid_a, uid_b, created_at } => pairs.push((uid_a.clone(), uid_b.clone(), created_at.clone())),
+            }
+        }
+        self.report.users += self.persister.insert_nodes(uids).await?.len();
+        let outcome = self.persister.insert_many(pairs).await?;
+        // Friendships of deactivated users are refused, so a snapshot deactivates its users
+        // only after their friendships are in.
+        let mut touched = self.persister.deactivate_many(deactivated).await?.into_iter().collect::<BTreeSet<_>>();
+        let mut created = outcome.created.into_iter().collect::<HashSet<_>>();
+        let rejected = outcome.rejected.into_iter().collect::<HashMap<_, _>>();
+        for (line_no, line, record) in &pending {
+            if let Record::Friendship { uid_a, uid_b, .. } = record {
+                let pair = (uid_a.clone(), uid_b.clone());
+                if let Some(reason) = rejected.get(&pair) {
+                    self.reject(*line_no, line, reason.detail.clone());
+                } else if created.remove(&pair) {
+                    // Like users, friendships which existed already are not counted.
+                    self.report.friendships += 1;
+                    touched.insert(pair.0);
+                    touched.insert(pair.1);
+                }
+            }
+        }
+        for uid in touched {
+            self.cacher.delete(uid).await?;
+        }
+        self.rejected.sort();
+        for (_, line, reason) in std::mem::take(&mut self.rejected) {
+            let entry = format!("# {}\n{}\n", reason.replace('\n', " "), line);
+            self.rejects.write_all(entry.as_bytes())?;
+            self.rejects_len += entry.len() as u64;
+        }
+        self.rejects.flush()?;
+        if let Some(path) = &self.checkpoint {
+            std::fs::write(path, format!("{} {}", self.report.lines, self.rejects_len))?;
+        }
+        info!(
+            "import progress: {} lines, {} users, {} friendships, {} rejected",
+            self.report.lines, self.report.users, self.report.friendships, self.report.rejected
+        );
+        Ok(())
+    }
+}
+
+pub fn read_checkpoint(path: &PathBuf) -> Result<Checkpoint, Error> {
+    let invalid = || Error::invalid_input(format!("invalid checkpoint file: {}", path.display()));
+    match std::fs::read_to_string(path) {
+        Ok(s) => match s.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [lines, rejects] => Ok(Checkpoint {
+                lines: lines.parse().map_err(|_| invalid())?,
+                rejects: rejects.parse().map_err(|_| invalid())?,
+            }),
+            _ => Err(invalid()),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Checkpoint::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn test_parse_csv() {
+        assert_eq!(
+            Format::Csv.parse("user,1").unwrap(),
+            Record::User {
+                uid: 1.to_string(),
+                deactivated: false
+            }
+        );
+        assert_eq!(
+            Format::Csv.parse("user,1,deactivated").unwrap(),
+            Record::User {
+                uid: 1.to_string(),
+                deactivated: true
+            }
+        );
+        assert_eq!(
+            Format::Csv.parse("friendship, 1,\"2\"").unwrap(),
+            Record::Friendship {
+                uid_a: 1.to_string(),
+                uid_b: 2.to_string(),
+                created_at: None
+            }
+        );
+        assert_eq!(
+            Format::Csv.parse("friendship,2,1,2024-01-02T03:04:05Z").unwrap(),
+            Record::Friendship {
+                uid_a: 2.to_string(),
+                uid_b: 1.to_string(),
+                created_at: Some("2024-01-02T03:04:05+00:00".into())
+            }
+        );
+        assert!(Format::Csv.parse("friendship,1,2,yesterday").is_err());
+        assert!(Format::Csv.parse("friendship,1,2,2024-01-02T03:04:05Z,x").is_err());
+        assert!(Format::Csv.parse("friendship,1").is_err());
+        assert!(Format::Csv.parse("user,").is_err());
+    }
+
+    #[test]
+    fn test_parse_jsonl() {
+        assert_eq!(
+            Format::Jsonl.parse(r#"{"type":"User","uid":"1"}"#).unwrap(),
+            Record::User {
+                uid: 1.to_string(),
+                deactivated: false
+            }
+        );
+        assert_eq!(
+            Format::Jsonl.parse(r#"{"type":"Friendship","uid_a":"1","uid_b":"2"}"#).unwrap(),
+            Record::Friendship {
+                uid_a: 1.to_string(),
+                uid_b: 2.to_string(),
+                created_at: None
+            }
+        );
+        assert_eq!(
+            Format::Jsonl
+                .parse(r#"{"type":"Friendship","uid_a":"1","uid_b":"2","created_at":"2024-01-02T11:04:05+08:00"}"#)
+                .unwrap(),
+            Record::Friendship {
+                uid_a: 1.to_string(),
+                uid_b: 2.to_string(),
+                created_at: Some("2024-01-02T11:04:05+08:00".into())
+            }
+        );
+        assert!(Format::Jsonl.parse(r#"{"type":"Friendship","uid_a":"1"}"#).is_err());
+        assert!(Format::Jsonl.parse(r#"{"type":"User","uid":""}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_uids() {
+        assert_eq!(Format::Jsonl.parse(r#"{"type":"User","uid":"1\u001e2"}"#).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(Format::Csv.parse("friendship,1,2\u{1f}3").unwrap_err().kind(), ErrorKind::InvalidInput);
+        let err = Format::Csv.parse("friendship,1,1").unwrap_err();
+        assert_eq!(format!("{}", err), "user 1 can not be friend of itself");
+    }
+
+    #[tokio::test]
+    async fn test_reject_invalid_uid() {
+        use crate::cachers::Memory as MemoryCache;
+        use crate::persisters::Memory;
+
+        let (persister, cacher) = (Memory::new(), MemoryCache::default());
+        let mut rejects = Vec::new();
+        let mut importer = Importer::new(&persister, &cacher, Format::Jsonl, &mut rejects);
+        for line in [r#"{"type":"User","uid":"1"}"#, r#"{"type":"User","uid":"2\u001e"}"#, r#"{"type":"User","uid":"3"}"#] {
+            importer.feed(line).await.unwrap();
+        }
+        let report = importer.finish().await.unwrap();
+        assert_eq!((report.users, report.rejected), (2, 1));
+        assert_eq!(String::from_utf8(rejects).unwrap(), "# invalid uid \"2\\u{1e}\"\n{\"type\":\"User\",\"uid\":\"2\\u001e\"}\n");
+        assert!(persister.exist_node("3".into()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        use crate::cachers::Memory as MemoryCache;
+        use crate::persisters::Memory;
+
+        let (persister, cacher) = (Memory::new(), MemoryCache::default());
+        let dir = std::env::temp_dir().join(format!("import-resume-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let checkpoint = dir.join("checkpoint");
+        let lines = ["user,1", "user,", "user,2", "friendship,1,1", "user,1", "user,3"];
+        let mut rejects = Vec::new();
+        let mut importer = Importer::new(&persister, &cacher, Format::Csv, &mut rejects).batch_size(2).checkpoint(checkpoint.clone());
+        for line in &lines[..4] {
+            importer.feed(line).await.unwrap();
+        }
+        // Interrupted after the first batch, the self-pair was rejected but not checkpointed yet.
+        drop(importer);
+        let at = read_checkpoint(&checkpoint).unwrap();
+        assert_eq!(at, Checkpoint { lines: 3, rejects: 27 });
+        rejects.truncate(at.rejects as usize);
+        let mut importer = Importer::new(&persister, &cacher, Format::Csv, &mut rejects).resume(at).checkpoint(checkpoint.clone());
+        for line in lines {
+            importer.feed(line).await.unwrap();
+        }
+        let report = importer.finish().await.unwrap();
+        // User 1 existed already, only user 3 is created.
+        assert_eq!((report.users, report.rejected), (1, 1));
+        assert_eq!(
+            String::from_utf8(rejects).unwrap(),
+            "# invalid row: user,\nuser,\n# user 1 can not be friend of itself\nfriendship,1,1\n"
+        );
+        assert_eq!(read_checkpoint(&checkpoint).unwrap(), Checkpoint { lines: 6, rejects: 79 });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_friendship_rejects() {
+        use crate::cachers::Memory as MemoryCache;
+        use crate::persisters::Memory;
+
+        let (persister, cacher) = (Memory::new(), MemoryCache::default());
+        persister.insert_nodes((1..=3).map(|uid| uid.to_string()).collect()).await.unwrap();
+        persister.insert("1".into(), "2".into()).await.unwrap();
+        persister.deactivate("3".into()).await.unwrap();
+        let lines = ["friendship,2,1", "friendship,1,5", "friendship,1,3", "friendship,1,1", "user,4", "friendship,2,4", "friendship,2,4"];
+        let mut rejects = Vec::new();
+        let mut importer = Importer::new(&persister, &cacher, Format::Csv, &mut rejects);
+        for line in lines {
+            importer.feed(line).await.unwrap();
+        }
+        let report = importer.finish().await.unwrap();
+        // The existing friendship and the repeated line are not counted.
+        assert_eq!((report.users, report.friendships, report.rejected), (1, 1, 3));
+        let rejects = String::from_utf8(rejects).unwrap();
+        assert_eq!(
+            rejects,
+            "# user 5 not found\nfriendship,1,5\n\
+             # user 1 or user 3 is deactivated\nfriendship,1,3\n\
+             # user 1 can not be friend of itself\nfriendship,1,1\n"
+        );
+
+        // The reasons are comments, so the reject file can be imported again.
+        let mut importer = Importer::new(&persister, &cacher, Format::Csv, std::io::sink());
+        for line in rejects.lines() {
+            importer.feed(line).await.unwrap();
+        }
+        assert_eq!(importer.finish().await.unwrap().rejected, 3);
+    }
+}
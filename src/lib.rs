@@ -0,0 +1,40 @@
+pub mod cachers;
+pub mod cli;
+pub mod client;
+pub mod consumer;
+pub mod core;
+pub mod error;
+pub mod events;
+pub mod exporter;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handlers;
+#[cfg(feature = "http-client")]
+pub mod http_client;
+pub mod importer;
+#[cfg(any(feature = "grpc", feature = "graphql"))]
+pub mod local;
+pub mod middleware;
+pub mod models;
+pub mod outputers;
+pub mod persisters;
+pub mod protocol;
+pub mod publishers;
+pub mod relay;
+pub mod websocket;
+
+use models::BatchOp;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    Add { uid_a: i64, uid_b: i64 },
+    Delete { uid_a: i64, uid_b: i64 },
+    Friends { uid: i64 },
+    Recommendation { uid: i64 },
+    AddNode { uid: i64 },
+    DeleteNode { uid: i64 },
+    Batch { ops: Vec<BatchOp<i64>>, atomic: bool },
+}
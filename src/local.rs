@@ -0,0 +1,50 @@
+use crate::core::{Cacher, Persister};
+use crate::error::Error;
+use std::future::Future;
+use std::rc::Rc;
+use tokio::sync::{mpsc, oneshot};
+
+type Job<P, C> = Box<dyn FnOnce(Rc<P>, Rc<C>) + Send>;
+
+// The futures of `Persister` and `Cacher` are not `Send`, so servers which need `Send`
+// handlers (tonic, async-graphql) ship their calls to a task on the local executor which
+// owns both.
+pub struct Local<P, C> {
+    jobs: mpsc::UnboundedSender<Job<P, C>>,
+}
+
+impl<P, C> Clone for Local<P, C> {
+    fn clone(&self) -> Self {
+        Self { jobs: self.jobs.clone() }
+    }
+}
+
+impl<P: Persister<UID = String> + 'static, C: Cacher<UID = String> + 'static> Local<P, C> {
+    // Has to be called on the actix runtime, which then runs the calls.
+    pub fn new(persister: P, cacher: C) -> Self {
+        let (jobs, mut pending) = mpsc::unbounded_channel::<Job<P, C>>();
+        let (persister, cacher) = (Rc::new(persister), Rc::new(cacher));
+        actix_web::rt::spawn(async move {
+            while let Some(job) = pending.recv().await {
+                job(persister.clone(), cacher.clone());
+            }
+        });
+        Self { jobs }
+    }
+
+    pub async fn call<T, F, Fut>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(Rc<P>, Rc<C>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, Error>> + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job<P, C> = Box::new(move |persister, cacher| {
+            actix_web::rt::spawn(async move {
+                let _ = tx.send(f(persister, cacher).await);
+            });
+        });
+        self.jobs.send(job).map_err(|_| Error::unavailable("service is shutting down".into()))?;
+        rx.await.map_err(|_| Error::unavailable("service is shutting down".into()))?
+    }
+}
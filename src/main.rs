@@ -1,45 +1,25 @@
-mod cachers;
-mod client;
-mod core;
-mod error;
-mod handlers;
-mod outputers;
-mod persisters;
-mod r2d2;
-
-use actix_web::{
-    web::{delete, get, post, Data},
-    App, HttpServer,
-};
-use cachers::Redis;
-use env_logger;
+use actix_web::{web::Data, App, HttpServer};
 use log::warn;
 use neo4rs::Graph;
-use persisters::Neo;
-use redis;
-use serde::{Deserialize, Serialize};
+use rdkafka::{
+    config::ClientConfig as KafkaConfig,
+    consumer::{Consumer as _, StreamConsumer},
+    producer::FutureProducer,
+};
 use std::sync::Arc;
-
-#[derive(Serialize, Deserialize)]
-pub enum Request {
-    Add { uid_a: i64, uid_b: i64 },
-    Delete { uid_a: i64, uid_b: i64 },
-    Friends { uid: i64 },
-    Recommendation { uid: i64 },
-    AddNode { uid: i64 },
-    DeleteNode { uid: i64 },
-}
-
-#[derive(Debug, Serialize)]
-pub enum Response<T: Serialize> {
-    Ok { data: T },
-    Err { detail: String },
-}
-
-pub trait Outputer<K, E, RE> {
-    fn ok<T: Serialize>(&self, key: K, data: T) -> Result<(), RE>;
-    fn error(&self, key: K, err: E) -> Result<(), RE>;
-}
+use std::time::Duration;
+use with_baby_friendship::cachers::{Redis, RedisProcessed, DEFAULT_PROCESSED_TTL};
+use with_baby_friendship::consumer::{self, Consumer};
+#[cfg(feature = "graphql")]
+use with_baby_friendship::graphql;
+#[cfg(feature = "grpc")]
+use with_baby_friendship::grpc;
+use with_baby_friendship::outputers::{self, KafkaOutput, RedisOutput, Router, WebhookOutput, DEFAULT_REPLY_TTL};
+use with_baby_friendship::persisters::Neo;
+use with_baby_friendship::protocol::{DEFAULT_DEAD_LETTER_TOPIC, DEFAULT_DELIVERY_FAILURE_TOPIC, DEFAULT_REQUEST_TOPIC};
+use with_baby_friendship::publishers::{Fanout, Kafka, RedisPubSub};
+use with_baby_friendship::relay::{self, Relay};
+use with_baby_friendship::{cli, handlers, middleware};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -56,20 +36,131 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("failed to connect to neo4j"),
     );
-    HttpServer::new(move || {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        let r = redis::Client::open("redis://localhost").expect("failed to connect to redis");
+        return cli::run(&args, Neo::new(graph), Redis::new(r)).await.map_err(|e| std::io::Error::other(e.to_string()));
+    }
+    let kafka_address = dotenv::var("KAFKA_ADDRESS").unwrap_or("localhost:9092".into());
+    let producer: FutureProducer = KafkaConfig::new().set("bootstrap.servers", &kafka_address).create().expect("failed to create kafka producer");
+    let requests: StreamConsumer = KafkaConfig::new()
+        .set("bootstrap.servers", &kafka_address)
+        .set("group.id", dotenv::var("CONSUMER_GROUP").unwrap_or("with-baby-friendship".into()))
+        .set("enable.auto.commit", "false")
+        .create()
+        .expect("failed to create kafka consumer");
+    requests
+        .subscribe(&[&dotenv::var("REQUEST_TOPIC").unwrap_or(DEFAULT_REQUEST_TOPIC.into())])
+        .expect("failed to subscribe to request topic");
+    let reply_ttl = dotenv::var("REPLY_TTL").ok().and_then(|ttl| ttl.parse().ok()).map_or(DEFAULT_REPLY_TTL, Duration::from_secs);
+    let webhook_secret = dotenv::var("WEBHOOK_SECRET").unwrap_or_default();
+    if webhook_secret.is_empty() {
+        warn!("WEBHOOK_SECRET not set, webhook replies are signed with an empty key");
+    }
+    let delivery_failures = Kafka::new(producer.clone(), dotenv::var("DELIVERY_FAILURE_TOPIC").unwrap_or(DEFAULT_DELIVERY_FAILURE_TOPIC.into()));
+    let webhook_retries = dotenv::var("WEBHOOK_RETRIES")
+        .ok()
+        .and_then(|retries| retries.parse().ok())
+        .unwrap_or(outputers::DEFAULT_WEBHOOK_RETRIES);
+    let webhook_backoff = dotenv::var("WEBHOOK_BACKOFF_MS")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .map_or(Duration::from_millis(100), Duration::from_millis);
+    let outputer = Router::new(
+        RedisOutput::new("redis://localhost").expect("failed to connect to redis").reply_ttl(reply_ttl),
+        KafkaOutput::new(producer.clone()),
+        WebhookOutput::new(webhook_secret.into_bytes(), delivery_failures)
+            .expect("failed to create webhook client")
+            .retries(webhook_retries)
+            .backoff(webhook_backoff),
+    );
+    let r = redis::Client::open("redis://localhost").expect("failed to connect to redis");
+    let dead_letters = Kafka::new(producer.clone(), dotenv::var("DEAD_LETTER_TOPIC").unwrap_or(DEFAULT_DEAD_LETTER_TOPIC.into()));
+    let retries = dotenv::var("CONSUMER_RETRIES").ok().and_then(|retries| retries.parse().ok()).unwrap_or(consumer::DEFAULT_RETRIES);
+    let backoff = dotenv::var("CONSUMER_BACKOFF_MS")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .map_or(Duration::from_millis(100), Duration::from_millis);
+    let processed_ttl = dotenv::var("PROCESSED_TTL").ok().and_then(|ttl| ttl.parse().ok()).map_or(DEFAULT_PROCESSED_TTL, Duration::from_secs);
+    let lease = dotenv::var("CONSUMER_LEASE")
+        .ok()
+        .and_then(|lease| lease.parse().ok())
+        .map_or(consumer::DEFAULT_LEASE, Duration::from_secs);
+    let processed = RedisProcessed::new(r.clone(), processed_ttl);
+    let consumer = Consumer::new(Neo::new(graph.clone()), Redis::new(r.clone()), outputer, dead_letters, processed)
+        .retries(retries)
+        .backoff(backoff)
+        .lease(lease);
+    actix_web::rt::spawn(async move { consumer.run(requests).await });
+    let publisher = Fanout::new(Kafka::new(producer.clone(), dotenv::var("EVENT_TOPIC").unwrap_or("friendship_events".into())), RedisPubSub::new(r));
+    let relay_interval = dotenv::var("OUTBOX_INTERVAL_MS")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .map_or(Duration::from_millis(500), Duration::from_millis);
+    let relay_batch_size = dotenv::var("OUTBOX_BATCH_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(relay::DEFAULT_BATCH_SIZE);
+    let relay_lease = dotenv::var("OUTBOX_LEASE_MS").ok().and_then(|ms| ms.parse().ok()).map_or(relay::DEFAULT_LEASE, Duration::from_millis);
+    let relay_retention = dotenv::var("OUTBOX_RETENTION")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map_or(relay::DEFAULT_RETENTION, Duration::from_secs);
+    let outbox = Neo::new(graph.clone());
+    outbox.create_schema().await.expect("failed to create the outbox schema");
+    let relay = Relay::new(outbox, publisher)
+        .batch_size(relay_batch_size)
+        .interval(relay_interval)
+        .lease(relay_lease)
+        .retention(relay_retention);
+    actix_web::rt::spawn(async move { relay.run().await });
+    #[cfg(feature = "grpc")]
+    {
+        let address = dotenv::var("GRPC_ADDRESS").unwrap_or("0.0.0.0:50051".into()).parse().expect("invalid GRPC_ADDRESS");
+        let r = redis::Client::open("redis://localhost").expect("failed to connect to redis");
+        let service = grpc::FriendshipServer::new(grpc::FriendshipService::new(Neo::new(graph.clone()), Redis::new(r)));
+        actix_web::rt::spawn(async move {
+            if let Err(e) = tonic::transport::Server::builder().add_service(service).serve(address).await {
+                warn!("gRPC server stopped: {}", e);
+            }
+        });
+    }
+    #[cfg(feature = "graphql")]
+    let graphql = {
+        let max_depth = dotenv::var("GRAPHQL_MAX_DEPTH").ok().and_then(|depth| depth.parse().ok()).unwrap_or(graphql::DEFAULT_MAX_DEPTH);
+        let max_complexity = dotenv::var("GRAPHQL_MAX_COMPLEXITY")
+            .ok()
+            .and_then(|complexity| complexity.parse().ok())
+            .unwrap_or(graphql::DEFAULT_MAX_COMPLEXITY);
+        let r = redis::Client::open("redis://localhost").expect("failed to connect to redis");
+        graphql::GraphQL::new(Neo::new(graph.clone()), Redis::new(r)).limits(max_depth, max_complexity)
+    };
+    let admin = {
+        let graph = graph.clone();
+        HttpServer::new(move || {
+            let r = redis::Client::open("redis://localhost").expect("failed to connect to redis");
+            App::new()
+                .wrap(middleware::RequestMeta)
+                .app_data(Data::new(Neo::new(graph.clone())))
+                .app_data(Data::new(Redis::new(r)))
+                .configure(handlers::admin_routes::<Neo, Redis>)
+        })
+        .bind(dotenv::var("ADMIN_ADDRESS").unwrap_or("127.0.0.1:8001".into()))?
+        .run()
+    };
+    let public = HttpServer::new(move || {
         let p = Neo::new(graph.clone());
         let r = redis::Client::open("redis://localhost").expect("failed to connect to redis");
-        let c = Redis::new(r);
-        App::new()
+        let c = Redis::new(r.clone());
+        let s = RedisPubSub::new(r);
+        let app = App::new()
+            .wrap(middleware::RequestMeta)
             .app_data(Data::new(p))
             .app_data(Data::new(c))
-            .route("/users/{uid}", post().to(handlers::add_user::<Neo, Redis>))
-            .route("/users/{uid_a}/friends/{uid_b}", post().to(handlers::add_friend::<Neo, Redis>))
-            .route("/users/{uid_a}/friends/{uid_b}", delete().to(handlers::delete_friend::<Neo, Redis>))
-            .route("/users/{uid}/friends", get().to(handlers::query_friends::<Neo, Redis>))
-            .route("/users/{uid_a}/friends/{uid_b}", get().to(handlers::is_friend::<Neo, Redis>))
+            .app_data(Data::new(s))
+            .configure(handlers::routes::<Neo, Redis, RedisPubSub>);
+        #[cfg(feature = "graphql")]
+        let app = app.app_data(Data::new(graphql.clone())).configure(graphql::routes);
+        app
     })
     .bind(dotenv::var("ADDRESS").unwrap_or("0.0.0.0:8000".into()))?
-    .run()
-    .await
+    .run();
+    futures_util::future::try_join(public, admin).await.map(|_| ())
 }
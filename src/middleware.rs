@@ -0,0 +1,121 @@
+use crate::error::Error;
+use crate::protocol::{Meta, Response, REQUEST_ID_HEADER, SERVER_TIMING_HEADER};
+use actix_web::body::{BoxBody, EitherBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use std::time::Instant;
+use uuid::Uuid;
+
+// Request ID and start of a request, kept in its extensions for the `Meta` of the reply.
+#[derive(Clone)]
+struct Served {
+    request_id: String,
+    started: Instant,
+}
+
+// `Meta` of the reply to `req`, empty when it did not pass through `RequestMeta`.
+pub fn meta(req: &HttpRequest) -> Meta {
+    req.extensions().get::<Served>().map(|served| Meta::new(served.request_id.clone(), served.started)).unwrap_or_default()
+}
+
+// Gives HTTP responses the `Meta` the other transports put in the reply body: the
+// request ID, echoed from the request or generated, and the time spent serving it. It
+// is sent as headers, and in the `protocol::Response` body of every reply; error bodies
+// are written again here, as `Error::error_response` knows nothing of the request.
+pub struct RequestMeta;
+
+impl<S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static, B: 'static> Transform<S, ServiceRequest> for RequestMeta {
+    type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+    type Error = actix_web::Error;
+    type Transform = RequestMetaMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetaMiddleware { service }))
+    }
+}
+
+pub struct RequestMetaMiddleware<S> {
+    service: S,
+}
+
+impl<S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static, B: 'static> Service<ServiceRequest> for RequestMetaMiddleware<S> {
+    type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        req.extensions_mut().insert(Served {
+            request_id: request_id.clone(),
+            started,
+        });
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            let meta = Meta::new(request_id, started);
+            let err = res.response().error().and_then(|e| e.as_error::<Error>()).cloned();
+            let mut res = match err {
+                Some(err) => res
+                    .into_response(HttpResponse::build(err.status_code()).json(Response::<()>::new(Err(err), meta.clone())))
+                    .map_into_right_body(),
+                None => res.map_into_left_body(),
+            };
+            let headers = res.headers_mut();
+            let values = [
+                (REQUEST_ID_HEADER, meta.request_id),
+                (SERVER_TIMING_HEADER, meta.elapsed_ms.map(|elapsed_ms| format!("app;dur={}", elapsed_ms))),
+            ];
+            for (name, value) in values {
+                if let (Ok(name), Some(Ok(value))) = (HeaderName::from_bytes(name.as_bytes()), value.as_deref().map(HeaderValue::from_str)) {
+                    headers.insert(name, value);
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ErrorKind;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App};
+
+    #[actix_web::test]
+    async fn test_request_meta() {
+        let app = init_service(
+            App::new()
+                .wrap(RequestMeta)
+                .route("/", web::get().to(|| async { "ok" }))
+                .route("/error", web::get().to(|| async { Err::<String, _>(Error::not_found("user 1 not found".into())) })),
+        )
+        .await;
+        let res = call_service(&app, TestRequest::get().uri("/").insert_header((REQUEST_ID_HEADER, "0f6e")).to_request()).await;
+        assert_eq!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "0f6e");
+        assert!(res.headers().get(SERVER_TIMING_HEADER).unwrap().to_str().unwrap().starts_with("app;dur="));
+
+        let res = call_service(&app, TestRequest::get().uri("/missing").to_request()).await;
+        let request_id = res.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap();
+        assert!(Uuid::parse_str(request_id).is_ok());
+
+        let res = call_service(&app, TestRequest::get().uri("/error").insert_header((REQUEST_ID_HEADER, "0f6e")).to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body: Response<()> = read_body_json(res).await;
+        assert_eq!(body.meta().request_id.as_deref(), Some("0f6e"));
+        assert_eq!(body.into_result().unwrap_err().kind(), ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::error::ErrorBody;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Friendship<UID> {
+    pub uid: UID,
+    pub initiated: bool,
+    pub created_at: Option<String>,
+}
+
+// A friendship seen from the smaller uid of the pair, as `Persister::list_friendships`
+// pages them.
+pub type FriendshipPair<UID> = (UID, Friendship<UID>);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserExport<UID> {
+    pub uid: UID,
+    pub exported_at: String,
+    pub profile: BTreeMap<String, String>,
+    pub friendships: Vec<Friendship<UID>>,
+}
+
+// A row of an import or a snapshot. A friendship is stored as `uid_a` added `uid_b`, at
+// `created_at` (RFC 3339) if known.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Record<UID> {
+    User {
+        uid: UID,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        deactivated: bool,
+    },
+    Friendship {
+        uid_a: UID,
+        uid_b: UID,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        created_at: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op")]
+pub enum BatchOp<UID> {
+    Add { uid_a: UID, uid_b: UID },
+    Delete { uid_a: UID, uid_b: UID },
+}
+
+// The friendships `Persister::insert_many` created and the pairs it refused, each with the
+// reason. Pairs which were friends already are in neither.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct InsertOutcome<UID> {
+    pub created: Vec<(UID, UID)>,
+    pub rejected: Vec<((UID, UID), ErrorBody)>,
+}
+
+// `results` holds one entry per operation, `None` for the ones which succeeded. When an
+// atomic batch has a failed item nothing is committed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchOutcome {
+    pub committed: bool,
+    pub results: Vec<Option<ErrorBody>>,
+}
+
+// A request the consumer gave up on, with the message as it was received so it can be
+// replayed to the request topic.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub payload: String,
+    pub key: Option<String>,
+    pub correlation_id: Option<String>,
+    pub error: ErrorBody,
+    pub attempts: usize,
+    pub failed_at: String,
+}
+
+// A webhook reply which could not be delivered, kept so the caller can be told about
+// it or the reply sent again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeliveryFailure {
+    pub url: String,
+    pub request_id: String,
+    pub payload: String,
+    pub error: String,
+    pub attempts: usize,
+    pub failed_at: String,
+}
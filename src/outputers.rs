@@ -1,44 +1,512 @@
-use std::ops::Deref;
-
-use crate::r2d2::RedisManager;
-use crate::Outputer;
-use r2d2::Pool;
-use redis::{Commands, ToRedisArgs};
+use crate::core::{BlockingOutputer, BoxFuture, DeliveryFailureLog, Outputer};
+use crate::error::Error;
+use crate::models::DeliveryFailure;
+use crate::protocol::{Channel, Meta, ReplyTo, Response, CORRELATION_HEADER, REQUEST_ID_HEADER};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::warn;
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use redis::Client;
+use reqwest::{header::CONTENT_TYPE, StatusCode, Url};
 use serde::Serialize;
-pub struct RedisOutput(Pool<RedisManager>);
+use sha2::Sha256;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+
+pub const DEFAULT_REPLY_TTL: Duration = Duration::from_secs(60);
+pub const DEFAULT_WEBHOOK_RETRIES: usize = 3;
+
+fn encode<T: Serialize>(response: &Response<T>) -> Result<String, Error> {
+    Ok(serde_json::to_string(response)?)
+}
+
+// Replies expire after `reply_ttl`, so the ones of clients which gave up waiting do not
+// stay in Redis.
+pub struct RedisOutput {
+    client: Client,
+    reply_ttl: Duration,
+}
 
 impl RedisOutput {
-    pub fn new(uri: &str) -> Result<Self, anyhow::Error> {
-        Ok(Self(Pool::new(RedisManager::new(uri)?)?))
+    pub fn new(uri: &str) -> Result<Self, Error> {
+        Ok(Self {
+            client: Client::open(uri)?,
+            reply_ttl: DEFAULT_REPLY_TTL,
+        })
+    }
+
+    pub fn reply_ttl(mut self, reply_ttl: Duration) -> Self {
+        self.reply_ttl = reply_ttl;
+        self
+    }
+
+    fn push(&self, to: ReplyTo, reply: Result<String, Error>) -> BoxFuture<()> {
+        let client = self.client.clone();
+        let ttl = self.reply_ttl.as_secs().max(1) as usize;
+        Box::pin(async move {
+            let key = to.address;
+            let mut conn = client.get_async_connection().await?;
+            redis::pipe().atomic().rpush(&key, reply?).ignore().expire(&key, ttl).ignore().query_async::<_, ()>(&mut conn).await?;
+            Ok(())
+        })
     }
 }
 
-impl Deref for RedisOutput {
-    type Target = Pool<RedisManager>;
-    fn deref(&self) -> &Self::Target {
-        &self.0
+impl Outputer for RedisOutput {
+    fn reply<T: Serialize>(&self, to: ReplyTo, response: Response<T>) -> BoxFuture<()> {
+        self.push(to, encode(&response))
     }
 }
 
-#[derive(Debug, Serialize)]
-pub enum Response<T: Serialize> {
-    Ok { data: T },
-    Err { detail: String },
+// Publishes replies to the topic named by the request, keyed by its request ID which is
+// also set as the correlation header.
+pub struct KafkaOutput {
+    producer: FutureProducer,
 }
 
-impl<K: ToRedisArgs, E: std::fmt::Display> Outputer<K, E, anyhow::Error> for RedisOutput {
-    fn error(&self, key: K, err: E) -> Result<(), anyhow::Error> {
-        let res = serde_json::to_string(&Response::<()>::Err {
-            detail: format!("{}", err),
+impl KafkaOutput {
+    pub fn new(producer: FutureProducer) -> Self {
+        Self { producer }
+    }
+
+    fn push(&self, to: ReplyTo, reply: Result<String, Error>) -> BoxFuture<()> {
+        let producer = self.producer.clone();
+        Box::pin(async move {
+            let reply = reply?;
+            let record = FutureRecord::to(&to.address)
+                .key(&to.request_id)
+                .payload(&reply)
+                .headers(OwnedHeaders::new().add(CORRELATION_HEADER, &to.request_id));
+            producer.send(record, Duration::from_secs(10)).await.map_err(|(e, _)| e)?;
+            Ok(())
         })
-        .unwrap();
-        self.get()?.rpush(key, res)?;
-        Ok(())
+    }
+}
+
+impl Outputer for KafkaOutput {
+    fn reply<T: Serialize>(&self, to: ReplyTo, response: Response<T>) -> BoxFuture<()> {
+        self.push(to, encode(&response))
+    }
+}
+
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+
+// Hex encoded HMAC-SHA256 of `{timestamp}.{body}`, sent as `sha256=<signature>` along with
+// the timestamp so receivers can reject replayed callbacks.
+pub fn sign(secret: &[u8], timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Worth another attempt: the receiver is overloaded or failing, as opposed to rejecting
+// the callback.
+fn retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+// Posts replies to the URL named by the request. Failed deliveries are retried with
+// exponential backoff; callbacks which still fail are recorded to `failures` and count as
+// written, so an unreachable receiver does not hold back the request topic.
+pub struct WebhookOutput<F> {
+    http: reqwest::Client,
+    secret: Arc<Vec<u8>>,
+    failures: Arc<F>,
+    retries: usize,
+    backoff: Duration,
+}
+
+impl<F: DeliveryFailureLog + 'static> WebhookOutput<F> {
+    pub fn new(secret: Vec<u8>, failures: F) -> Result<Self, Error> {
+        Ok(Self {
+            http: reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?,
+            secret: Arc::new(secret),
+            failures: Arc::new(failures),
+            retries: DEFAULT_WEBHOOK_RETRIES,
+            backoff: Duration::from_millis(100),
+        })
+    }
+
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    // Delay before the first retry, doubled for every following one.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    fn push(&self, to: ReplyTo, reply: Result<String, Error>) -> BoxFuture<()> {
+        let http = self.http.clone();
+        let secret = self.secret.clone();
+        let failures = self.failures.clone();
+        let (retries, mut backoff) = (self.retries, self.backoff);
+        Box::pin(async move {
+            let body = reply?;
+            let mut attempts = 0;
+            let error = match Url::parse(&to.address) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => loop {
+                    attempts += 1;
+                    let timestamp = Utc::now().timestamp();
+                    let res = http
+                        .post(url.clone())
+                        .header(CONTENT_TYPE, "application/json")
+                        .header(REQUEST_ID_HEADER, &to.request_id)
+                        .header(TIMESTAMP_HEADER, timestamp)
+                        .header(SIGNATURE_HEADER, format!("sha256={}", sign(&secret, timestamp, &body)))
+                        .body(body.clone())
+                        .send()
+                        .await;
+                    let (error, retry) = match res {
+                        Ok(res) if res.status().is_success() => return Ok(()),
+                        Ok(res) => (format!("callback answered {}", res.status()), retryable(res.status())),
+                        Err(e) => (format!("{}", e), true),
+                    };
+                    if !retry || attempts > retries {
+                        break error;
+                    }
+                    warn!("attempt {} to deliver reply {} failed, retrying in {:?}: {}", attempts, to.request_id, backoff, error);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                },
+                _ => format!("invalid callback url {}", to.address),
+            };
+            warn!("failed to deliver reply {} to {}: {}", to.request_id, to.address, error);
+            failures
+                .record(DeliveryFailure {
+                    url: to.address,
+                    request_id: to.request_id,
+                    payload: body,
+                    error,
+                    attempts,
+                    failed_at: Utc::now().to_rfc3339(),
+                })
+                .await
+        })
+    }
+}
+
+impl<F: DeliveryFailureLog + 'static> Outputer for WebhookOutput<F> {
+    fn reply<T: Serialize>(&self, to: ReplyTo, response: Response<T>) -> BoxFuture<()> {
+        self.push(to, encode(&response))
+    }
+}
+
+// Writes every reply through the outputer of the channel its request asked for.
+pub struct Router<R, K, W> {
+    redis: R,
+    kafka: K,
+    webhook: W,
+}
+
+impl<R: Outputer, K: Outputer, W: Outputer> Router<R, K, W> {
+    pub fn new(redis: R, kafka: K, webhook: W) -> Self {
+        Self { redis, kafka, webhook }
+    }
+}
+
+impl<R: Outputer, K: Outputer, W: Outputer> Outputer for Router<R, K, W> {
+    fn reply<T: Serialize>(&self, to: ReplyTo, response: Response<T>) -> BoxFuture<()> {
+        match to.channel {
+            Channel::Redis => self.redis.reply(to, response),
+            Channel::Kafka => self.kafka.reply(to, response),
+            Channel::Webhook => self.webhook.reply(to, response),
+        }
+    }
+}
+
+// Drives an `Outputer` to completion on a runtime of its own, for callers outside of
+// any async runtime. Calling it from within one panics, as it blocks the thread. The
+// service itself replies asynchronously, so only synchronous callers construct it.
+pub struct Blocking<O> {
+    inner: O,
+    runtime: Runtime,
+}
+
+impl<O: Outputer> Blocking<O> {
+    pub fn new(inner: O) -> Result<Self, Error> {
+        Ok(Self {
+            inner,
+            runtime: Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|e| Error::internal(format!("failed to start runtime: {}", e)))?,
+        })
+    }
+
+    pub fn into_inner(self) -> O {
+        self.inner
+    }
+}
+
+// Blocking callers do not say when they started serving the request, so their replies
+// carry no timing.
+fn meta(to: &ReplyTo) -> Meta {
+    Meta {
+        request_id: Some(to.request_id.clone()),
+        served_at: Some(Utc::now().to_rfc3339()),
+        elapsed_ms: None,
+    }
+}
+
+impl<O: Outputer, E: Display> BlockingOutputer<ReplyTo, E, Error> for Blocking<O> {
+    fn ok<T: Serialize>(&self, to: ReplyTo, data: T) -> Result<(), Error> {
+        let meta = meta(&to);
+        self.runtime.block_on(self.inner.reply(to, Response::new(Ok(data), meta)))
+    }
+
+    fn error(&self, to: ReplyTo, err: E) -> Result<(), Error> {
+        let meta = meta(&to);
+        self.runtime.block_on(self.inner.reply(to, Response::<()>::new(Err(Error::internal(err.to_string())), meta)))
+    }
+}
+
+// In-memory implementation of `Outputer` for tests, failing the given number of writes
+// before keeping the replies by address.
+#[cfg(test)]
+#[derive(Default, Clone)]
+pub struct Memory {
+    replies: std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>,
+    failures: std::sync::Arc<std::sync::Mutex<usize>>,
+}
+
+#[cfg(test)]
+impl Memory {
+    pub fn responses(&self) -> Vec<(String, Response<serde_json::Value>)> {
+        let replies = self.replies.lock().unwrap();
+        replies.iter().map(|(address, reply)| (address.clone(), serde_json::from_str(reply).unwrap())).collect()
+    }
+
+    // Replies without their `Meta`, which differs from run to run.
+    pub fn replies(&self) -> Vec<(String, String)> {
+        self.responses()
+            .into_iter()
+            .map(|(address, response)| {
+                let bare = Response::new(response.into_result(), Meta::default());
+                (address, serde_json::to_string(&bare).unwrap())
+            })
+            .collect()
+    }
+
+    pub fn fail(&self, times: usize) {
+        *self.failures.lock().unwrap() = times;
+    }
+
+    fn push(&self, to: ReplyTo, reply: Result<String, Error>) -> BoxFuture<()> {
+        let mut failures = self.failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return Box::pin(async { Err(Error::unavailable("connection refused".into())) });
+        }
+        let res = reply.map(|reply| self.replies.lock().unwrap().push((to.address, reply)));
+        Box::pin(async move { res })
+    }
+}
+
+#[cfg(test)]
+impl Outputer for Memory {
+    fn reply<T: Serialize>(&self, to: ReplyTo, response: Response<T>) -> BoxFuture<()> {
+        self.push(to, encode(&response))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use redis::AsyncCommands;
+    use std::sync::Mutex;
+
+    fn ok<T>(data: T) -> Response<T> {
+        Response::new(Ok(data), Meta::default())
+    }
+
+    fn err(e: Error) -> Response<()> {
+        Response::new(Err(e), Meta::default())
+    }
+
+    fn to(channel: Channel, address: &str) -> ReplyTo {
+        ReplyTo {
+            channel,
+            address: address.into(),
+            request_id: "0f6e".into(),
+        }
+    }
+
+    #[test]
+    fn test_blocking() {
+        let outputer = Blocking::new(Memory::default()).unwrap();
+        BlockingOutputer::<_, &str, _>::ok(&outputer, to(Channel::Redis, "reply:a"), vec![1, 2]).unwrap();
+        outputer.error(to(Channel::Redis, "reply:b"), "user 3 not found").unwrap();
+        let memory = outputer.into_inner();
+        assert_eq!(
+            memory.replies(),
+            vec![
+                ("reply:a".into(), r#"{"Ok":{"data":[1,2]}}"#.into()),
+                ("reply:b".into(), r#"{"Err":{"code":"INTERNAL","detail":"user 3 not found"}}"#.into())
+            ]
+        );
+        memory.fail(1);
+        let outputer = Blocking::new(memory).unwrap();
+        assert!(BlockingOutputer::<_, &str, _>::ok(&outputer, to(Channel::Redis, "reply:c"), ()).is_err());
+        assert_eq!(outputer.into_inner().replies().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_router() {
+        let router = Router::new(Memory::default(), Memory::default(), Memory::default());
+        router.reply(to(Channel::Redis, "reply:0f6e"), ok(1)).await.unwrap();
+        router.reply(to(Channel::Kafka, "replies"), err(Error::not_found("user 1 not found".into()))).await.unwrap();
+        router.reply(to(Channel::Webhook, "http://feed/replies"), ok(2)).await.unwrap();
+        assert_eq!(router.redis.replies(), vec![("reply:0f6e".into(), r#"{"Ok":{"data":1}}"#.into())]);
+        assert_eq!(router.kafka.replies(), vec![("replies".into(), r#"{"Err":{"code":"NOT_FOUND","detail":"user 1 not found"}}"#.into())]);
+        assert_eq!(router.webhook.replies(), vec![("http://feed/replies".into(), r#"{"Ok":{"data":2}}"#.into())]);
+    }
+
+    #[derive(Default, Clone)]
+    struct Failures(Arc<Mutex<Vec<DeliveryFailure>>>);
+
+    impl DeliveryFailureLog for Failures {
+        fn record(&self, failure: DeliveryFailure) -> BoxFuture<()> {
+            self.0.lock().unwrap().push(failure);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct Callback {
+        request_id: String,
+        timestamp: String,
+        signature: String,
+        body: String,
+    }
+
+    // Stand-in for a callback receiver, answering with the queued statuses in turn and
+    // 200 once they are used up.
+    #[derive(Default, Clone)]
+    struct Receiver {
+        statuses: Arc<Mutex<Vec<u16>>>,
+        callbacks: Arc<Mutex<Vec<Callback>>>,
+    }
+
+    impl Receiver {
+        fn answer(&self, statuses: Vec<u16>) {
+            *self.statuses.lock().unwrap() = statuses;
+        }
+
+        fn callbacks(&self) -> Vec<Callback> {
+            self.callbacks.lock().unwrap().clone()
+        }
+    }
+
+    async fn callback(req: HttpRequest, body: String, receiver: web::Data<Receiver>) -> HttpResponse {
+        let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok()).unwrap_or_default().to_owned();
+        receiver.callbacks.lock().unwrap().push(Callback {
+            request_id: header(REQUEST_ID_HEADER),
+            timestamp: header(TIMESTAMP_HEADER),
+            signature: header(SIGNATURE_HEADER),
+            body,
+        });
+        let mut statuses = receiver.statuses.lock().unwrap();
+        let status = if statuses.is_empty() { 200 } else { statuses.remove(0) };
+        HttpResponse::new(actix_web::http::StatusCode::from_u16(status).unwrap())
+    }
+
+    fn receiver() -> (String, Receiver) {
+        let receiver = Receiver::default();
+        let data = receiver.clone();
+        let server = HttpServer::new(move || App::new().app_data(web::Data::new(data.clone())).route("/replies", web::post().to(callback)))
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .expect("failed to bind test server");
+        let url = format!("http://{}/replies", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        (url, receiver)
+    }
+
+    fn webhook(retries: usize) -> (WebhookOutput<Failures>, Failures) {
+        let failures = Failures::default();
+        let outputer = WebhookOutput::new(b"s3cret".to_vec(), failures.clone()).unwrap().retries(retries).backoff(Duration::from_millis(1));
+        (outputer, failures)
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign(b"key", 1_700_000_000, r#"{"Ok":{"data":null}}"#),
+            "64fbd4b7570581ab17762b83b5fc18995d69517d776b20edbee4d493101c0648"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_webhook() {
+        let (url, receiver) = receiver();
+        let (outputer, failures) = webhook(3);
+        outputer.reply(to(Channel::Webhook, &url), ok(vec![1])).await.unwrap();
+        outputer.reply(to(Channel::Webhook, &url), err(Error::not_found("user 1 not found".into()))).await.unwrap();
+        let callbacks = receiver.callbacks();
+        assert_eq!(callbacks.len(), 2);
+        assert_eq!(callbacks[0].body, r#"{"Ok":{"data":[1]}}"#);
+        assert_eq!(callbacks[1].body, r#"{"Err":{"code":"NOT_FOUND","detail":"user 1 not found"}}"#);
+        for callback in callbacks {
+            assert_eq!(callback.request_id, "0f6e");
+            assert_eq!(callback.signature, format!("sha256={}", sign(b"s3cret", callback.timestamp.parse().unwrap(), &callback.body)));
+        }
+        assert!(failures.0.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_webhook_retry() {
+        let (url, receiver) = receiver();
+        let (outputer, failures) = webhook(3);
+        receiver.answer(vec![503, 429]);
+        outputer.reply(to(Channel::Webhook, &url), ok(1)).await.unwrap();
+        assert_eq!(receiver.callbacks().len(), 3);
+        assert!(failures.0.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_webhook_failure() {
+        let (url, receiver) = receiver();
+        let (outputer, failures) = webhook(2);
+        receiver.answer(vec![500; 5]);
+        outputer.reply(to(Channel::Webhook, &url), ok(1)).await.unwrap();
+        assert_eq!(receiver.callbacks().len(), 3);
+        receiver.answer(vec![400]);
+        outputer.reply(to(Channel::Webhook, &url), ok(1)).await.unwrap();
+        assert_eq!(receiver.callbacks().len(), 4);
+        outputer.reply(to(Channel::Webhook, "ftp://feed/replies"), ok(1)).await.unwrap();
+
+        let failures = failures.0.lock().unwrap().clone();
+        let summary = failures.iter().map(|failure| (failure.url.as_str(), failure.error.as_str(), failure.attempts)).collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (url.as_str(), "callback answered 500 Internal Server Error", 3),
+                (url.as_str(), "callback answered 400 Bad Request", 1),
+                ("ftp://feed/replies", "invalid callback url ftp://feed/replies", 0)
+            ]
+        );
+        assert_eq!((failures[0].request_id.as_str(), failures[0].payload.as_str()), ("0f6e", r#"{"Ok":{"data":1}}"#));
     }
 
-    fn ok<T: Serialize>(&self, key: K, data: T) -> Result<(), anyhow::Error> {
-        let res = serde_json::to_string(&Response::<T>::Ok { data: data }).unwrap();
-        self.get()?.rpush(key, res)?;
-        Ok(())
+    #[tokio::test]
+    async fn test_redis_output() {
+        let outputer = RedisOutput::new("redis://localhost").unwrap().reply_ttl(Duration::from_secs(5));
+        let key = format!("reply:{}", uuid::Uuid::new_v4());
+        outputer.reply(to(Channel::Redis, &key), ok(vec![1])).await.unwrap();
+        outputer.reply(to(Channel::Redis, &key), err(Error::not_found("user 1 not found".into()))).await.unwrap();
+        let mut conn = Client::open("redis://localhost").unwrap().get_async_connection().await.unwrap();
+        let replies: Vec<String> = conn.lrange(&key, 0, -1).await.unwrap();
+        assert_eq!(replies, vec![r#"{"Ok":{"data":[1]}}"#, r#"{"Err":{"code":"NOT_FOUND","detail":"user 1 not found"}}"#]);
+        let ttl: i64 = conn.ttl(&key).await.unwrap();
+        assert!(ttl > 0 && ttl <= 5);
+        conn.del::<_, ()>(&key).await.unwrap();
     }
 }
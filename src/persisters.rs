@@ -1,97 +1,389 @@
-use crate::core::Persister;
+use crate::core::{BoxFuture, Outbox, Persister};
 use crate::error::Error;
-use neo4rs::{query, Graph};
-use std::future::Future;
-use std::pin::Pin;
+use crate::events::{Event, OutboxEvent};
+use crate::models::{BatchOp, BatchOutcome, Friendship, FriendshipPair, InsertOutcome, UserExport};
+use chrono::{DateTime, FixedOffset, Utc};
+use neo4rs::{query, Graph, Query, RowStream, Txn};
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
 
 pub struct Neo {
     graph: Arc<Graph>,
 }
 
+// neo4rs can not bind list parameters, so batches are sent as a single string joined
+// by these ASCII separators and split back into a list inside the query.
+const RECORD_SEPARATOR: &str = "\u{1e}";
+const UNIT_SEPARATOR: &str = "\u{1f}";
+
+fn check_separators(uid: &str) -> Result<(), Error> {
+    if uid.contains(RECORD_SEPARATOR) || uid.contains(UNIT_SEPARATOR) {
+        return Err(Error::invalid_input(format!("uid {:?} contains reserved separator characters", uid)));
+    }
+    Ok(())
+}
+
+fn check_not_self(uid_a: &str, uid_b: &str) -> Result<(), Error> {
+    if uid_a == uid_b {
+        return Err(Error::invalid_input(format!("user {} can not be friend of itself", uid_a)));
+    }
+    Ok(())
+}
+
+// Why `insert_many` refuses a pair, checked in the order `insert` checks it.
+fn refusal(uid_a: &str, uid_b: &str, a_exists: bool, b_exists: bool, deactivated: bool) -> Option<Error> {
+    if let Err(e) = check_not_self(uid_a, uid_b) {
+        Some(e)
+    } else if !a_exists {
+        Some(Error::not_found(format!("user {} not found", uid_a)))
+    } else if !b_exists {
+        Some(Error::not_found(format!("user {} not found", uid_b)))
+    } else if deactivated {
+        Some(Error::conflict(format!("user {} or user {} is deactivated", uid_a, uid_b)))
+    } else {
+        None
+    }
+}
+
+fn insert_query(uid_a: &str, uid_b: &str) -> Query {
+    query(
+        "OPTIONAL MATCH (a:Person{ uid: $uid_a })
+        OPTIONAL MATCH (b:Person{ uid: $uid_b })
+        OPTIONAL MATCH (a) -[r:BE_FRIEND_OF]- (b)
+        WITH a, b, count(r) > 0 AS is_friend, coalesce(a.deactivated, false) OR coalesce(b.deactivated, false) AS deactivated
+        FOREACH (_ IN CASE WHEN a IS NOT NULL AND b IS NOT NULL AND NOT is_friend AND NOT deactivated THEN [1] ELSE [] END | CREATE (a) -[:BE_FRIEND_OF{ created_at: datetime() }]-> (b))
+        RETURN a IS NOT NULL AS a_exists, b IS NOT NULL AS b_exists, is_friend, deactivated",
+    )
+    .param("uid_a", uid_a)
+    .param("uid_b", uid_b)
+}
+
+async fn insert_result(mut rows: RowStream, uid_a: &str, uid_b: &str) -> Result<(), Error> {
+    let row = rows.next().await?.ok_or_else(|| Error::internal("no result returned from neo4j".into()))?;
+    if !row.get::<bool>("a_exists").unwrap_or_default() {
+        return Err(Error::not_found(format!("user {} not found", uid_a)));
+    }
+    if !row.get::<bool>("b_exists").unwrap_or_default() {
+        return Err(Error::not_found(format!("user {} not found", uid_b)));
+    }
+    if row.get::<bool>("is_friend").unwrap_or_default() {
+        return Err(Error::already_exists(format!("user {} and user {} are already friends", uid_a, uid_b)));
+    }
+    if row.get::<bool>("deactivated").unwrap_or_default() {
+        return Err(Error::conflict(format!("user {} or user {} is deactivated", uid_a, uid_b)));
+    }
+    Ok(())
+}
+
+fn delete_query(uid_a: &str, uid_b: &str) -> Query {
+    query("MATCH (:Person{uid: $uid_a}) -[r:BE_FRIEND_OF]- (:Person{uid: $uid_b}) DELETE r RETURN count(*) AS deleted")
+        .param("uid_a", uid_a)
+        .param("uid_b", uid_b)
+}
+
+async fn delete_result(mut rows: RowStream, uid_a: &str, uid_b: &str) -> Result<(), Error> {
+    match rows.next().await?.and_then(|row| row.get::<i64>("deleted")) {
+        Some(deleted) if deleted > 0 => Ok(()),
+        _ => Err(Error::not_found(format!("user {} and user {} are not friends", uid_a, uid_b))),
+    }
+}
+
+// Events are stored as `OutboxEvent` nodes next to the graph they describe, numbered by
+// `seq` from the `OutboxSequence` node. Its write lock is held until the transaction
+// ends, so events are numbered in the order their transactions commit.
+async fn record(txn: &Txn, events: Vec<Event<String>>) -> Result<(), Error> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let mut rows = txn
+        .execute(query("MERGE (s:OutboxSequence{ name: 'outbox' }) ON CREATE SET s.last = 0 SET s.last = s.last + $count RETURN s.last AS last").param("count", events.len() as i64))
+        .await?;
+    let last = rows
+        .next()
+        .await?
+        .and_then(|row| row.get::<i64>("last"))
+        .ok_or_else(|| Error::internal("failed to number outbox events".into()))?;
+    let first = last - events.len() as i64 + 1;
+    for (seq, event) in (first..).zip(events) {
+        txn.run(
+            query("CREATE (:OutboxEvent{ id: $id, uid: $uid, payload: $payload, created_at: timestamp(), seq: $seq })")
+                .param("id", Uuid::new_v4().to_string())
+                .param("uid", event.uid().clone())
+                .param("payload", serde_json::to_string(&event)?)
+                .param("seq", seq),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+// Commits a mutation together with its events, or rolls it back when it failed, so an
+// event is in the outbox exactly when its mutation is in the graph.
+async fn finish<T>(txn: Txn, res: Result<(T, Vec<Event<String>>), Error>) -> Result<T, Error> {
+    let res = match res {
+        Ok((value, events)) => record(&txn, events).await.map(|()| value),
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(value) => {
+            txn.commit().await?;
+            Ok(value)
+        }
+        Err(e) => {
+            txn.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+fn deactivation_event(uid: String, friends: Vec<String>, deactivated: bool) -> Event<String> {
+    if deactivated {
+        Event::UserDeactivated { uid, friends }
+    } else {
+        Event::UserReactivated { uid, friends }
+    }
+}
+
 impl Neo {
     pub fn new(graph: Arc<Graph>) -> Self {
         Self { graph }
     }
+
+    // Creates the constraints and indexes the outbox relies on, if they are missing.
+    pub async fn create_schema(&self) -> Result<(), Error> {
+        for statement in [
+            "CREATE CONSTRAINT outbox_sequence_name IF NOT EXISTS FOR (s:OutboxSequence) REQUIRE s.name IS UNIQUE",
+            "CREATE CONSTRAINT outbox_lock_name IF NOT EXISTS FOR (l:OutboxLock) REQUIRE l.name IS UNIQUE",
+            "CREATE INDEX outbox_event_id IF NOT EXISTS FOR (e:OutboxEvent) ON (e.id)",
+            "CREATE INDEX outbox_event_seq IF NOT EXISTS FOR (e:OutboxEvent) ON (e.seq)",
+            "CREATE INDEX outbox_event_delivered_at IF NOT EXISTS FOR (e:OutboxEvent) ON (e.delivered_at)",
+        ] {
+            self.graph.run(query(statement)).await?;
+        }
+        Ok(())
+    }
+
+    fn list_friends(&self, uid: String, with_deactivated: bool) -> BoxFuture<Vec<String>> {
+        let graph = self.graph.clone();
+        Box::pin(async move {
+            let filter = if with_deactivated { "" } else { "WHERE NOT coalesce(b.deactivated, false)" };
+            let mut rows = graph
+                .execute(
+                    query(&format!(
+                        "MATCH (a:Person {{ uid: $uid }}) OPTIONAL MATCH (a) -[:BE_FRIEND_OF]- (b:Person) {} RETURN b.uid AS uid ORDER BY uid",
+                        filter
+                    ))
+                    .param("uid", uid.clone()),
+                )
+                .await?;
+            let mut found = false;
+            let mut res = Vec::new();
+            while let Some(r) = rows.next().await? {
+                found = true;
+                if let Some(uid) = r.get("uid") {
+                    res.push(uid);
+                }
+            }
+            if !found {
+                return Err(Error::not_found(format!("user {} not found", uid)));
+            }
+            Ok(res)
+        })
+    }
+
+    fn set_deactivated(&self, uid: String, deactivated: bool) -> BoxFuture<Vec<String>> {
+        let graph = self.graph.clone();
+        Box::pin(async move {
+            let txn = graph.start_txn().await?;
+            let res = async {
+                let mut rows = txn
+                    .execute(
+                        query(&format!(
+                            "MATCH (p:Person{{ uid: $uid }})
+                            WITH p, coalesce(p.deactivated, false) = {0} AS unchanged
+                            SET p.deactivated = {0}
+                            WITH p, unchanged
+                            OPTIONAL MATCH (p) -[:BE_FRIEND_OF]- (f:Person)
+                            RETURN unchanged, f.uid AS friend",
+                            deactivated
+                        ))
+                        .param("uid", uid.clone()),
+                    )
+                    .await?;
+                let mut friends = Vec::new();
+                let mut unchanged = None;
+                while let Some(row) = rows.next().await? {
+                    unchanged = row.get::<bool>("unchanged");
+                    if let Some(friend) = row.get("friend") {
+                        friends.push(friend);
+                    }
+                }
+                match unchanged {
+                    None => Err(Error::not_found(format!("user {} not found", uid))),
+                    Some(true) if deactivated => Err(Error::conflict(format!("user {} is already deactivated", uid))),
+                    Some(true) => Err(Error::conflict(format!("user {} is not deactivated", uid))),
+                    Some(false) => {
+                        let event = deactivation_event(uid.clone(), friends.clone(), deactivated);
+                        Ok((friends, vec![event]))
+                    }
+                }
+            }
+            .await;
+            finish(txn, res).await
+        })
+    }
 }
 
 impl Persister for Neo {
     type UID = String;
-    fn insert_node(&self, uid: Self::UID) -> Pin<Box<dyn Future<Output = Result<(), Error>>>> {
+    fn insert_node(&self, uid: Self::UID) -> BoxFuture<()> {
         let graph = self.graph.clone();
         Box::pin(async move {
-            graph
-                .run(query("CREATE (:Person{ uid: $uid })").param("uid", uid))
-                .await
-                .map_err(|e| Error::new_500(format!("{:?}", e)))
+            let txn = graph.start_txn().await?;
+            let res = async {
+                let mut rows = txn
+                    .execute(
+                        query(
+                            "OPTIONAL MATCH (e:Person{ uid: $uid })
+                            WITH e WHERE e IS NULL
+                            CREATE (:Person{ uid: $uid })
+                            RETURN count(*) AS created",
+                        )
+                        .param("uid", uid.clone()),
+                    )
+                    .await?;
+                match rows.next().await?.and_then(|row| row.get::<i64>("created")) {
+                    Some(created) if created > 0 => Ok(((), vec![Event::UserCreated { uid: uid.clone() }])),
+                    _ => Err(Error::already_exists(format!("user {} already exists", uid))),
+                }
+            }
+            .await;
+            finish(txn, res).await
         })
     }
 
-    fn delete_node(&self, uid: Self::UID) -> Pin<Box<dyn Future<Output = Result<(), Error>>>> {
+    fn delete_node(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>> {
         let graph = self.graph.clone();
         Box::pin(async move {
-            graph
-                .run(query("MATCH (p: Person{ uid: $uid }) DELETE p").param("uid", uid))
-                .await
-                .map_err(|e| Error::new_500(format!("{:?}", e)))
+            let txn = graph.start_txn().await?;
+            let res = async {
+                let mut rows = txn
+                    .execute(
+                        query(
+                            "MATCH (p: Person{ uid: $uid })
+                            OPTIONAL MATCH (p) -[:BE_FRIEND_OF]- (f:Person)
+                            WITH p, collect(f.uid) AS friends
+                            DETACH DELETE p
+                            WITH friends UNWIND CASE WHEN size(friends) = 0 THEN [null] ELSE friends END AS friend
+                            RETURN friend",
+                        )
+                        .param("uid", uid.clone()),
+                    )
+                    .await?;
+                let mut found = false;
+                let mut friends = Vec::new();
+                while let Some(row) = rows.next().await? {
+                    found = true;
+                    if let Some(friend) = row.get("friend") {
+                        friends.push(friend);
+                    }
+                }
+                if !found {
+                    return Err(Error::not_found(format!("user {} not found", uid)));
+                }
+                let event = Event::UserDeleted {
+                    uid: uid.clone(),
+                    friends: friends.clone(),
+                };
+                Ok((friends, vec![event]))
+            }
+            .await;
+            finish(txn, res).await
         })
     }
-    fn exist_node(&self, uid: Self::UID) -> Pin<Box<dyn Future<Output = Result<bool, Error>>>> {
+    fn exist_node(&self, uid: Self::UID) -> BoxFuture<bool> {
         let graph = self.graph.clone();
         Box::pin(async move {
             let mut rows = graph
                 .execute(query("MATCH (p: Person{ uid: $uid } ) WITH count(p) > 0 AS node_exists RETURN node_exists").param("uid", uid))
-                .await
-                .map_err(|e| Error::new_500(format!("{:?}", e)))?;
-            if let Some(row) = rows.next().await.map_err(|e| Error::new_500(format!("{:?}", e)))? {
+                .await?;
+            if let Some(row) = rows.next().await? {
                 return Ok(row.get("node_exists").unwrap());
             }
             unreachable!()
         })
     }
-    fn delete(&self, uid_a: Self::UID, uid_b: Self::UID) -> Pin<Box<dyn Future<Output = Result<(), Error>>>> {
+    fn delete(&self, uid_a: Self::UID, uid_b: Self::UID) -> BoxFuture<()> {
         let graph = self.graph.clone();
         Box::pin(async move {
-            graph
-                .run(
-                    query("MATCH (:Person{uid: $uid_a}) -[r:BE_FRIEND_OF]- (:Person{uid: $uid_b}) DELETE r")
-                        .param("uid_a", uid_a)
-                        .param("uid_b", uid_b),
-                )
-                .await
-                .map_err(|e| Error::new_500(format!("{:?}", e)))
+            let txn = graph.start_txn().await?;
+            let res = match txn.execute(delete_query(&uid_a, &uid_b)).await {
+                Ok(rows) => delete_result(rows, &uid_a, &uid_b).await,
+                Err(e) => Err(e.into()),
+            };
+            finish(txn, res.map(|()| ((), vec![Event::FriendRemoved { uid: uid_a, friend: uid_b }]))).await
         })
     }
 
-    fn friends(&self, uid: Self::UID) -> Pin<Box<dyn Future<Output = Result<Vec<Self::UID>, Error>>>> {
+    fn friends(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>> {
+        self.list_friends(uid, false)
+    }
+
+    fn all_friends(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>> {
+        self.list_friends(uid, true)
+    }
+
+    fn friends_many(&self, uids: Vec<Self::UID>) -> BoxFuture<BTreeMap<Self::UID, Vec<Self::UID>>> {
         let graph = self.graph.clone();
         Box::pin(async move {
+            let mut res = BTreeMap::new();
+            if uids.is_empty() {
+                return Ok(res);
+            }
+            for uid in &uids {
+                check_separators(uid)?;
+            }
             let mut rows = graph
-                .execute(query("MATCH (:Person { uid: $uid }) -[:BE_FRIEND_OF]- (b:Person) RETURN b.uid AS uid ORDER BY uid").param("uid", uid))
-                .await
-                .map_err(|e| Error::new_500(format!("{:?}", e)))?;
-            let mut res = Vec::new();
-            while let Some(r) = rows.next().await.map_err(|e| Error::new_500(format!("{:?}", e)))? {
-                if let Some(uid) = r.get("uid") {
-                    res.push(uid);
+                .execute(
+                    query(
+                        "UNWIND split($batch, $rs) AS uid
+                        MATCH (a:Person{ uid: uid })
+                        OPTIONAL MATCH (a) -[:BE_FRIEND_OF]- (b:Person) WHERE NOT coalesce(b.deactivated, false)
+                        RETURN uid, b.uid AS friend ORDER BY uid, friend",
+                    )
+                    .param("batch", uids.join(RECORD_SEPARATOR))
+                    .param("rs", RECORD_SEPARATOR),
+                )
+                .await?;
+            while let Some(row) = rows.next().await? {
+                if let Some(uid) = row.get::<String>("uid") {
+                    let friends: &mut Vec<String> = res.entry(uid).or_default();
+                    if let Some(friend) = row.get("friend") {
+                        friends.push(friend);
+                    }
                 }
             }
             Ok(res)
         })
     }
 
-    fn is_friend(&self, uid_a: Self::UID, uid_b: Self::UID) -> Pin<Box<dyn Future<Output = Result<bool, Error>>>> {
+    fn is_friend(&self, uid_a: Self::UID, uid_b: Self::UID) -> BoxFuture<bool> {
         let graph = self.graph.clone();
         Box::pin(async move {
             let mut rows = graph
                 .execute(
-                    query("MATCH (: Person { uid: $uid_a }) -[r: BE_FRIEND_OF]- (: Person { uid: $uid_b }) WITH count(r) > 0 AS is_friend RETURN is_friend")
-                        .param("uid_a", uid_a)
-                        .param("uid_b", uid_b),
+                    query(
+                        "OPTIONAL MATCH (a: Person { uid: $uid_a }) -[r: BE_FRIEND_OF]- (b: Person { uid: $uid_b })
+                        WHERE NOT coalesce(a.deactivated, false) AND NOT coalesce(b.deactivated, false)
+                        WITH count(r) > 0 AS is_friend RETURN is_friend",
+                    )
+                    .param("uid_a", uid_a)
+                    .param("uid_b", uid_b),
                 )
-                .await
-                .map_err(|e| Error::new_500(format!("{:?}", e)))?;
-            if let Some(row) = rows.next().await.map_err(|e| Error::new_500(format!("{:?}", e)))? {
+                .await?;
+            if let Some(row) = rows.next().await? {
                 if let Some(is_friend) = row.get("is_friend") {
                     return Ok(is_friend);
                 }
@@ -100,27 +392,27 @@ impl Persister for Neo {
         })
     }
 
-    fn insert(&self, uid_a: Self::UID, uid_b: Self::UID) -> Pin<Box<dyn Future<Output = Result<(), Error>>>> {
+    fn insert(&self, uid_a: Self::UID, uid_b: Self::UID) -> BoxFuture<()> {
         let graph = self.graph.clone();
         Box::pin(async move {
-            graph
-                .run(
-                    query("MATCH (a:Person{ uid: $uid_a }), (b: Person{ uid: $uid_b }) CREATE (a) -[:BE_FRIEND_OF]-> (b)")
-                        .param("uid_a", uid_a)
-                        .param("uid_b", uid_b),
-                )
-                .await
-                .map_err(|e| Error::new_500(format!("{:?}", e)))
+            check_not_self(&uid_a, &uid_b)?;
+            let txn = graph.start_txn().await?;
+            let res = match txn.execute(insert_query(&uid_a, &uid_b)).await {
+                Ok(rows) => insert_result(rows, &uid_a, &uid_b).await,
+                Err(e) => Err(e.into()),
+            };
+            finish(txn, res.map(|()| ((), vec![Event::FriendAdded { uid: uid_a, friend: uid_b }]))).await
         })
     }
 
-    fn recommendations(&self, uid: Self::UID, level: i32, threshold: i32) -> Pin<Box<dyn Future<Output = Result<Vec<Self::UID>, Error>>>> {
+    fn recommendations(&self, uid: Self::UID, level: i32, threshold: i32) -> BoxFuture<Vec<Self::UID>> {
         let graph = self.graph.clone();
         Box::pin(async move {
             let mut rows = graph
                 .execute(
                     query(&format!(
-                        "MATCH (a:Person) -[:BE_FRIEND_OF * {}]- (b:Person)
+                        "MATCH path = (a:Person) -[:BE_FRIEND_OF * {}]- (b:Person)
+                        WHERE none(n IN nodes(path) WHERE coalesce(n.deactivated, false))
                         WITH a.uid AS src_uid, b.uid AS dst_uid, count(*) AS relative
                         WHERE src_uid = $uid AND relative >= $threshold
                         RETURN dst_uid",
@@ -129,10 +421,9 @@ impl Persister for Neo {
                     .param("uid", uid)
                     .param("threshold", threshold as i64),
                 )
-                .await
-                .map_err(|e| Error::new_500(format!("{:?}", e)))?;
+                .await?;
             let mut res = Vec::new();
-            while let Some(row) = rows.next().await.map_err(|e| Error::new_500(format!("{:?}", e)))? {
+            while let Some(row) = rows.next().await? {
                 if let Some(uid) = row.get("dst_uid") {
                     res.push(uid);
                 }
@@ -140,12 +431,849 @@ impl Persister for Neo {
             Ok(res)
         })
     }
+
+    fn deactivate(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>> {
+        self.set_deactivated(uid, true)
+    }
+
+    fn reactivate(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>> {
+        self.set_deactivated(uid, false)
+    }
+
+    fn insert_nodes(&self, uids: Vec<Self::UID>) -> BoxFuture<Vec<Self::UID>> {
+        let graph = self.graph.clone();
+        Box::pin(async move {
+            // Duplicates would all see the user missing and create it twice.
+            let uids = uids.into_iter().collect::<BTreeSet<_>>();
+            if uids.is_empty() {
+                return Ok(Vec::new());
+            }
+            for uid in &uids {
+                check_separators(uid)?;
+            }
+            let batch = uids.into_iter().collect::<Vec<_>>().join(RECORD_SEPARATOR);
+            let txn = graph.start_txn().await?;
+            let res = async {
+                let mut rows = txn
+                    .execute(
+                        query(
+                            "UNWIND split($batch, $rs) AS uid
+                            OPTIONAL MATCH (e:Person{ uid: uid })
+                            WITH uid, e WHERE e IS NULL
+                            CREATE (:Person{ uid: uid })
+                            RETURN uid",
+                        )
+                        .param("batch", batch)
+                        .param("rs", RECORD_SEPARATOR),
+                    )
+                    .await?;
+                let mut created = Vec::new();
+                while let Some(row) = rows.next().await? {
+                    if let Some(uid) = row.get::<String>("uid") {
+                        created.push(uid);
+                    }
+                }
+                let events = created.iter().map(|uid| Event::UserCreated { uid: uid.clone() }).collect();
+                Ok((created, events))
+            }
+            .await;
+            finish(txn, res).await
+        })
+    }
+
+    fn insert_many(&self, pairs: Vec<(Self::UID, Self::UID, Option<String>)>) -> BoxFuture<InsertOutcome<Self::UID>> {
+        let graph = self.graph.clone();
+        Box::pin(async move {
+            if pairs.is_empty() {
+                return Ok(InsertOutcome::default());
+            }
+            // The existing friendships are matched before any is created, so only the first
+            // of the pairs given twice, in either order, may create the friendship.
+            let mut seen = BTreeSet::new();
+            let mut batch = Vec::with_capacity(pairs.len());
+            for (i, (uid_a, uid_b, created_at)) in pairs.iter().enumerate() {
+                check_separators(uid_a)?;
+                check_separators(uid_b)?;
+                let created_at = created_at.as_deref().unwrap_or_default();
+                check_separators(created_at)?;
+                let first = seen.insert(if uid_a < uid_b { (uid_a, uid_b) } else { (uid_b, uid_a) });
+                batch.push([i.to_string(), uid_a.clone(), uid_b.clone(), created_at.to_owned(), (first as u8).to_string()].join(UNIT_SEPARATOR));
+            }
+            let batch = batch.join(RECORD_SEPARATOR);
+            let txn = graph.start_txn().await?;
+            let res = async {
+                let mut rows = txn
+                    .execute(
+                        query(
+                            "UNWIND split($batch, $rs) AS pair
+                            WITH split(pair, $us) AS fields
+                            OPTIONAL MATCH (a:Person{ uid: fields[1] })
+                            OPTIONAL MATCH (b:Person{ uid: fields[2] })
+                            OPTIONAL MATCH (a) -[r:BE_FRIEND_OF]- (b)
+                            WITH fields, a, b, count(r) > 0 AS is_friend, coalesce(a.deactivated, false) OR coalesce(b.deactivated, false) AS deactivated
+                            WITH fields, a, b, is_friend, deactivated, a IS NOT NULL AND b IS NOT NULL AND fields[1] <> fields[2] AND NOT deactivated AS valid
+                            WITH fields, a, b, deactivated, valid, valid AND NOT is_friend AND fields[4] = '1' AS created
+                            FOREACH (_ IN CASE WHEN created THEN [1] ELSE [] END |
+                                CREATE (a) -[:BE_FRIEND_OF{ created_at: CASE fields[3] WHEN '' THEN datetime() ELSE datetime(fields[3]) END }]-> (b))
+                            RETURN fields[1] AS uid_a, fields[2] AS uid_b, a IS NOT NULL AS a_exists, b IS NOT NULL AS b_exists, deactivated, valid, created
+                            ORDER BY toInteger(fields[0])",
+                        )
+                        .param("batch", batch)
+                        .param("rs", RECORD_SEPARATOR)
+                        .param("us", UNIT_SEPARATOR),
+                    )
+                    .await?;
+                let mut outcome = InsertOutcome::default();
+                let mut events = Vec::new();
+                while let Some(row) = rows.next().await? {
+                    if let (Some(uid_a), Some(uid_b)) = (row.get::<String>("uid_a"), row.get::<String>("uid_b")) {
+                        if !row.get::<bool>("valid").unwrap_or(false) {
+                            let (a_exists, b_exists) = (row.get("a_exists").unwrap_or(false), row.get("b_exists").unwrap_or(false));
+                            let reason = refusal(&uid_a, &uid_b, a_exists, b_exists, row.get("deactivated").unwrap_or(false))
+                                .unwrap_or_else(|| Error::internal(format!("friendship of user {} and user {} not created", uid_a, uid_b)));
+                            outcome.rejected.push(((uid_a, uid_b), reason.body()));
+                        } else if row.get::<bool>("created").unwrap_or(false) {
+                            events.push(Event::FriendAdded {
+                                uid: uid_a.clone(),
+                                friend: uid_b.clone(),
+                            });
+                            outcome.created.push((uid_a, uid_b));
+                        }
+                    }
+                }
+                Ok((outcome, events))
+            }
+            .await;
+            finish(txn, res).await
+        })
+    }
+
+    fn deactivate_many(&self, uids: Vec<Self::UID>) -> BoxFuture<Vec<Self::UID>> {
+        let graph = self.graph.clone();
+        Box::pin(async move {
+            let uids = uids.into_iter().collect::<BTreeSet<_>>();
+            if uids.is_empty() {
+                return Ok(Vec::new());
+            }
+            for uid in &uids {
+                check_separators(uid)?;
+            }
+            let batch = uids.into_iter().collect::<Vec<_>>().join(RECORD_SEPARATOR);
+            let txn = graph.start_txn().await?;
+            let res = async {
+                let mut rows = txn
+                    .execute(
+                        query(
+                            "UNWIND split($batch, $rs) AS uid
+                            MATCH (p:Person{ uid: uid }) WHERE NOT coalesce(p.deactivated, false)
+                            SET p.deactivated = true
+                            WITH p
+                            OPTIONAL MATCH (p) -[:BE_FRIEND_OF]- (f:Person)
+                            RETURN p.uid AS uid, f.uid AS friend",
+                        )
+                        .param("batch", batch)
+                        .param("rs", RECORD_SEPARATOR),
+                    )
+                    .await?;
+                let mut deactivated = BTreeMap::<String, Vec<String>>::new();
+                while let Some(row) = rows.next().await? {
+                    if let Some(uid) = row.get("uid") {
+                        let friends = deactivated.entry(uid).or_default();
+                        friends.extend(row.get("friend"));
+                    }
+                }
+                let friends = deactivated.values().flatten().cloned().collect::<BTreeSet<_>>().into_iter().collect();
+                let events = deactivated.into_iter().map(|(uid, friends)| deactivation_event(uid, friends, true)).collect();
+                Ok((friends, events))
+            }
+            .await;
+            finish(txn, res).await
+        })
+    }
+
+    fn profiles(&self, uids: Vec<Self::UID>) -> BoxFuture<BTreeMap<Self::UID, BTreeMap<String, String>>> {
+        let graph = self.graph.clone();
+        Box::pin(async move {
+            let mut res = BTreeMap::new();
+            if uids.is_empty() {
+                return Ok(res);
+            }
+            for uid in &uids {
+                check_separators(uid)?;
+            }
+            let mut rows = graph
+                .execute(
+                    query(
+                        "UNWIND split($batch, $rs) AS uid
+                        MATCH (p:Person{ uid: uid })
+                        UNWIND keys(p) AS key
+                        RETURN uid, key, toString(p[key]) AS value",
+                    )
+                    .param("batch", uids.join(RECORD_SEPARATOR))
+                    .param("rs", RECORD_SEPARATOR),
+                )
+                .await?;
+            while let Some(row) = rows.next().await? {
+                if let (Some(uid), Some(key), Some(value)) = (row.get::<String>("uid"), row.get("key"), row.get("value")) {
+                    let profile: &mut BTreeMap<String, String> = res.entry(uid).or_default();
+                    profile.insert(key, value);
+                }
+            }
+            Ok(res)
+        })
+    }
+
+    fn export(&self, uid: Self::UID) -> BoxFuture<UserExport<Self::UID>> {
+        let graph = self.graph.clone();
+        let profiles = self.profiles(vec![uid.clone()]);
+        Box::pin(async move {
+            let profile = match profiles.await?.remove(&uid) {
+                Some(profile) => profile,
+                None => return Err(Error::not_found(format!("user {} not found", uid))),
+            };
+            let mut rows = graph
+                .execute(
+                    query(
+                        "MATCH (p:Person{ uid: $uid }) -[r:BE_FRIEND_OF]- (f:Person)
+                        RETURN f.uid AS uid, startNode(r) = p AS initiated, toString(r.created_at) AS created_at
+                        ORDER BY uid",
+                    )
+                    .param("uid", uid.clone()),
+                )
+                .await?;
+            let mut friendships = Vec::new();
+            while let Some(row) = rows.next().await? {
+                if let Some(friend) = row.get("uid") {
+                    friendships.push(Friendship {
+                        uid: friend,
+                        initiated: row.get("initiated").unwrap_or_default(),
+                        created_at: row.get("created_at"),
+                    });
+                }
+            }
+            Ok(UserExport {
+                uid,
+                exported_at: Utc::now().to_rfc3339(),
+                profile,
+                friendships,
+            })
+        })
+    }
+
+    fn batch(&self, ops: Vec<BatchOp<Self::UID>>, atomic: bool) -> BoxFuture<BatchOutcome> {
+        let graph = self.graph.clone();
+        Box::pin(async move {
+            let txn = graph.start_txn().await?;
+            let res = async {
+                let mut results = Vec::with_capacity(ops.len());
+                let mut events = Vec::new();
+                for op in &ops {
+                    // Failed items leave the graph untouched, so only the atomic mode needs a rollback.
+                    let res = match op {
+                        BatchOp::Add { uid_a, uid_b } => match check_not_self(uid_a, uid_b) {
+                            Ok(()) => insert_result(txn.execute(insert_query(uid_a, uid_b)).await?, uid_a, uid_b).await,
+                            Err(e) => Err(e),
+                        },
+                        BatchOp::Delete { uid_a, uid_b } => delete_result(txn.execute(delete_query(uid_a, uid_b)).await?, uid_a, uid_b).await,
+                    };
+                    if res.is_ok() {
+                        events.push(match op.clone() {
+                            BatchOp::Add { uid_a, uid_b } => Event::FriendAdded { uid: uid_a, friend: uid_b },
+                            BatchOp::Delete { uid_a, uid_b } => Event::FriendRemoved { uid: uid_a, friend: uid_b },
+                        });
+                    }
+                    results.push(res.err().map(|e| e.body()));
+                }
+                Ok::<_, Error>((results, events))
+            }
+            .await;
+            // A failed query aborts the whole batch, whatever the mode.
+            let (results, events) = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    txn.rollback().await?;
+                    return Err(e);
+                }
+            };
+            let committed = !atomic || results.iter().all(Option::is_none);
+            if committed {
+                finish(txn, Ok(((), events))).await?;
+            } else {
+                txn.rollback().await?;
+            }
+            Ok(BatchOutcome { committed, results })
+        })
+    }
+
+    fn list_users(&self, after: Option<Self::UID>, limit: usize) -> BoxFuture<Vec<(Self::UID, bool)>> {
+        let graph = self.graph.clone();
+        Box::pin(async move {
+            let filter = if after.is_some() { "WHERE p.uid > $after" } else { "" };
+            let mut rows = graph
+                .execute(
+                    query(&format!(
+                        "MATCH (p:Person) {} RETURN p.uid AS uid, coalesce(p.deactivated, false) AS deactivated ORDER BY uid LIMIT $limit",
+                        filter
+                    ))
+                    .param("after", after.unwrap_or_default())
+                    .param("limit", limit as i64),
+                )
+                .await?;
+            let mut res = Vec::new();
+            while let Some(row) = rows.next().await? {
+                if let Some(uid) = row.get("uid") {
+                    res.push((uid, row.get("deactivated").unwrap_or_default()));
+                }
+            }
+            Ok(res)
+        })
+    }
+
+    fn list_friendships(&self, after: Option<(Self::UID, Self::UID)>, limit: usize) -> BoxFuture<Vec<FriendshipPair<Self::UID>>> {
+        let graph = self.graph.clone();
+        Box::pin(async move {
+            // Every friendship is matched from both ends, `a.uid < b.uid` keeps one of them.
+            let filter = if after.is_some() {
+                "AND (a.uid > $after_a OR (a.uid = $after_a AND b.uid > $after_b))"
+            } else {
+                ""
+            };
+            let (after_a, after_b) = after.unwrap_or_default();
+            let mut rows = graph
+                .execute(
+                    query(&format!(
+                        "MATCH (a:Person) -[r:BE_FRIEND_OF]- (b:Person) WHERE a.uid < b.uid {}
+                        RETURN a.uid AS uid_a, b.uid AS uid_b, startNode(r) = a AS initiated, r.created_at AS created_at
+                        ORDER BY uid_a, uid_b LIMIT $limit",
+                        filter
+                    ))
+                    .param("after_a", after_a)
+                    .param("after_b", after_b)
+                    .param("limit", limit as i64),
+                )
+                .await?;
+            let mut res = Vec::new();
+            while let Some(row) = rows.next().await? {
+                if let (Some(uid_a), Some(uid)) = (row.get("uid_a"), row.get("uid_b")) {
+                    let created_at = row.get::<DateTime<FixedOffset>>("created_at");
+                    res.push((
+                        uid_a,
+                        Friendship {
+                            uid,
+                            initiated: row.get("initiated").unwrap_or_default(),
+                            created_at: created_at.map(|created_at| created_at.to_rfc3339()),
+                        },
+                    ));
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+// Events which were numbered by the per-transaction `seq` of older versions share it with
+// other transactions, `created_at` keeps those in order.
+const OUTBOX_ORDER: &str = "ORDER BY e.seq, e.created_at";
+
+impl Outbox for Neo {
+    type UID = String;
+    fn claim(&self, owner: String, limit: usize, lease: Duration) -> BoxFuture<Vec<OutboxEvent<Self::UID>>> {
+        let graph = self.graph.clone();
+        Box::pin(async move {
+            let txn = graph.start_txn().await?;
+            let res = async {
+                // Relays take the lock before looking at the claims, so two of them never
+                // both see the outbox unclaimed.
+                txn.run(query("MERGE (l:OutboxLock{ name: 'relay' }) SET l.owner = $owner").param("owner", owner.clone())).await?;
+                let mut rows = txn
+                    .execute(
+                        query(&format!(
+                            "OPTIONAL MATCH (c:OutboxEvent) WHERE c.delivered_at IS NULL AND c.claimed_by <> $owner AND c.claimed_at > timestamp() - $lease
+                            WITH count(c) AS claimed
+                            MATCH (e:OutboxEvent) WHERE claimed = 0 AND e.delivered_at IS NULL
+                            WITH e {0} LIMIT $limit
+                            SET e.claimed_by = $owner, e.claimed_at = timestamp()
+                            RETURN e.id AS id, e.payload AS payload {0}",
+                            OUTBOX_ORDER
+                        ))
+                        .param("owner", owner)
+                        .param("lease", lease.as_millis() as i64)
+                        .param("limit", limit as i64),
+                    )
+                    .await?;
+                let mut claimed = Vec::new();
+                while let Some(row) = rows.next().await? {
+                    if let (Some(id), Some(payload)) = (row.get("id"), row.get::<String>("payload")) {
+                        claimed.push(OutboxEvent {
+                            id,
+                            event: serde_json::from_str(&payload)?,
+                        });
+                    }
+                }
+                Ok((claimed, Vec::new()))
+            }
+            .await;
+            finish(txn, res).await
+        })
+    }
+
+    fn mark_delivered(&self, ids: Vec<String>) -> BoxFuture<()> {
+        let graph = self.graph.clone();
+        Box::pin(async move {
+            if ids.is_empty() {
+                return Ok(());
+            }
+            graph
+                .run(
+                    query("UNWIND split($ids, $rs) AS id MATCH (e:OutboxEvent{ id: id }) SET e.delivered_at = timestamp()")
+                        .param("ids", ids.join(RECORD_SEPARATOR))
+                        .param("rs", RECORD_SEPARATOR),
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn purge(&self, retention: Duration, limit: usize) -> BoxFuture<usize> {
+        let graph = self.graph.clone();
+        Box::pin(async move {
+            let mut rows = graph
+                .execute(
+                    query(
+                        "MATCH (e:OutboxEvent) WHERE e.delivered_at < timestamp() - $retention
+                        WITH e LIMIT $limit
+                        DELETE e
+                        RETURN count(*) AS purged",
+                    )
+                    .param("retention", retention.as_millis() as i64)
+                    .param("limit", limit as i64),
+                )
+                .await?;
+            Ok(rows.next().await?.and_then(|row| row.get::<i64>("purged")).unwrap_or(0) as usize)
+        })
+    }
+}
+
+// In-memory implementation of `Persister`, so tests can run (and be seeded from a
+// snapshot) without a Neo4j instance.
+#[cfg(test)]
+pub use memory::Memory;
+
+#[cfg(test)]
+mod memory {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    #[derive(Default, Clone)]
+    struct MemoryGraph {
+        users: BTreeMap<String, bool>,
+        friendships: BTreeMap<(String, String), String>,
+        // Events in commit order, rolled back with the rest of the graph.
+        outbox: Vec<Stored>,
+    }
+
+    #[derive(Clone)]
+    struct Stored {
+        event: OutboxEvent<String>,
+        claim: Option<(String, Instant)>,
+        delivered_at: Option<Instant>,
+    }
+
+    impl MemoryGraph {
+        fn record(&mut self, event: Event<String>) {
+            let id = Uuid::new_v4().to_string();
+            self.outbox.push(Stored {
+                event: OutboxEvent { id, event },
+                claim: None,
+                delivered_at: None,
+            });
+        }
+
+        fn check_user(&self, uid: &str) -> Result<bool, Error> {
+            self.users.get(uid).copied().ok_or_else(|| Error::not_found(format!("user {} not found", uid)))
+        }
+
+        // The properties `Neo` would store for the user.
+        fn profile(&self, uid: &str) -> Result<BTreeMap<String, String>, Error> {
+            let mut profile = BTreeMap::from([("uid".to_owned(), uid.to_owned())]);
+            if self.check_user(uid)? {
+                profile.insert("deactivated".into(), "true".into());
+            }
+            Ok(profile)
+        }
+
+        fn key(&self, uid_a: &str, uid_b: &str) -> Option<(String, String)> {
+            [(uid_a, uid_b), (uid_b, uid_a)]
+                .into_iter()
+                .map(|(a, b)| (a.to_owned(), b.to_owned()))
+                .find(|key| self.friendships.contains_key(key))
+        }
+
+        fn all_friends(&self, uid: &str) -> Vec<String> {
+            let mut friends = self
+                .friendships
+                .keys()
+                .filter_map(|(a, b)| match (a == uid, b == uid) {
+                    (true, _) => Some(b.clone()),
+                    (_, true) => Some(a.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            friends.sort();
+            friends
+        }
+
+        fn insert(&mut self, uid_a: String, uid_b: String) -> Result<(), Error> {
+            check_not_self(&uid_a, &uid_b)?;
+            let deactivated = self.check_user(&uid_a)? | self.check_user(&uid_b)?;
+            if self.key(&uid_a, &uid_b).is_some() {
+                return Err(Error::already_exists(format!("user {} and user {} are already friends", uid_a, uid_b)));
+            }
+            if deactivated {
+                return Err(Error::conflict(format!("user {} or user {} is deactivated", uid_a, uid_b)));
+            }
+            self.friendships.insert((uid_a.clone(), uid_b.clone()), Utc::now().to_rfc3339());
+            self.record(Event::FriendAdded { uid: uid_a, friend: uid_b });
+            Ok(())
+        }
+
+        fn delete(&mut self, uid_a: &str, uid_b: &str) -> Result<(), Error> {
+            match self.key(uid_a, uid_b) {
+                Some(key) => {
+                    self.friendships.remove(&key);
+                    self.record(Event::FriendRemoved {
+                        uid: uid_a.to_owned(),
+                        friend: uid_b.to_owned(),
+                    });
+                    Ok(())
+                }
+                None => Err(Error::not_found(format!("user {} and user {} are not friends", uid_a, uid_b))),
+            }
+        }
+
+        fn set_deactivated(&mut self, uid: &str, deactivated: bool) -> Result<Vec<String>, Error> {
+            match self.check_user(uid)? {
+                current if current == deactivated && deactivated => Err(Error::conflict(format!("user {} is already deactivated", uid))),
+                current if current == deactivated => Err(Error::conflict(format!("user {} is not deactivated", uid))),
+                _ => {
+                    self.users.insert(uid.to_owned(), deactivated);
+                    let friends = self.all_friends(uid);
+                    self.record(deactivation_event(uid.to_owned(), friends.clone(), deactivated));
+                    Ok(friends)
+                }
+            }
+        }
+
+        // Mirrors the variable length match of `Neo::recommendations`: paths of exactly `level`
+        // hops which never reuse a friendship and never pass through a deactivated user.
+        fn walk(&self, uid: &str, level: i32, used: &mut Vec<(String, String)>, counts: &mut BTreeMap<String, i32>) {
+            if level == 0 {
+                *counts.entry(uid.to_owned()).or_default() += 1;
+                return;
+            }
+            for friend in self.all_friends(uid) {
+                let key = self.key(uid, &friend).unwrap();
+                if used.contains(&key) || self.users.get(&friend).copied().unwrap_or(true) {
+                    continue;
+                }
+                used.push(key);
+                self.walk(&friend, level - 1, used, counts);
+                used.pop();
+            }
+        }
+    }
+
+    #[derive(Default, Clone)]
+    pub struct Memory {
+        graph: Arc<Mutex<MemoryGraph>>,
+    }
+
+    impl Memory {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        // Undelivered events in the order they were recorded, claimed or not.
+        pub fn pending(&self, limit: usize) -> BoxFuture<Vec<OutboxEvent<String>>> {
+            self.with(move |g| Ok(g.outbox.iter().filter(|stored| stored.delivered_at.is_none()).take(limit).map(|stored| stored.event.clone()).collect()))
+        }
+
+        fn with<T: 'static>(&self, f: impl FnOnce(&mut MemoryGraph) -> Result<T, Error>) -> BoxFuture<T> {
+            let res = f(&mut self.graph.lock().unwrap());
+            Box::pin(async move { res })
+        }
+    }
+
+    impl Persister for Memory {
+        type UID = String;
+        fn insert_node(&self, uid: Self::UID) -> BoxFuture<()> {
+            self.with(move |g| {
+                if g.users.contains_key(&uid) {
+                    return Err(Error::already_exists(format!("user {} already exists", uid)));
+                }
+                g.users.insert(uid.clone(), false);
+                g.record(Event::UserCreated { uid });
+                Ok(())
+            })
+        }
+
+        fn delete_node(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>> {
+            self.with(move |g| {
+                g.check_user(&uid)?;
+                let friends = g.all_friends(&uid);
+                g.users.remove(&uid);
+                g.friendships.retain(|(a, b), _| a != &uid && b != &uid);
+                g.record(Event::UserDeleted { uid, friends: friends.clone() });
+                Ok(friends)
+            })
+        }
+
+        fn exist_node(&self, uid: Self::UID) -> BoxFuture<bool> {
+            self.with(move |g| Ok(g.users.contains_key(&uid)))
+        }
+
+        fn insert(&self, uid_a: Self::UID, uid_b: Self::UID) -> BoxFuture<()> {
+            self.with(move |g| g.insert(uid_a, uid_b))
+        }
+
+        fn delete(&self, uid_a: Self::UID, uid_b: Self::UID) -> BoxFuture<()> {
+            self.with(move |g| g.delete(&uid_a, &uid_b))
+        }
+
+        fn friends(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>> {
+            self.with(move |g| {
+                g.check_user(&uid)?;
+                Ok(g.all_friends(&uid).into_iter().filter(|f| !g.users[f]).collect())
+            })
+        }
+
+        fn all_friends(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>> {
+            self.with(move |g| {
+                g.check_user(&uid)?;
+                Ok(g.all_friends(&uid))
+            })
+        }
+
+        fn friends_many(&self, uids: Vec<Self::UID>) -> BoxFuture<BTreeMap<Self::UID, Vec<Self::UID>>> {
+            self.with(move |g| {
+                Ok(uids
+                    .into_iter()
+                    .filter(|uid| g.users.contains_key(uid))
+                    .map(|uid| {
+                        let friends = g.all_friends(&uid).into_iter().filter(|f| !g.users[f]).collect();
+                        (uid, friends)
+                    })
+                    .collect())
+            })
+        }
+
+        fn is_friend(&self, uid_a: Self::UID, uid_b: Self::UID) -> BoxFuture<bool> {
+            self.with(move |g| {
+                let active = |uid: &String| g.users.get(uid) == Some(&false);
+                Ok(active(&uid_a) && active(&uid_b) && g.key(&uid_a, &uid_b).is_some())
+            })
+        }
+
+        fn recommendations(&self, uid: Self::UID, level: i32, threshold: i32) -> BoxFuture<Vec<Self::UID>> {
+            self.with(move |g| {
+                let mut counts = BTreeMap::new();
+                if !g.users.get(&uid).copied().unwrap_or(true) {
+                    g.walk(&uid, level, &mut Vec::new(), &mut counts);
+                }
+                Ok(counts.into_iter().filter(|(_, count)| *count >= threshold).map(|(uid, _)| uid).collect())
+            })
+        }
+
+        fn deactivate(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>> {
+            self.with(move |g| g.set_deactivated(&uid, true))
+        }
+
+        fn reactivate(&self, uid: Self::UID) -> BoxFuture<Vec<Self::UID>> {
+            self.with(move |g| g.set_deactivated(&uid, false))
+        }
+
+        fn deactivate_many(&self, uids: Vec<Self::UID>) -> BoxFuture<Vec<Self::UID>> {
+            self.with(move |g| {
+                let mut friends = BTreeSet::new();
+                for uid in uids {
+                    if g.users.get(&uid) == Some(&false) {
+                        friends.extend(g.set_deactivated(&uid, true)?);
+                    }
+                }
+                Ok(friends.into_iter().collect())
+            })
+        }
+
+        fn export(&self, uid: Self::UID) -> BoxFuture<UserExport<Self::UID>> {
+            self.with(move |g| {
+                let profile = g.profile(&uid)?;
+                let friendships = g
+                    .friendships
+                    .iter()
+                    .filter_map(|((a, b), created_at)| match (a == &uid, b == &uid) {
+                        (true, _) => Some((b.clone(), true, created_at.clone())),
+                        (_, true) => Some((a.clone(), false, created_at.clone())),
+                        _ => None,
+                    })
+                    .map(|(friend, initiated, created_at)| Friendship {
+                        uid: friend,
+                        initiated,
+                        created_at: Some(created_at),
+                    })
+                    .collect::<Vec<_>>();
+                let mut friendships = friendships;
+                friendships.sort_by(|a, b| a.uid.cmp(&b.uid));
+                Ok(UserExport {
+                    uid,
+                    exported_at: Utc::now().to_rfc3339(),
+                    profile,
+                    friendships,
+                })
+            })
+        }
+
+        fn profiles(&self, uids: Vec<Self::UID>) -> BoxFuture<BTreeMap<Self::UID, BTreeMap<String, String>>> {
+            self.with(move |g| Ok(uids.into_iter().filter_map(|uid| Some((uid.clone(), g.profile(&uid).ok()?))).collect()))
+        }
+
+        fn insert_nodes(&self, uids: Vec<Self::UID>) -> BoxFuture<Vec<Self::UID>> {
+            self.with(move |g| {
+                let mut created = Vec::new();
+                for uid in uids {
+                    if !g.users.contains_key(&uid) {
+                        g.users.insert(uid.clone(), false);
+                        g.record(Event::UserCreated { uid: uid.clone() });
+                        created.push(uid);
+                    }
+                }
+                Ok(created)
+            })
+        }
+
+        fn insert_many(&self, pairs: Vec<(Self::UID, Self::UID, Option<String>)>) -> BoxFuture<InsertOutcome<Self::UID>> {
+            self.with(move |g| {
+                let mut outcome = InsertOutcome::default();
+                for (uid_a, uid_b, created_at) in pairs {
+                    let (a, b) = (g.users.get(&uid_a).copied(), g.users.get(&uid_b).copied());
+                    if let Some(reason) = refusal(&uid_a, &uid_b, a.is_some(), b.is_some(), a == Some(true) || b == Some(true)) {
+                        outcome.rejected.push(((uid_a, uid_b), reason.body()));
+                    } else if g.key(&uid_a, &uid_b).is_none() {
+                        g.friendships.insert((uid_a.clone(), uid_b.clone()), created_at.unwrap_or_else(|| Utc::now().to_rfc3339()));
+                        g.record(Event::FriendAdded {
+                            uid: uid_a.clone(),
+                            friend: uid_b.clone(),
+                        });
+                        outcome.created.push((uid_a, uid_b));
+                    }
+                }
+                Ok(outcome)
+            })
+        }
+
+        fn batch(&self, ops: Vec<BatchOp<Self::UID>>, atomic: bool) -> BoxFuture<BatchOutcome> {
+            self.with(move |g| {
+                let snapshot = g.clone();
+                let results = ops
+                    .into_iter()
+                    .map(|op| match op {
+                        BatchOp::Add { uid_a, uid_b } => g.insert(uid_a, uid_b),
+                        BatchOp::Delete { uid_a, uid_b } => g.delete(&uid_a, &uid_b),
+                    })
+                    .map(|res| res.err().map(|e| e.body()))
+                    .collect::<Vec<_>>();
+                let committed = !atomic || results.iter().all(Option::is_none);
+                if !committed {
+                    *g = snapshot;
+                }
+                Ok(BatchOutcome { committed, results })
+            })
+        }
+
+        fn list_users(&self, after: Option<Self::UID>, limit: usize) -> BoxFuture<Vec<(Self::UID, bool)>> {
+            self.with(move |g| {
+                Ok(g.users
+                    .iter()
+                    .filter(|(uid, _)| after.as_ref().is_none_or(|after| *uid > after))
+                    .take(limit)
+                    .map(|(uid, deactivated)| (uid.clone(), *deactivated))
+                    .collect())
+            })
+        }
+
+        fn list_friendships(&self, after: Option<(Self::UID, Self::UID)>, limit: usize) -> BoxFuture<Vec<FriendshipPair<Self::UID>>> {
+            self.with(move |g| {
+                let friendships = g
+                    .friendships
+                    .iter()
+                    .map(|((a, b), created_at)| {
+                        let (uid_a, uid) = if a < b { (a, b) } else { (b, a) };
+                        let friendship = Friendship {
+                            uid: uid.clone(),
+                            initiated: uid_a == a,
+                            created_at: Some(created_at.clone()),
+                        };
+                        ((uid_a.clone(), uid.clone()), friendship)
+                    })
+                    .collect::<BTreeMap<_, _>>();
+                Ok(friendships
+                    .into_iter()
+                    .filter(|(pair, _)| after.as_ref().is_none_or(|after| pair > after))
+                    .take(limit)
+                    .map(|((uid_a, _), friendship)| (uid_a, friendship))
+                    .collect())
+            })
+        }
+    }
+
+    impl Outbox for Memory {
+        type UID = String;
+        fn claim(&self, owner: String, limit: usize, lease: Duration) -> BoxFuture<Vec<OutboxEvent<Self::UID>>> {
+            self.with(move |g| {
+                let undelivered = g.outbox.iter_mut().filter(|stored| stored.delivered_at.is_none());
+                let mut undelivered = undelivered.collect::<Vec<_>>();
+                let claimed = undelivered.iter().any(|stored| stored.claim.as_ref().is_some_and(|(by, at)| by != &owner && at.elapsed() < lease));
+                if claimed {
+                    return Ok(Vec::new());
+                }
+                Ok(undelivered
+                    .iter_mut()
+                    .take(limit)
+                    .map(|stored| {
+                        stored.claim = Some((owner.clone(), Instant::now()));
+                        stored.event.clone()
+                    })
+                    .collect())
+            })
+        }
+
+        fn mark_delivered(&self, ids: Vec<String>) -> BoxFuture<()> {
+            self.with(move |g| {
+                for stored in g.outbox.iter_mut().filter(|stored| ids.contains(&stored.event.id)) {
+                    stored.delivered_at = Some(Instant::now());
+                }
+                Ok(())
+            })
+        }
+
+        fn purge(&self, retention: Duration, limit: usize) -> BoxFuture<usize> {
+            self.with(move |g| {
+                let mut purged = 0;
+                g.outbox.retain(|stored| {
+                    let expired = purged < limit && stored.delivered_at.is_some_and(|at| at.elapsed() >= retention);
+                    purged += expired as usize;
+                    !expired
+                });
+                Ok(purged)
+            })
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use dotenv;
+    use crate::error::ErrorKind;
     use neo4rs::Graph;
 
     #[tokio::test]
@@ -156,7 +1284,7 @@ mod test {
         let graph = Graph::new("localhost:7687", &username, &password).await.expect("failed to connect to neo4j");
         let neo = Neo::new(Arc::new(graph));
         neo.insert_node(1.to_string()).await.expect("failed to insert node");
-        assert!(neo.exist_node(1.to_string()).await.expect("failed to check node exists") == true);
+        assert!(neo.exist_node(1.to_string()).await.expect("failed to check node exists"));
         neo.delete_node(1.to_string()).await.expect("failed to delete node");
     }
 
@@ -170,7 +1298,7 @@ mod test {
         neo.insert_node(1.to_string()).await.expect("failed to insert node");
         neo.insert_node(2.to_string()).await.expect("failed to insert node");
         neo.insert(1.to_string(), 2.to_string()).await.expect("failed to insert relation");
-        assert!(neo.is_friend(1.to_string(), 2.to_string()).await.expect("failed to check is friend") == true);
+        assert!(neo.is_friend(1.to_string(), 2.to_string()).await.expect("failed to check is friend"));
         neo.delete(1.to_string(), 2.to_string()).await.expect("failed to delete relation");
         neo.delete_node(1.to_string()).await.expect("failed to delete node");
         neo.delete_node(2.to_string()).await.expect("failed to delete node");
@@ -223,4 +1351,326 @@ mod test {
         neo.delete_node(4.to_string()).await.expect("failed to delete node");
         assert!(rs == vec![4.to_string()]);
     }
+
+    #[tokio::test]
+    async fn test_error_kinds() {
+        dotenv::dotenv().expect("failed to load environment variables");
+        let username = dotenv::var("NEO4J_USERNAME").expect("failed to get NEO4J_USERNAME");
+        let password = dotenv::var("NEO4J_PASSWORD").expect("failed to get NEO4J_PASSWORD");
+        let graph = Graph::new("localhost:7687", &username, &password).await.expect("failed to connect to neo4j");
+        let neo = Neo::new(Arc::new(graph));
+        neo.insert_node(1.to_string()).await.expect("failed to insert node");
+        neo.insert_node(2.to_string()).await.expect("failed to insert node");
+        assert_eq!(neo.insert_node(1.to_string()).await.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(neo.insert(1.to_string(), 1.to_string()).await.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(neo.insert(1.to_string(), 3.to_string()).await.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(neo.delete(1.to_string(), 2.to_string()).await.unwrap_err().kind(), ErrorKind::NotFound);
+        neo.insert(1.to_string(), 2.to_string()).await.expect("failed to insert relation");
+        assert_eq!(neo.insert(2.to_string(), 1.to_string()).await.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        neo.delete(1.to_string(), 2.to_string()).await.expect("failed to delete relation");
+        neo.delete_node(1.to_string()).await.expect("failed to delete node");
+        neo.delete_node(2.to_string()).await.expect("failed to delete node");
+        assert_eq!(neo.delete_node(1.to_string()).await.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(neo.friends(1.to_string()).await.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_delete_node_with_friends() {
+        dotenv::dotenv().expect("failed to load environment variables");
+        let username = dotenv::var("NEO4J_USERNAME").expect("failed to get NEO4J_USERNAME");
+        let password = dotenv::var("NEO4J_PASSWORD").expect("failed to get NEO4J_PASSWORD");
+        let graph = Graph::new("localhost:7687", &username, &password).await.expect("failed to connect to neo4j");
+        let neo = Neo::new(Arc::new(graph));
+        neo.insert_node(1.to_string()).await.expect("failed to insert node");
+        neo.insert_node(2.to_string()).await.expect("failed to insert node");
+        neo.insert_node(3.to_string()).await.expect("failed to insert node");
+        neo.insert(1.to_string(), 2.to_string()).await.expect("failed to insert relation");
+        neo.insert(3.to_string(), 1.to_string()).await.expect("failed to insert relation");
+        let mut friends = neo.delete_node(1.to_string()).await.expect("failed to delete node");
+        friends.sort();
+        assert!(friends == vec![2.to_string(), 3.to_string()]);
+        assert!(!neo.exist_node(1.to_string()).await.expect("failed to check node exists"));
+        assert!(neo.friends(2.to_string()).await.expect("failed to get friends").is_empty());
+        neo.delete_node(2.to_string()).await.expect("failed to delete node");
+        neo.delete_node(3.to_string()).await.expect("failed to delete node");
+    }
+
+    #[tokio::test]
+    async fn test_deactivate() {
+        dotenv::dotenv().expect("failed to load environment variables");
+        let username = dotenv::var("NEO4J_USERNAME").expect("failed to get NEO4J_USERNAME");
+        let password = dotenv::var("NEO4J_PASSWORD").expect("failed to get NEO4J_PASSWORD");
+        let graph = Graph::new("localhost:7687", &username, &password).await.expect("failed to connect to neo4j");
+        let neo = Neo::new(Arc::new(graph));
+        neo.insert_node(1.to_string()).await.expect("failed to insert node");
+        neo.insert_node(2.to_string()).await.expect("failed to insert node");
+        neo.insert(1.to_string(), 2.to_string()).await.expect("failed to insert relation");
+        assert!(neo.deactivate(2.to_string()).await.expect("failed to deactivate") == vec![1.to_string()]);
+        assert_eq!(neo.deactivate(2.to_string()).await.unwrap_err().kind(), ErrorKind::Conflict);
+        assert!(neo.friends(1.to_string()).await.expect("failed to get friends").is_empty());
+        assert!(neo.all_friends(1.to_string()).await.expect("failed to get friends") == vec![2.to_string()]);
+        assert!(!neo.is_friend(1.to_string(), 2.to_string()).await.expect("failed to check is friend"));
+        assert!(neo.reactivate(2.to_string()).await.expect("failed to reactivate") == vec![1.to_string()]);
+        assert!(neo.friends(1.to_string()).await.expect("failed to get friends") == vec![2.to_string()]);
+        neo.delete_node(1.to_string()).await.expect("failed to delete node");
+        neo.delete_node(2.to_string()).await.expect("failed to delete node");
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        dotenv::dotenv().expect("failed to load environment variables");
+        let username = dotenv::var("NEO4J_USERNAME").expect("failed to get NEO4J_USERNAME");
+        let password = dotenv::var("NEO4J_PASSWORD").expect("failed to get NEO4J_PASSWORD");
+        let graph = Graph::new("localhost:7687", &username, &password).await.expect("failed to connect to neo4j");
+        let neo = Neo::new(Arc::new(graph));
+        neo.insert_node(1.to_string()).await.expect("failed to insert node");
+        neo.insert_node(2.to_string()).await.expect("failed to insert node");
+        neo.insert_node(3.to_string()).await.expect("failed to insert node");
+        neo.insert(1.to_string(), 2.to_string()).await.expect("failed to insert relation");
+        neo.insert(3.to_string(), 1.to_string()).await.expect("failed to insert relation");
+        let export = neo.export(1.to_string()).await.expect("failed to export user");
+        neo.delete_node(1.to_string()).await.expect("failed to delete node");
+        neo.delete_node(2.to_string()).await.expect("failed to delete node");
+        neo.delete_node(3.to_string()).await.expect("failed to delete node");
+        assert!(export.profile.get("uid") == Some(&1.to_string()));
+        assert!(export.friendships.iter().map(|f| (f.uid.clone(), f.initiated)).collect::<Vec<_>>() == vec![(2.to_string(), true), (3.to_string(), false)]);
+        assert!(export.friendships.iter().all(|f| f.created_at.is_some()));
+        assert_eq!(neo.export(1.to_string()).await.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_insert_many() {
+        dotenv::dotenv().expect("failed to load environment variables");
+        let username = dotenv::var("NEO4J_USERNAME").expect("failed to get NEO4J_USERNAME");
+        let password = dotenv::var("NEO4J_PASSWORD").expect("failed to get NEO4J_PASSWORD");
+        let graph = Graph::new("localhost:7687", &username, &password).await.expect("failed to connect to neo4j");
+        let neo = Neo::new(Arc::new(graph));
+        let created = neo
+            .insert_nodes(vec![1.to_string(), 2.to_string(), 3.to_string(), 1.to_string()])
+            .await
+            .expect("failed to insert nodes");
+        assert!(created.len() == 3);
+        assert!(neo.insert_nodes(vec![1.to_string()]).await.expect("failed to insert nodes").is_empty());
+        let outcome = neo
+            .insert_many(vec![
+                (1.to_string(), 2.to_string(), Some("2024-01-02T03:04:05+00:00".into())),
+                (1.to_string(), 3.to_string(), None),
+                (1.to_string(), 4.to_string(), None),
+                (2.to_string(), 1.to_string(), None),
+            ])
+            .await
+            .expect("failed to insert relations");
+        let friends = neo.friends(1.to_string()).await.expect("failed to get friends");
+        let friendships = neo.list_friendships(Some((0.to_string(), 0.to_string())), 2).await.expect("failed to list friendships");
+        let touched = neo
+            .deactivate_many(vec![2.to_string(), 3.to_string(), 3.to_string(), 4.to_string()])
+            .await
+            .expect("failed to deactivate");
+        let again = neo.deactivate_many(vec![2.to_string()]).await.expect("failed to deactivate");
+        let existing = neo.insert_many(vec![(1.to_string(), 3.to_string(), None)]).await.expect("failed to insert relations");
+        neo.delete_node(1.to_string()).await.expect("failed to delete node");
+        neo.delete_node(2.to_string()).await.expect("failed to delete node");
+        neo.delete_node(3.to_string()).await.expect("failed to delete node");
+        assert!(outcome.created == vec![(1.to_string(), 2.to_string()), (1.to_string(), 3.to_string())]);
+        assert!(outcome.rejected.iter().map(|(pair, reason)| (pair.clone(), reason.code)).collect::<Vec<_>>() == vec![((1.to_string(), 4.to_string()), ErrorKind::NotFound)]);
+        assert!(existing.created.is_empty() && existing.rejected[0].1.code == ErrorKind::Conflict);
+        assert!(friends == vec![2.to_string(), 3.to_string()]);
+        assert!(friendships.iter().map(|(uid, f)| (uid.clone(), f.uid.clone(), f.initiated)).collect::<Vec<_>>() == vec![(1.to_string(), 2.to_string(), true), (1.to_string(), 3.to_string(), true)]);
+        assert!(friendships[0].1.created_at.as_deref() == Some("2024-01-02T03:04:05+00:00"));
+        assert!(touched == vec![1.to_string()]);
+        assert!(again.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_outbox() {
+        dotenv::dotenv().expect("failed to load environment variables");
+        let username = dotenv::var("NEO4J_USERNAME").expect("failed to get NEO4J_USERNAME");
+        let password = dotenv::var("NEO4J_PASSWORD").expect("failed to get NEO4J_PASSWORD");
+        let graph = Graph::new("localhost:7687", &username, &password).await.expect("failed to connect to neo4j");
+        let neo = Neo::new(Arc::new(graph));
+        neo.insert_node(7.to_string()).await.expect("failed to insert node");
+        neo.insert_node(8.to_string()).await.expect("failed to insert node");
+        neo.insert(7.to_string(), 8.to_string()).await.expect("failed to insert relation");
+        assert_eq!(neo.insert(8.to_string(), 7.to_string()).await.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        neo.deactivate(8.to_string()).await.expect("failed to deactivate");
+        neo.reactivate(8.to_string()).await.expect("failed to reactivate");
+        neo.delete_node(7.to_string()).await.expect("failed to delete node");
+        neo.delete_node(8.to_string()).await.expect("failed to delete node");
+        // Claims expire at once with no lease, so the relays of running services are not held up.
+        let pending = neo
+            .claim("test".into(), 10000, Duration::ZERO)
+            .await
+            .expect("failed to get pending events")
+            .into_iter()
+            .filter(|pending| ["7", "8"].contains(&pending.event.uid().as_str()))
+            .collect::<Vec<_>>();
+        neo.mark_delivered(pending.iter().map(|pending| pending.id.clone()).collect()).await.expect("failed to mark delivered");
+        let delivered = pending.iter().map(|pending| pending.id.clone()).collect::<Vec<_>>();
+        let undelivered = neo.claim("test".into(), 10000, Duration::ZERO).await.expect("failed to get pending events");
+        assert!(undelivered.iter().all(|pending| !delivered.contains(&pending.id)));
+        assert!(neo.purge(Duration::ZERO, 10000).await.expect("failed to purge") >= delivered.len());
+        assert_eq!(
+            pending.into_iter().map(|pending| pending.event).collect::<Vec<_>>(),
+            vec![
+                Event::UserCreated { uid: 7.to_string() },
+                Event::UserCreated { uid: 8.to_string() },
+                Event::FriendAdded {
+                    uid: 7.to_string(),
+                    friend: 8.to_string()
+                },
+                Event::UserDeactivated {
+                    uid: 8.to_string(),
+                    friends: vec![7.to_string()]
+                },
+                Event::UserReactivated {
+                    uid: 8.to_string(),
+                    friends: vec![7.to_string()]
+                },
+                Event::UserDeleted {
+                    uid: 7.to_string(),
+                    friends: vec![8.to_string()]
+                },
+                Event::UserDeleted { uid: 8.to_string(), friends: vec![] },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory() {
+        let memory = Memory::new();
+        for uid in 1..=4 {
+            memory.insert_node(uid.to_string()).await.expect("failed to insert node");
+        }
+        assert_eq!(memory.insert_node(1.to_string()).await.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        memory.insert(1.to_string(), 2.to_string()).await.expect("failed to insert relation");
+        memory.insert(1.to_string(), 3.to_string()).await.expect("failed to insert relation");
+        memory.insert(2.to_string(), 4.to_string()).await.expect("failed to insert relation");
+        memory.insert(3.to_string(), 4.to_string()).await.expect("failed to insert relation");
+        assert_eq!(memory.insert(2.to_string(), 1.to_string()).await.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(memory.insert(1.to_string(), 5.to_string()).await.unwrap_err().kind(), ErrorKind::NotFound);
+        assert!(memory.is_friend(2.to_string(), 1.to_string()).await.expect("failed to check is friend"));
+        assert!(memory.recommendations(1.to_string(), 2, 2).await.expect("failed to get recommendation") == vec![4.to_string()]);
+        memory.deactivate(3.to_string()).await.expect("failed to deactivate");
+        assert!(memory.friends(1.to_string()).await.expect("failed to get friends") == vec![2.to_string()]);
+        assert!(memory.all_friends(1.to_string()).await.expect("failed to get friends") == vec![2.to_string(), 3.to_string()]);
+        assert!(!memory.is_friend(3.to_string(), 1.to_string()).await.expect("failed to check is friend"));
+        assert!(memory.recommendations(1.to_string(), 2, 2).await.expect("failed to get recommendation").is_empty());
+        assert!(memory.delete_node(1.to_string()).await.expect("failed to delete node") == vec![2.to_string(), 3.to_string()]);
+        assert!(memory.friends(2.to_string()).await.expect("failed to get friends") == vec![4.to_string()]);
+        assert_eq!(memory.delete(1.to_string(), 2.to_string()).await.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_memory_bulk_events() {
+        let memory = Memory::new();
+        memory.insert_nodes(vec![1.to_string(), 2.to_string(), 1.to_string()]).await.expect("failed to insert nodes");
+        memory.insert_nodes(vec![3.to_string()]).await.expect("failed to insert nodes");
+        let outcome = memory
+            .insert_many(vec![
+                (1.to_string(), 2.to_string(), None),
+                (2.to_string(), 1.to_string(), None),
+                (1.to_string(), 4.to_string(), None),
+                (3.to_string(), 3.to_string(), None),
+            ])
+            .await
+            .expect("failed to insert relations");
+        assert_eq!(outcome.created, vec![(1.to_string(), 2.to_string())]);
+        let reasons = outcome.rejected.into_iter().map(|(_, reason)| (reason.code, reason.detail)).collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            vec![
+                (ErrorKind::NotFound, "user 4 not found".to_owned()),
+                (ErrorKind::InvalidInput, "user 3 can not be friend of itself".to_owned())
+            ]
+        );
+        memory.deactivate_many(vec![2.to_string(), 3.to_string()]).await.expect("failed to deactivate");
+        let outcome = memory.insert_many(vec![(1.to_string(), 3.to_string(), None)]).await.expect("failed to insert relations");
+        assert!(outcome.created.is_empty() && outcome.rejected[0].1.code == ErrorKind::Conflict);
+        memory.reactivate(2.to_string()).await.expect("failed to reactivate");
+        let pending = memory.pending(100).await.expect("failed to get pending events");
+        assert_eq!(
+            pending.into_iter().map(|pending| pending.event).collect::<Vec<_>>(),
+            vec![
+                Event::UserCreated { uid: 1.to_string() },
+                Event::UserCreated { uid: 2.to_string() },
+                Event::UserCreated { uid: 3.to_string() },
+                Event::FriendAdded {
+                    uid: 1.to_string(),
+                    friend: 2.to_string()
+                },
+                Event::UserDeactivated {
+                    uid: 2.to_string(),
+                    friends: vec![1.to_string()]
+                },
+                Event::UserDeactivated { uid: 3.to_string(), friends: vec![] },
+                Event::UserReactivated {
+                    uid: 2.to_string(),
+                    friends: vec![1.to_string()]
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_batch() {
+        let memory = Memory::new();
+        memory.insert_nodes(vec![1.to_string(), 2.to_string(), 3.to_string()]).await.expect("failed to insert nodes");
+        memory.insert(1.to_string(), 3.to_string()).await.expect("failed to insert relation");
+        let ops = vec![
+            BatchOp::Add {
+                uid_a: 1.to_string(),
+                uid_b: 2.to_string(),
+            },
+            BatchOp::Add {
+                uid_a: 1.to_string(),
+                uid_b: 4.to_string(),
+            },
+            BatchOp::Delete {
+                uid_a: 3.to_string(),
+                uid_b: 1.to_string(),
+            },
+        ];
+        let outcome = memory.batch(ops.clone(), true).await.expect("failed to run batch");
+        assert!(!outcome.committed);
+        assert_eq!(
+            outcome.results.iter().map(|r| r.as_ref().map(|e| e.code)).collect::<Vec<_>>(),
+            vec![None, Some(ErrorKind::NotFound), None]
+        );
+        assert!(memory.friends(1.to_string()).await.expect("failed to get friends") == vec![3.to_string()]);
+        let outcome = memory.batch(ops, false).await.expect("failed to run batch");
+        assert!(outcome.committed);
+        assert!(memory.friends(1.to_string()).await.expect("failed to get friends") == vec![2.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_friends_many() {
+        let memory = Memory::new();
+        memory.insert_nodes(vec![1.to_string(), 2.to_string(), 3.to_string()]).await.expect("failed to insert nodes");
+        memory.insert(1.to_string(), 2.to_string()).await.expect("failed to insert relation");
+        let friends = memory.friends_many(vec![1.to_string(), 3.to_string(), 4.to_string()]).await.expect("failed to get friends");
+        assert!(friends == BTreeMap::from([(1.to_string(), vec![2.to_string()]), (3.to_string(), vec![])]));
+        memory.deactivate(3.to_string()).await.expect("failed to deactivate");
+        let profiles = memory.profiles(vec![1.to_string(), 3.to_string(), 4.to_string()]).await.expect("failed to get profiles");
+        assert!(profiles.keys().collect::<Vec<_>>() == vec!["1", "3"]);
+        assert!(profiles["3"].get("deactivated").map(String::as_str) == Some("true"));
+    }
+
+    #[tokio::test]
+    async fn test_friends_many() {
+        dotenv::dotenv().expect("failed to load environment variables");
+        let username = dotenv::var("NEO4J_USERNAME").expect("failed to get NEO4J_USERNAME");
+        let password = dotenv::var("NEO4J_PASSWORD").expect("failed to get NEO4J_PASSWORD");
+        let graph = Graph::new("localhost:7687", &username, &password).await.expect("failed to connect to neo4j");
+        let neo = Neo::new(Arc::new(graph));
+        neo.insert_nodes(vec![1.to_string(), 2.to_string(), 3.to_string()]).await.expect("failed to insert nodes");
+        neo.insert(1.to_string(), 2.to_string()).await.expect("failed to insert relation");
+        let friends = neo.friends_many(vec![1.to_string(), 3.to_string(), 4.to_string()]).await.expect("failed to get friends");
+        let profiles = neo.profiles(vec![1.to_string(), 3.to_string(), 4.to_string()]).await.expect("failed to get profiles");
+        neo.delete_node(1.to_string()).await.expect("failed to delete node");
+        neo.delete_node(2.to_string()).await.expect("failed to delete node");
+        neo.delete_node(3.to_string()).await.expect("failed to delete node");
+        assert!(friends == BTreeMap::from([(1.to_string(), vec![2.to_string()]), (3.to_string(), vec![])]));
+        assert!(profiles.keys().collect::<Vec<_>>() == vec!["1", "3"]);
+        assert!(profiles["1"].get("uid").map(String::as_str) == Some("1"));
+    }
 }
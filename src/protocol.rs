@@ -0,0 +1,366 @@
+use crate::error::{Error, ErrorKind};
+use crate::Request;
+use chrono::Utc;
+use rdkafka::message::Headers;
+use serde::{Deserialize, Serialize};
+use std::str::from_utf8;
+use std::time::Instant;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub const DEFAULT_REQUEST_TOPIC: &str = "friendship";
+pub const DEFAULT_DEAD_LETTER_TOPIC: &str = "friendship_dead_letters";
+pub const DEFAULT_DELIVERY_FAILURE_TOPIC: &str = "friendship_delivery_failures";
+
+// Kafka header carrying the ID that correlates a request with its reply.
+pub const CORRELATION_HEADER: &str = "correlation_id";
+
+// HTTP counterparts of `Meta`: the request ID, taken from the request when it has one,
+// and the time spent serving it as `app;dur=<milliseconds>`.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+pub const SERVER_TIMING_HEADER: &str = "Server-Timing";
+
+pub fn correlation_id<H: Headers>(headers: &H) -> Option<&str> {
+    (0..headers.count())
+        .filter_map(|i| headers.get(i))
+        .find(|(name, _)| *name == CORRELATION_HEADER)
+        .and_then(|(_, value)| from_utf8(value).ok())
+}
+
+// Name of the Redis list the reply to request `correlation_id` is pushed to.
+pub fn reply_key(correlation_id: &str) -> String {
+    format!("reply:{}", correlation_id)
+}
+
+// Where a reply is written: `reply_to` names a Redis list, the topic a Kafka reply
+// keyed by the request ID is published to, or the URL a webhook reply is posted to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    #[default]
+    Redis,
+    Kafka,
+    Webhook,
+}
+
+impl Channel {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+// Destination of the reply to one request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyTo {
+    pub channel: Channel,
+    pub address: String,
+    pub request_id: String,
+}
+
+// Payload of every request message. `deadline` is a unix timestamp in milliseconds,
+// `caller` identifies the sending service for logs.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u32,
+    pub request_id: String,
+    pub reply_to: String,
+    #[serde(default, skip_serializing_if = "Channel::is_default")]
+    pub reply_channel: Channel,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caller: Option<String>,
+    pub request: Request,
+}
+
+impl Envelope {
+    pub fn reply(&self) -> ReplyTo {
+        ReplyTo {
+            channel: self.reply_channel,
+            address: self.reply_to.clone(),
+            request_id: self.request_id.clone(),
+        }
+    }
+}
+
+// Request sent over the WebSocket API. The socket is its reply address, so only the
+// request ID is kept to match the `Response` with.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SocketRequest {
+    pub request_id: String,
+    pub request: Request,
+}
+
+// A message which can not be handled. The error is still replied when the reply
+// address could be recovered from it.
+#[derive(Debug)]
+pub struct Rejected {
+    pub reply_to: Option<ReplyTo>,
+    pub error: Error,
+}
+
+#[derive(Deserialize)]
+struct Probe {
+    version: Option<u32>,
+    request_id: Option<String>,
+    reply_to: Option<String>,
+    reply_channel: Option<Channel>,
+}
+
+// Messages without a `version` field come from clients predating the envelope: a bare
+// `Request`, replied to the list named by the correlation header or, before correlation
+// IDs, by the message key. They are decoded as version 0.
+pub fn decode(payload: &[u8], key: Option<&[u8]>, correlation_id: Option<&str>) -> Result<Envelope, Rejected> {
+    let probe = serde_json::from_slice::<Probe>(payload).ok();
+    let legacy_id = correlation_id.map(String::from).or_else(|| key.and_then(|key| from_utf8(key).ok()).map(String::from));
+    let reply_to = match (&probe, correlation_id) {
+        (Some(Probe { reply_to: Some(reply_to), .. }), _) => Some(reply_to.clone()),
+        (_, Some(correlation_id)) => Some(reply_key(correlation_id)),
+        _ => legacy_id.clone(),
+    };
+    let request_id = probe.as_ref().and_then(|probe| probe.request_id.clone()).or_else(|| legacy_id.clone()).unwrap_or_default();
+    let channel = probe.as_ref().and_then(|probe| probe.reply_channel).unwrap_or_default();
+    let reject = |error: Error| Rejected {
+        reply_to: reply_to.clone().map(|address| ReplyTo {
+            channel,
+            address,
+            request_id: request_id.clone(),
+        }),
+        error,
+    };
+    match probe.and_then(|probe| probe.version) {
+        Some(PROTOCOL_VERSION) => serde_json::from_slice(payload).map_err(|e| reject(Error::invalid_input(format!("invalid envelope: {}", e)))),
+        Some(version) => Err(reject(Error::invalid_input(format!("unsupported protocol version {}", version)))),
+        None => {
+            let request = serde_json::from_slice(payload).map_err(|e| reject(Error::invalid_input(format!("invalid request: {}", e))))?;
+            match (legacy_id, reply_to.clone()) {
+                (Some(request_id), Some(reply_to)) => Ok(Envelope {
+                    version: 0,
+                    request_id,
+                    reply_to,
+                    reply_channel: Channel::Redis,
+                    deadline: None,
+                    caller: None,
+                    request,
+                }),
+                _ => Err(reject(Error::invalid_input("request without reply address".into()))),
+            }
+        }
+    }
+}
+
+// Where and when a reply was produced. Every field is optional so replies of older
+// servers, which had none of them, still decode.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Meta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub served_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elapsed_ms: Option<u64>,
+}
+
+impl Meta {
+    pub fn new(request_id: String, started: Instant) -> Self {
+        Self {
+            request_id: Some(request_id),
+            served_at: Some(Utc::now().to_rfc3339()),
+            elapsed_ms: Some(started.elapsed().as_millis() as u64),
+        }
+    }
+}
+
+fn unknown_code() -> ErrorKind {
+    ErrorKind::Internal
+}
+
+// Reply to a request over any transport: `{"Ok":{"data":…}}` or
+// `{"Err":{"code":…,"detail":…}}`, both along with the fields of `Meta`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Response<T> {
+    Ok {
+        data: T,
+        #[serde(flatten)]
+        meta: Meta,
+    },
+    Err {
+        #[serde(default = "unknown_code")]
+        code: ErrorKind,
+        detail: String,
+        #[serde(flatten)]
+        meta: Meta,
+    },
+}
+
+impl<T> Response<T> {
+    pub fn new(res: Result<T, Error>, meta: Meta) -> Self {
+        match res {
+            Ok(data) => Self::Ok { data, meta },
+            Err(e) => Self::Err {
+                code: e.kind(),
+                detail: format!("{}", e),
+                meta,
+            },
+        }
+    }
+
+    pub fn meta(&self) -> &Meta {
+        match self {
+            Self::Ok { meta, .. } | Self::Err { meta, .. } => meta,
+        }
+    }
+
+    pub fn into_result(self) -> Result<T, Error> {
+        match self {
+            Self::Ok { data, .. } => Ok(data),
+            Self::Err { code, detail, .. } => Err(Error::new(code, detail)),
+        }
+    }
+}
+
+// Replaying a dead letter is about applying the request, so the deadline of its
+// original caller (who got an error reply long ago) is dropped.
+pub fn without_deadline(payload: &str) -> String {
+    match serde_json::from_str::<serde_json::Value>(payload) {
+        Ok(serde_json::Value::Object(mut envelope)) if envelope.contains_key("version") => {
+            envelope.remove("deadline");
+            serde_json::Value::Object(envelope).to_string()
+        }
+        _ => payload.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ErrorKind;
+    use rdkafka::message::OwnedHeaders;
+
+    #[test]
+    fn test_correlation_id() {
+        let headers = OwnedHeaders::new().add("other", "x").add(CORRELATION_HEADER, "0f6e");
+        assert_eq!(correlation_id(&headers), Some("0f6e"));
+        assert_eq!(correlation_id(&OwnedHeaders::new()), None);
+        assert_eq!(reply_key("0f6e"), "reply:0f6e");
+    }
+
+    #[test]
+    fn test_decode_envelope() {
+        let envelope = Envelope {
+            version: PROTOCOL_VERSION,
+            request_id: "0f6e".into(),
+            reply_to: reply_key("0f6e"),
+            reply_channel: Channel::Redis,
+            deadline: Some(1_700_000_000_000),
+            caller: Some("feed".into()),
+            request: Request::Friends { uid: 1 },
+        };
+        let payload = serde_json::to_vec(&envelope).unwrap();
+        assert_eq!(
+            String::from_utf8(payload.clone()).unwrap(),
+            r#"{"version":1,"request_id":"0f6e","reply_to":"reply:0f6e","deadline":1700000000000,"caller":"feed","request":{"Friends":{"uid":1}}}"#
+        );
+        let decoded = decode(&payload, Some(b"1"), Some("0f6e")).unwrap();
+        assert_eq!((decoded.version, decoded.reply_to.as_str(), decoded.deadline), (1, "reply:0f6e", Some(1_700_000_000_000)));
+        assert!(matches!(decoded.request, Request::Friends { uid: 1 }));
+
+        let minimal = decode(br#"{"version":1,"request_id":"a","reply_to":"r","request":{"AddNode":{"uid":2}},"extra":true}"#, None, None).unwrap();
+        assert_eq!((minimal.deadline, minimal.caller, minimal.reply_channel), (None, None, Channel::Redis));
+
+        let kafka = decode(
+            br#"{"version":1,"request_id":"a","reply_to":"replies","reply_channel":"kafka","request":{"AddNode":{"uid":2}}}"#,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            kafka.reply(),
+            ReplyTo {
+                channel: Channel::Kafka,
+                address: "replies".into(),
+                request_id: "a".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_legacy() {
+        // Payloads of clients predating correlation IDs: reply list in the message key.
+        let decoded = decode(br#"{"AddNode":{"uid":1}}"#, Some(b"1700000000-3"), None).unwrap();
+        assert_eq!((decoded.version, decoded.request_id.as_str(), decoded.reply_to.as_str()), (0, "1700000000-3", "1700000000-3"));
+        assert!(matches!(decoded.request, Request::AddNode { uid: 1 }));
+
+        // Payloads of clients with correlation IDs but without the envelope.
+        let decoded = decode(br#"{"Add":{"uid_a":1,"uid_b":2}}"#, Some(b"1"), Some("0f6e")).unwrap();
+        assert_eq!((decoded.request_id.as_str(), decoded.reply_to.as_str()), ("0f6e", "reply:0f6e"));
+
+        let decoded = decode(br#"{"Batch":{"ops":[{"op":"Add","uid_a":1,"uid_b":2}],"atomic":true}}"#, Some(b"k"), None).unwrap();
+        assert!(matches!(decoded.request, Request::Batch { atomic: true, .. }));
+    }
+
+    #[test]
+    fn test_decode_rejected() {
+        let rejected = decode(br#"{"version":2,"request_id":"a","reply_to":"r","request":{"Next":{}}}"#, None, None).unwrap_err();
+        let reply_to = rejected.reply_to.as_ref().unwrap();
+        assert_eq!((reply_to.address.as_str(), reply_to.request_id.as_str(), rejected.error.kind()), ("r", "a", ErrorKind::InvalidInput));
+        assert_eq!(format!("{}", rejected.error), "unsupported protocol version 2");
+
+        let rejected = decode(br#"{"version":1,"request_id":"a","reply_to":"t","reply_channel":"kafka","request":{"Unknown":{}}}"#, None, None).unwrap_err();
+        let reply_to = rejected.reply_to.unwrap();
+        assert_eq!((reply_to.channel, reply_to.address.as_str()), (Channel::Kafka, "t"));
+
+        let rejected = decode(b"garbage", Some(b"k"), None).unwrap_err();
+        let reply_to = rejected.reply_to.unwrap();
+        assert_eq!((reply_to.channel, reply_to.address.as_str(), reply_to.request_id.as_str()), (Channel::Redis, "k", "k"));
+
+        let rejected = decode(br#"{"AddNode":{"uid":1}}"#, None, None).unwrap_err();
+        assert_eq!(rejected.reply_to, None);
+    }
+
+    #[test]
+    fn test_response() {
+        let meta = Meta {
+            request_id: Some("0f6e".into()),
+            served_at: Some("2024-01-01T00:00:00+00:00".into()),
+            elapsed_ms: Some(12),
+        };
+        let ok = Response::new(Ok(vec![2, 3]), meta.clone());
+        let json = serde_json::to_string(&ok).unwrap();
+        assert_eq!(json, r#"{"Ok":{"data":[2,3],"request_id":"0f6e","served_at":"2024-01-01T00:00:00+00:00","elapsed_ms":12}}"#);
+        assert_eq!(serde_json::from_str::<Response<Vec<i64>>>(&json).unwrap(), ok);
+
+        let err = Response::<()>::new(Err(Error::not_found("user 1 not found".into())), meta);
+        let json = serde_json::to_string(&err).unwrap();
+        assert_eq!(
+            json,
+            r#"{"Err":{"code":"NOT_FOUND","detail":"user 1 not found","request_id":"0f6e","served_at":"2024-01-01T00:00:00+00:00","elapsed_ms":12}}"#
+        );
+        let decoded = serde_json::from_str::<Response<()>>(&json).unwrap();
+        assert_eq!(decoded, err);
+        assert_eq!(decoded.meta().elapsed_ms, Some(12));
+        let e = decoded.into_result().unwrap_err();
+        assert_eq!((e.kind(), format!("{}", e)), (ErrorKind::NotFound, "user 1 not found".to_owned()));
+
+        let bare = Response::<()>::new(Ok(()), Meta::default());
+        assert_eq!(serde_json::to_string(&bare).unwrap(), r#"{"Ok":{"data":null}}"#);
+    }
+
+    #[test]
+    fn test_response_of_older_servers() {
+        let ok = serde_json::from_str::<Response<Vec<i64>>>(r#"{"Ok":{"data":[1]}}"#).unwrap();
+        assert_eq!(ok.meta(), &Meta::default());
+        assert_eq!(ok.into_result().unwrap(), vec![1]);
+        let e = serde_json::from_str::<Response<()>>(r#"{"Err":{"detail":"user 1 not found"}}"#).unwrap().into_result().unwrap_err();
+        assert_eq!((e.kind(), format!("{}", e)), (ErrorKind::Internal, "user 1 not found".to_owned()));
+    }
+
+    #[test]
+    fn test_without_deadline() {
+        let replayed = without_deadline(r#"{"version":1,"request_id":"a","reply_to":"r","deadline":1,"request":{"AddNode":{"uid":1}}}"#);
+        let envelope = decode(replayed.as_bytes(), None, None).unwrap();
+        assert_eq!((envelope.request_id.as_str(), envelope.deadline), ("a", None));
+        assert_eq!(without_deadline(r#"{"AddNode":{"uid":1}}"#), r#"{"AddNode":{"uid":1}}"#);
+        assert_eq!(without_deadline("garbage"), "garbage");
+    }
+}
@@ -0,0 +1,193 @@
+use crate::core::{BoxFuture, DeadLetterQueue, DeliveryFailureLog, EventStream, Publisher, Subscriber};
+use crate::events::Event;
+use crate::models::{DeadLetter, DeliveryFailure};
+use crate::protocol::CORRELATION_HEADER;
+use futures_util::StreamExt;
+use log::warn;
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use redis::Client;
+use std::time::Duration;
+
+pub struct Kafka {
+    producer: FutureProducer,
+    topic: String,
+}
+
+impl Kafka {
+    pub fn new(producer: FutureProducer, topic: String) -> Self {
+        Self { producer, topic }
+    }
+}
+
+// Events are keyed by the user they are about, so the events of one user land on the
+// same partition in the order they happened.
+impl Publisher for Kafka {
+    type UID = String;
+    fn publish(&self, event: Event<Self::UID>) -> BoxFuture<()> {
+        let producer = self.producer.clone();
+        let topic = self.topic.clone();
+        Box::pin(async move {
+            let body = serde_json::to_string(&event)?;
+            producer
+                .send(FutureRecord::to(&topic).key(event.uid()).payload(&body), Duration::from_secs(10))
+                .await
+                .map_err(|(e, _)| e)?;
+            Ok(())
+        })
+    }
+}
+
+// Dead letters keep the key and correlation ID of the failed request.
+impl DeadLetterQueue for Kafka {
+    fn push(&self, letter: DeadLetter) -> BoxFuture<()> {
+        let producer = self.producer.clone();
+        let topic = self.topic.clone();
+        Box::pin(async move {
+            let body = serde_json::to_string(&letter)?;
+            let mut record = FutureRecord::to(&topic).payload(&body);
+            if let Some(key) = &letter.key {
+                record = record.key(key);
+            }
+            if let Some(correlation_id) = &letter.correlation_id {
+                record = record.headers(OwnedHeaders::new().add(CORRELATION_HEADER, correlation_id));
+            }
+            producer.send(record, Duration::from_secs(10)).await.map_err(|(e, _)| e)?;
+            Ok(())
+        })
+    }
+}
+
+// Delivery failures are keyed by the request ID, which is also the correlation ID.
+impl DeliveryFailureLog for Kafka {
+    fn record(&self, failure: DeliveryFailure) -> BoxFuture<()> {
+        let producer = self.producer.clone();
+        let topic = self.topic.clone();
+        Box::pin(async move {
+            let body = serde_json::to_string(&failure)?;
+            let record = FutureRecord::to(&topic)
+                .key(&failure.request_id)
+                .payload(&body)
+                .headers(OwnedHeaders::new().add(CORRELATION_HEADER, &failure.request_id));
+            producer.send(record, Duration::from_secs(10)).await.map_err(|(e, _)| e)?;
+            Ok(())
+        })
+    }
+}
+
+fn event_channel(uid: &str) -> String {
+    format!("user_events:{}", uid)
+}
+
+// Fans events out to every service instance over Redis pub/sub, on one channel per user
+// concerned by the event. Nothing is kept for users who are not subscribed.
+pub struct RedisPubSub {
+    client: Client,
+}
+
+impl RedisPubSub {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+}
+
+impl Publisher for RedisPubSub {
+    type UID = String;
+    fn publish(&self, event: Event<Self::UID>) -> BoxFuture<()> {
+        let client = self.client.clone();
+        Box::pin(async move {
+            let body = serde_json::to_string(&event)?;
+            let mut pipe = redis::pipe();
+            for uid in event.users() {
+                pipe.publish(event_channel(uid), &body).ignore();
+            }
+            let mut conn = client.get_async_connection().await?;
+            pipe.query_async::<_, ()>(&mut conn).await?;
+            Ok(())
+        })
+    }
+}
+
+impl Subscriber for RedisPubSub {
+    type UID = String;
+    fn subscribe(&self, uid: Self::UID) -> BoxFuture<EventStream<Self::UID>> {
+        let client = self.client.clone();
+        Box::pin(async move {
+            let mut pubsub = client.get_async_connection().await?.into_pubsub();
+            pubsub.subscribe(event_channel(&uid)).await?;
+            let events = pubsub.into_on_message().filter_map(|msg| async move {
+                let payload = msg.get_payload::<String>().ok()?;
+                serde_json::from_str(&payload).map_err(|e| warn!("invalid event {:?}: {}", payload, e)).ok()
+            });
+            Ok(Box::pin(events) as EventStream<Self::UID>)
+        })
+    }
+}
+
+// Publishes to `publisher` and then to `notifier`. Only `publisher` is the durable record
+// of an event, failing to notify is logged so it does not hold back the events behind it.
+pub struct Fanout<E, N> {
+    publisher: E,
+    notifier: N,
+}
+
+impl<E, N> Fanout<E, N> {
+    pub fn new(publisher: E, notifier: N) -> Self {
+        Self { publisher, notifier }
+    }
+}
+
+impl<E: Publisher<UID = String>, N: Publisher<UID = String>> Publisher for Fanout<E, N> {
+    type UID = String;
+    fn publish(&self, event: Event<Self::UID>) -> BoxFuture<()> {
+        let published = self.publisher.publish(event.clone());
+        let notified = self.notifier.publish(event.clone());
+        Box::pin(async move {
+            published.await?;
+            if let Err(e) = notified.await {
+                warn!("failed to notify subscribers of {:?}: {}", event, e);
+            }
+            Ok(())
+        })
+    }
+}
+
+// In-memory implementation of `Publisher` for tests, keeping every published event.
+#[cfg(test)]
+type Subscription = (String, tokio::sync::mpsc::UnboundedSender<Event<String>>);
+
+#[cfg(test)]
+#[derive(Default, Clone)]
+pub struct Memory {
+    events: std::sync::Arc<std::sync::Mutex<Vec<Event<String>>>>,
+    subscribers: std::sync::Arc<std::sync::Mutex<Vec<Subscription>>>,
+}
+
+#[cfg(test)]
+impl Memory {
+    pub fn events(&self) -> Vec<Event<String>> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Publisher for Memory {
+    type UID = String;
+    fn publish(&self, event: Event<Self::UID>) -> BoxFuture<()> {
+        let users = event.users();
+        self.subscribers.lock().unwrap().retain(|(uid, tx)| !users.contains(&uid) || tx.send(event.clone()).is_ok());
+        self.events.lock().unwrap().push(event);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+impl Subscriber for Memory {
+    type UID = String;
+    fn subscribe(&self, uid: Self::UID) -> BoxFuture<EventStream<Self::UID>> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().push((uid, tx));
+        let events = futures_util::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|event| (event, rx)) });
+        Box::pin(async move { Ok(Box::pin(events) as EventStream<Self::UID>) })
+    }
+}
@@ -0,0 +1,242 @@
+use crate::core::{Outbox, Publisher};
+use crate::error::Error;
+use log::warn;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+pub const DEFAULT_BATCH_SIZE: usize = 100;
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(30);
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(7 * 24 * 3600);
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Publishes the events waiting in the outbox, oldest first. An event is marked delivered
+// only after it is published, so an event published right before a crash is published
+// again: consumers see every event at least once and have to tolerate duplicates.
+//
+// Every replica runs a relay, they take turns through the claims of `Outbox::claim`: one
+// relay publishes while its claims are live, the others find nothing to publish. A
+// batch has to be published within `lease`, or another relay may publish it as well.
+pub struct Relay<O, E> {
+    outbox: O,
+    publisher: E,
+    owner: String,
+    batch_size: usize,
+    interval: Duration,
+    lease: Duration,
+    retention: Duration,
+}
+
+impl<O: Outbox<UID = String>, E: Publisher<UID = String>> Relay<O, E> {
+    pub fn new(outbox: O, publisher: E) -> Self {
+        Self {
+            outbox,
+            publisher,
+            owner: Uuid::new_v4().to_string(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            interval: Duration::from_millis(500),
+            lease: DEFAULT_LEASE,
+            retention: DEFAULT_RETENTION,
+        }
+    }
+
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    // How long to wait before polling again once the outbox is drained.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    // How long claimed events are left to this relay before another one may take them.
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    // How long delivered events are kept before they are purged.
+    pub fn retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    // Publishes one batch and returns how many events were delivered. Publishing stops at
+    // the first failure, so no event overtakes an earlier one of the same user.
+    pub async fn run_once(&self) -> Result<usize, Error> {
+        let mut delivered = Vec::new();
+        let mut failure = None;
+        for pending in self.outbox.claim(self.owner.clone(), self.batch_size, self.lease).await? {
+            if let Err(e) = self.publisher.publish(pending.event).await {
+                failure = Some(e);
+                break;
+            }
+            delivered.push(pending.id);
+        }
+        let count = delivered.len();
+        if count > 0 {
+            self.outbox.mark_delivered(delivered).await?;
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(count),
+        }
+    }
+
+    // Deletes the events delivered longer than `retention` ago, a batch at a time, and
+    // returns how many were deleted.
+    pub async fn purge(&self) -> Result<usize, Error> {
+        let mut total = 0;
+        loop {
+            let purged = self.outbox.purge(self.retention, self.batch_size).await?;
+            total += purged;
+            if purged < self.batch_size {
+                return Ok(total);
+            }
+        }
+    }
+
+    pub async fn run(&self) {
+        let mut next_purge = Instant::now();
+        loop {
+            match self.run_once().await {
+                // A full batch means more events may be waiting.
+                Ok(count) if count == self.batch_size => continue,
+                Ok(_) => {}
+                Err(e) => warn!("failed to relay outbox events: {}", e),
+            }
+            if Instant::now() >= next_purge {
+                if let Err(e) = self.purge().await {
+                    warn!("failed to purge delivered outbox events: {}", e);
+                }
+                next_purge = Instant::now() + PURGE_INTERVAL;
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{BoxFuture, Persister};
+    use crate::events::Event;
+    use crate::models::BatchOp;
+    use crate::persisters::Memory;
+    use crate::publishers::Memory as MemoryPublisher;
+    use std::cell::Cell;
+
+    // Fails the publish after `ok` successful ones.
+    struct Flaky {
+        published: MemoryPublisher,
+        ok: Cell<usize>,
+    }
+
+    impl Publisher for Flaky {
+        type UID = String;
+        fn publish(&self, event: Event<Self::UID>) -> BoxFuture<()> {
+            if self.ok.get() == 0 {
+                return Box::pin(async { Err(Error::unavailable("kafka is down".into())) });
+            }
+            self.ok.set(self.ok.get() - 1);
+            self.published.publish(event)
+        }
+    }
+
+    fn uid(uid: i64) -> String {
+        uid.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let persister = Memory::new();
+        persister.insert_node(uid(1)).await.unwrap();
+        persister.insert_node(uid(2)).await.unwrap();
+        persister.insert(uid(1), uid(2)).await.unwrap();
+        let ops = vec![BatchOp::Delete { uid_a: uid(2), uid_b: uid(1) }, BatchOp::Add { uid_a: uid(1), uid_b: uid(3) }];
+        persister.batch(ops, true).await.unwrap();
+        persister.delete_node(uid(2)).await.unwrap();
+
+        let publisher = MemoryPublisher::default();
+        let relay = Relay::new(persister.clone(), publisher.clone()).batch_size(2);
+        assert_eq!(relay.run_once().await.unwrap(), 2);
+        assert_eq!(relay.run_once().await.unwrap(), 2);
+        assert_eq!(relay.run_once().await.unwrap(), 0);
+        assert_eq!(
+            publisher.events(),
+            vec![
+                Event::UserCreated { uid: uid(1) },
+                Event::UserCreated { uid: uid(2) },
+                Event::FriendAdded { uid: uid(1), friend: uid(2) },
+                Event::UserDeleted { uid: uid(2), friends: vec![uid(1)] },
+            ]
+        );
+        assert!(persister.pending(10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_relay_failure() {
+        let persister = Memory::new();
+        for n in 1..=3 {
+            persister.insert_node(uid(n)).await.unwrap();
+        }
+        let published = MemoryPublisher::default();
+        let relay = Relay::new(
+            persister.clone(),
+            Flaky {
+                published: published.clone(),
+                ok: Cell::new(1),
+            },
+        );
+        assert!(relay.run_once().await.is_err());
+        assert_eq!(published.events(), vec![Event::UserCreated { uid: uid(1) }]);
+        assert_eq!(persister.pending(10).await.unwrap().len(), 2);
+
+        relay.publisher.ok.set(10);
+        assert_eq!(relay.run_once().await.unwrap(), 2);
+        assert_eq!(published.events().into_iter().map(|event| event.uid().clone()).collect::<Vec<_>>(), vec![uid(1), uid(2), uid(3)]);
+    }
+
+    #[tokio::test]
+    async fn test_relay_claims() {
+        let persister = Memory::new();
+        for n in 1..=3 {
+            persister.insert_node(uid(n)).await.unwrap();
+        }
+        let published = MemoryPublisher::default();
+        let crashed = Relay::new(
+            persister.clone(),
+            Flaky {
+                published: published.clone(),
+                ok: Cell::new(1),
+            },
+        );
+        assert!(crashed.run_once().await.is_err());
+
+        // The events claimed by the failed relay are left to it until its lease runs out.
+        let relay = Relay::new(persister.clone(), published.clone());
+        assert_eq!(relay.run_once().await.unwrap(), 0);
+        let relay = relay.lease(Duration::ZERO);
+        crashed.publisher.ok.set(10);
+        let crashed = crashed.lease(Duration::from_secs(60));
+        assert_eq!(relay.run_once().await.unwrap(), 2);
+        assert_eq!(crashed.run_once().await.unwrap(), 0);
+        assert_eq!(published.events().into_iter().map(|event| event.uid().clone()).collect::<Vec<_>>(), vec![uid(1), uid(2), uid(3)]);
+    }
+
+    #[tokio::test]
+    async fn test_purge() {
+        let persister = Memory::new();
+        for n in 1..=3 {
+            persister.insert_node(uid(n)).await.unwrap();
+        }
+        let relay = Relay::new(persister.clone(), MemoryPublisher::default()).batch_size(2);
+        assert_eq!(relay.run_once().await.unwrap(), 2);
+        persister.insert_node(uid(4)).await.unwrap();
+        assert_eq!(relay.purge().await.unwrap(), 0);
+        let relay = relay.retention(Duration::ZERO);
+        assert_eq!(relay.purge().await.unwrap(), 2);
+        assert_eq!(persister.pending(10).await.unwrap().len(), 2);
+    }
+}
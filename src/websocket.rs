@@ -0,0 +1,160 @@
+use crate::consumer::handle;
+use crate::core::{Cacher, Persister};
+use crate::error::Error;
+use crate::protocol::{Meta, Response, SocketRequest};
+use actix_web::web::{Data, Payload};
+use actix_web::{HttpRequest, HttpResponse};
+use actix_ws::AggregatedMessage;
+use log::{debug, warn};
+use serde_json::Value;
+use std::time::Instant;
+
+// Largest request accepted, batches included.
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+// Runs one text message as a `SocketRequest`. Messages which are not one are answered
+// with an error carrying their `request_id` when it can be read.
+pub async fn respond<P: Persister<UID = String>, C: Cacher<UID = String>>(persister: &P, cacher: &C, text: &str) -> Response<Value> {
+    let started = Instant::now();
+    let (request_id, res) = match serde_json::from_str::<SocketRequest>(text) {
+        Ok(SocketRequest { request_id, request }) => (Some(request_id), handle(persister, cacher, request).await),
+        Err(e) => {
+            let request_id = serde_json::from_str::<Value>(text)
+                .ok()
+                .and_then(|value| value.get("request_id").and_then(Value::as_str).map(String::from));
+            (request_id, Err(Error::invalid_input(format!("invalid request: {}", e))))
+        }
+    };
+    let meta = Meta::new(request_id.clone().unwrap_or_default(), started);
+    Response::new(res, Meta { request_id, ..meta })
+}
+
+// Serves the request protocol over a WebSocket: every text message is a `SocketRequest`
+// and is answered with a `Response` text message. Requests of one socket run one at a
+// time in the order they were sent, like the requests of one user on Kafka.
+pub async fn connect<P: Persister<UID = String> + 'static, C: Cacher<UID = String> + 'static>(
+    req: HttpRequest,
+    body: Payload,
+    persister: Data<P>,
+    cacher: Data<C>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, mut session, messages) = actix_ws::handle(&req, body)?;
+    let mut messages = messages.max_frame_size(MAX_MESSAGE_SIZE).aggregate_continuations().max_continuation_size(MAX_MESSAGE_SIZE);
+    actix_web::rt::spawn(async move {
+        let reason = loop {
+            let text = match messages.recv().await {
+                Some(Ok(AggregatedMessage::Text(text))) => text,
+                Some(Ok(AggregatedMessage::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Some(Ok(AggregatedMessage::Close(reason))) => break reason,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    warn!("websocket protocol error: {}", e);
+                    break None;
+                }
+                None => break None,
+            };
+            let response = respond(persister.get_ref(), cacher.get_ref(), &text).await;
+            debug!("websocket request {:?} served", response.meta().request_id);
+            match serde_json::to_string(&response) {
+                Ok(response) => {
+                    if session.text(response).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => warn!("failed to encode websocket response: {}", e),
+            }
+        };
+        let _ = session.close(reason).await;
+    });
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cachers::Memory as MemoryCache;
+    use crate::persisters::Memory;
+    use crate::Request;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    fn request(request_id: &str, request: Request) -> String {
+        serde_json::to_string(&SocketRequest {
+            request_id: request_id.into(),
+            request,
+        })
+        .unwrap()
+    }
+
+    fn strip(response: Response<Value>) -> String {
+        let response = match response {
+            Response::Ok { data, meta } => Response::Ok {
+                data,
+                meta: Meta {
+                    request_id: meta.request_id,
+                    ..Meta::default()
+                },
+            },
+            Response::Err { code, detail, meta } => Response::Err {
+                code,
+                detail,
+                meta: Meta {
+                    request_id: meta.request_id,
+                    ..Meta::default()
+                },
+            },
+        };
+        serde_json::to_string(&response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_respond() {
+        let (persister, cacher) = (Memory::new(), MemoryCache::default());
+        for uid in 1..=2 {
+            respond(&persister, &cacher, &request(&format!("n{}", uid), Request::AddNode { uid })).await;
+        }
+        let response = respond(&persister, &cacher, &request("a", Request::Add { uid_a: 1, uid_b: 2 })).await;
+        assert!(response.meta().served_at.is_some());
+        assert_eq!(strip(response), r#"{"Ok":{"data":null,"request_id":"a"}}"#);
+        let response = respond(&persister, &cacher, &request("f", Request::Friends { uid: 2 })).await;
+        assert_eq!(strip(response), r#"{"Ok":{"data":[1],"request_id":"f"}}"#);
+        let response = respond(&persister, &cacher, &request("r", Request::Recommendation { uid: 3 })).await;
+        assert_eq!(strip(response), r#"{"Err":{"code":"NOT_FOUND","detail":"user 3 not found","request_id":"r"}}"#);
+        let response = respond(&persister, &cacher, r#"{"request_id":"x","request":{"Unknown":{}}}"#).await;
+        assert!(matches!(
+            &response,
+            Response::Err {
+                code: crate::error::ErrorKind::InvalidInput,
+                ..
+            }
+        ));
+        assert_eq!(response.meta().request_id.as_deref(), Some("x"));
+        assert_eq!(respond(&persister, &cacher, "not json").await.meta().request_id.as_deref(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_connect() {
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(Memory::new()))
+                .app_data(Data::new(MemoryCache::default()))
+                .route("/ws", web::get().to(connect::<Memory, MemoryCache>)),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri("/ws")
+            .insert_header(("upgrade", "websocket"))
+            .insert_header(("connection", "upgrade"))
+            .insert_header(("sec-websocket-version", "13"))
+            .insert_header(("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(call_service(&app, TestRequest::get().uri("/ws").to_request()).await.status(), StatusCode::BAD_REQUEST);
+    }
+}